@@ -5,6 +5,9 @@ struct Material {
     vec3 albedo;
     float roughness;
     float metallic;
+    vec3 subsurface; //Scattering radius per channel, 0 if light doesn't get in
+    int subsurface_medium; //Medium for the walk below the surface, -1 without subsurface
+    int brdf; //Id of the BRDF in material()
 };
 
 #endif
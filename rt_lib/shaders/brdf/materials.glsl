@@ -0,0 +1,46 @@
+#ifndef _INCLUDE_MATERIALS_
+#define _INCLUDE_MATERIALS_
+
+#include "mat.glsl"
+
+//Material table uploaded by scene.rs, indexed by the material ID returned from map().
+//Keep the layout in sync with GpuMaterial!
+struct RawMaterial {
+    vec4 albedo_roughness;
+    vec4 emission_metallic;
+    vec4 subsurface_medium; //w = medium of the subsurface walk, -1 without subsurface
+    uvec4 brdf; //x = id of the BRDF, 0 for Lambert
+};
+
+layout(std430, binding = 3) buffer material_buffer {
+    RawMaterial materials[];
+};
+
+Material getMaterial(int materialID) {
+    Material mat;
+    mat.albedo = vec3(1.0);
+    mat.roughness = 1.0;
+    mat.metallic = 0.0;
+    mat.subsurface = vec3(0.0);
+    mat.subsurface_medium = -1;
+    mat.brdf = 0;
+    if (materialID >= 0 && materialID < materials.length()) {
+        RawMaterial raw = materials[materialID];
+        mat.albedo = raw.albedo_roughness.rgb;
+        mat.roughness = raw.albedo_roughness.w;
+        mat.metallic = raw.emission_metallic.w;
+        mat.subsurface = raw.subsurface_medium.rgb;
+        mat.subsurface_medium = int(raw.subsurface_medium.w);
+        mat.brdf = int(raw.brdf.x);
+    }
+    return mat;
+}
+
+vec3 getEmission(int materialID) {
+    if (materialID >= 0 && materialID < materials.length()) {
+        return materials[materialID].emission_metallic.rgb;
+    }
+    return vec3(0.0);
+}
+
+#endif
@@ -0,0 +1,47 @@
+#ifndef _INCLUDE_BRICKMAP_
+#define _INCLUDE_BRICKMAP_
+
+//Sparse distance fields as uploaded by brickmap.rs. Keep the layout in sync with BrickAtlas!
+//Every brick map starts with a header in the grid buffer, followed by one entry per cell:
+//the index of its brick in the atlas, or a negative value with the distance the surface is at least away.
+
+#define BRICK_SIZE 8 //Samples per axis, neighbouring bricks share their border samples
+#define ATLAS_BRICKS 32 //Bricks along the X and Y axis of the atlas
+#define BRICK_HEADER_SIZE 8 //Origin, voxel size, dims and band
+
+layout(binding = 0) uniform sampler3D brick_atlas;
+layout(binding = 1) uniform isamplerBuffer brick_grid;
+
+float brickHeaderFloat(int header, int i) {
+    return intBitsToFloat(texelFetch(brick_grid, header + i).r);
+}
+
+//p is in object space, header is where the brick map starts in the grid buffer
+float sdBrickMap(vec3 p, int header) {
+    vec3 origin = vec3(brickHeaderFloat(header, 0), brickHeaderFloat(header, 1), brickHeaderFloat(header, 2));
+    float voxel_size = brickHeaderFloat(header, 3);
+    ivec3 dims = ivec3(texelFetch(brick_grid, header + 4).r, texelFetch(brick_grid, header + 5).r, texelFetch(brick_grid, header + 6).r);
+    float band = brickHeaderFloat(header, 7);
+
+    float span = voxel_size * float(BRICK_SIZE - 1);
+    vec3 local = (p - origin) / span;
+    //Outside of the grid, the surface is at least a band further away than the grid itself
+    vec3 outside = max(max(-local, local - vec3(dims)), 0.0) * span;
+    if (any(greaterThan(outside, vec3(0.0)))) return length(outside) + band;
+
+    ivec3 cell = min(ivec3(local), dims - 1);
+    int entry = texelFetch(brick_grid, header + BRICK_HEADER_SIZE + cell.x + dims.x * (cell.y + dims.y * cell.z)).r;
+    if (entry < 0) {
+        //-1 - (2 * voxels + inside)
+        int code = -1 - entry;
+        float dist = float(code / 2) * voxel_size;
+        return (code % 2 == 1) ? -dist : dist;
+    }
+
+    //Texel centers of the brick, so the hardware interpolation never reaches into the next brick
+    ivec3 corner = ivec3(entry % ATLAS_BRICKS, (entry / ATLAS_BRICKS) % ATLAS_BRICKS, entry / (ATLAS_BRICKS * ATLAS_BRICKS)) * BRICK_SIZE;
+    vec3 texel = vec3(corner) + (local - vec3(cell)) * float(BRICK_SIZE - 1) + 0.5;
+    return texture(brick_atlas, texel / vec3(textureSize(brick_atlas, 0))).r;
+}
+
+#endif
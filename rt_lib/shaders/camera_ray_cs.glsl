@@ -8,34 +8,251 @@ layout(std430, binding = 0) buffer ray_buffer {
     RawRay ray_ssbo[];
 };
 
+//Inverse CDF of the pixel filter, see filter.rs
+layout(std430, binding = 1) buffer filter_buffer {
+    vec4 filter_table[]; //x = offset in pixels, y = sample weight, z = sample weight past w, w = offset the filter changes sign at before the next entry
+};
+
 uniform vec2 dims;
-uniform mat4 invprojview;
-uniform vec2 jitter;
+uniform uint sample_index;
+uniform uint seed;
+uniform float sampler_kind;
+
+//Columns are right, up, backward and the eye position
+uniform mat4 camera_to_world_open;
+uniform mat4 camera_to_world_close;
+uniform vec2 shutter; //Open and close time, in seconds
+
+mat4 camera_to_world; //At the time of the current ray
+uniform float tan_half_fov; //Vertical
+uniform float near;
+uniform float far;
+
+//See projection.rs
+uniform float projection;
+uniform float projection_param; //Orthographic height, or fisheye fov in radians
+
+#define PROJECTION_PERSPECTIVE 0
+#define PROJECTION_ORTHOGRAPHIC 1
+#define PROJECTION_EQUIRECTANGULAR 2
+#define PROJECTION_FISHEYE 3
+#define PROJECTION_CUBEMAP 4
+
+//Thin lens, see lens.rs
+uniform float aperture_radius; //0 = pinhole
+uniform float focus_distance;
+uniform float aperture_shape;
+uniform float aperture_blades;
+uniform float aperture_rotation; //In radians
+uniform vec2 aperture_dims; //Size of the aperture texture
+
+//CDFs for sampling the aperture texture
+layout(std430, binding = 2) buffer aperture_buffer {
+    float aperture_table[];
+};
+
+#define APERTURE_CIRCLE 0
+#define APERTURE_POLYGON 1
+#define APERTURE_TEXTURE 2
+
+#include "sampler.glsl"
+
+Ray rayPerspective(vec2 uv) {
+    vec2 pos = (uv * 2.0 - 1.0) * vec2(dims.x / dims.y, 1.0) * tan_half_fov;
+    Ray ray;
+    ray.pos = camera_to_world[3].xyz;
+    ray.dir = normalize(mat3(camera_to_world) * vec3(pos, -1.0));
+    return ray;
+}
+
+//Blends between the camera at shutter open and close, t in [0, 1]
+mat4 cameraToWorldAt(float t) {
+    vec3 eye = mix(camera_to_world_open[3].xyz, camera_to_world_close[3].xyz, t);
+    vec3 back = normalize(mix(camera_to_world_open[2].xyz, camera_to_world_close[2].xyz, t));
+    vec3 up = mix(camera_to_world_open[1].xyz, camera_to_world_close[1].xyz, t);
+    vec3 right = normalize(cross(up, back));
+    up = cross(back, right);
+    return mat4(vec4(right, 0.0), vec4(up, 0.0), vec4(back, 0.0), vec4(eye, 1.0));
+}
+
+//Maps a uniform number to an offset from the pixel center, distributed like the pixel filter
+vec2 sampleFilter(float u) {
+    int n = filter_table.length();
+    float fi = u * float(n - 1);
+    int i = min(int(fi), n - 2);
+    vec4 entry = filter_table[i];
+    float offset = mix(entry.x, filter_table[i + 1].x, fi - float(i));
+    //The weight only changes sign at zero crossings, so it has to come from the side of the crossing the offset is on
+    float weight = offset < entry.w ? entry.y : entry.z;
+    return vec2(offset, weight);
+}
+
+//Direction on a cubemap face, in camera space. Faces are laid out 3x2,
+//with right, left and up on the top row, and down, back and front on the bottom row.
+vec3 cubemapDir(vec2 uv) {
+    //uv starts at the bottom, the layout at the top
+    ivec2 cell = min(ivec2(uv.x * 3.0, (1.0 - uv.y) * 2.0), ivec2(2, 1));
+    int face = cell.x + cell.y * 3;
+    vec2 st = vec2(uv.x * 3.0 - float(cell.x), uv.y * 2.0 - float(1 - cell.y)) * 2.0 - 1.0;
+
+    vec3 forward, right, up;
+    if (face == 0) { forward = vec3(1, 0, 0); right = vec3(0, 0, 1); up = vec3(0, 1, 0); }
+    else if (face == 1) { forward = vec3(-1, 0, 0); right = vec3(0, 0, -1); up = vec3(0, 1, 0); }
+    else if (face == 2) { forward = vec3(0, 1, 0); right = vec3(1, 0, 0); up = vec3(0, 0, 1); }
+    else if (face == 3) { forward = vec3(0, -1, 0); right = vec3(1, 0, 0); up = vec3(0, 0, -1); }
+    else if (face == 4) { forward = vec3(0, 0, 1); right = vec3(-1, 0, 0); up = vec3(0, 1, 0); }
+    else { forward = vec3(0, 0, -1); right = vec3(1, 0, 0); up = vec3(0, 1, 0); }
+    return normalize(forward + right * st.x + up * st.y);
+}
 
-Ray rayFromProjview(vec2 uv) {
+//Rays for every projection other than perspective.
+//valid is false for pixels that don't map to any direction, like outside the fisheye circle.
+Ray rayFromProjection(int proj, vec2 uv, out bool valid) {
     vec2 pos = uv * 2.0 - 1.0;
-    float near = 0.02;
-    float far = 1024.0;
-    vec3 origin = (invprojview * vec4(pos, -1.0, 1.0) * near).xyz;
-    vec3 dir = (invprojview * vec4(pos * (far - near), far + near, far - near)).xyz;
+    float aspect = dims.x / dims.y;
+    vec3 eye = camera_to_world[3].xyz;
+    vec3 dir = vec3(0.0, 0.0, -1.0); //Camera space
+    valid = true;
+
     Ray ray;
-    ray.pos = origin;
-    ray.dir = normalize(dir);
+    ray.pos = eye;
+    if (proj == PROJECTION_ORTHOGRAPHIC) {
+        vec2 offset = pos * vec2(aspect, 1.0) * projection_param * 0.5;
+        ray.pos = eye + camera_to_world[0].xyz * offset.x + camera_to_world[1].xyz * offset.y;
+    } else if (proj == PROJECTION_EQUIRECTANGULAR) {
+        float phi = pos.x * PI; //Longitude, 0 is forward
+        float theta = pos.y * PI * 0.5; //Latitude
+        dir = vec3(sin(phi) * cos(theta), sin(theta), -cos(phi) * cos(theta));
+    } else if (proj == PROJECTION_FISHEYE) {
+        //Equidistant: the angle from the center grows linearly with the distance from the center
+        vec2 p = pos * vec2(aspect, 1.0);
+        float r = length(p);
+        valid = r <= 1.0;
+        float angle = r * projection_param * 0.5;
+        vec2 side = r > 0.0 ? p / r : vec2(0.0);
+        dir = vec3(side * sin(angle), -cos(angle));
+    } else if (proj == PROJECTION_CUBEMAP) {
+        dir = cubemapDir(uv);
+    }
+    ray.dir = normalize(mat3(camera_to_world) * dir);
     return ray;
 }
 
+//Concentric mapping (Shirley & Chiu 1997), which keeps the stratification of the sample
+vec2 sampleDisk(vec2 u) {
+    u = u * 2.0 - 1.0;
+    if (u.x == 0.0 && u.y == 0.0) return vec2(0.0);
+    float r, theta;
+    if (abs(u.x) > abs(u.y)) {
+        r = u.x;
+        theta = PI / 4.0 * (u.y / u.x);
+    } else {
+        r = u.y;
+        theta = PI / 2.0 - PI / 4.0 * (u.x / u.y);
+    }
+    return r * vec2(cos(theta), sin(theta));
+}
+
+vec2 samplePolygon(vec2 u) {
+    //Pick a triangle between the center and two corners, then a uniform point inside it
+    float blades = max(aperture_blades, 3.0);
+    float sector = floor(u.x * blades);
+    u.x = u.x * blades - sector;
+    float a0 = aperture_rotation + sector / blades * (2.0 * PI);
+    float a1 = a0 + (2.0 * PI) / blades;
+    return sqrt(u.x) * mix(vec2(cos(a0), sin(a0)), vec2(cos(a1), sin(a1)), u.y);
+}
+
+//Last entry in a CDF in the aperture table that is <= u
+int searchCDF(int start, int count, float u) {
+    int lo = 0;
+    int hi = count - 1;
+    while (lo + 1 < hi) {
+        int mid = (lo + hi) / 2;
+        if (aperture_table[start + mid] <= u) {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    return lo;
+}
+
+vec2 sampleTexture(vec2 u) {
+    int w = int(aperture_dims.x);
+    int h = int(aperture_dims.y);
+
+    int row = searchCDF(0, h + 1, u.y);
+    float r0 = aperture_table[row];
+    float r1 = aperture_table[row + 1];
+    float y = (float(row) + (u.y - r0) / max(r1 - r0, 1e-8)) / float(h);
+
+    int start = h + 1 + row * (w + 1);
+    int col = searchCDF(start, w + 1, u.x);
+    float c0 = aperture_table[start + col];
+    float c1 = aperture_table[start + col + 1];
+    float x = (float(col) + (u.x - c0) / max(c1 - c0, 1e-8)) / float(w);
+
+    //Rows are stored top to bottom
+    return vec2(x * 2.0 - 1.0, 1.0 - y * 2.0);
+}
+
+//Point on the lens, in [-1, 1]
+vec2 sampleAperture(vec2 u) {
+    int shape = int(aperture_shape);
+    if (shape == APERTURE_POLYGON) return samplePolygon(u);
+    if (shape == APERTURE_TEXTURE) return sampleTexture(u);
+    return sampleDisk(u);
+}
+
 void main() {
+    //The dispatch is rounded up, so skip anything outside of the image
+    if (gl_GlobalInvocationID.x >= uint(dims.x) || gl_GlobalInvocationID.y >= uint(dims.y)) return;
+
     uint ray_index = gl_GlobalInvocationID.x + gl_GlobalInvocationID.y * uint(dims.x);
 
     vec2 pixel_coords = vec2(gl_GlobalInvocationID.xy);
-    vec2 uv = pixel_coords / dims + jitter * 0.001;
+    vec2 jitter = sample2D(int(sampler_kind), gl_GlobalInvocationID.xy, uint(dims.x), sample_index, SAMPLE_DIM_PIXEL, seed);
+    vec2 filter_x = sampleFilter(jitter.x);
+    vec2 filter_y = sampleFilter(jitter.y);
+    vec2 uv = (pixel_coords + 0.5 + vec2(filter_x.x, filter_y.x)) / dims;
+
+    float t = sample2D(int(sampler_kind), gl_GlobalInvocationID.xy, uint(dims.x), sample_index, SAMPLE_DIM_TIME, seed).x;
+    float time = mix(shutter.x, shutter.y, t);
+    camera_to_world = cameraToWorldAt(t);
+
+    int proj = int(projection);
+    bool valid = true;
+    Ray ray;
+    if (proj == PROJECTION_PERSPECTIVE) {
+        ray = rayPerspective(uv);
+    } else {
+        ray = rayFromProjection(proj, uv, valid);
+    }
+
+    if (proj == PROJECTION_PERSPECTIVE && aperture_radius > 0.0) {
+        //Thin lens: every ray through the lens meets the pinhole ray on the focal plane
+        vec3 eye = camera_to_world[3].xyz;
+        vec3 forward = -camera_to_world[2].xyz;
+        vec3 focus_point = eye + ray.dir * (focus_distance / dot(ray.dir, forward));
+
+        vec2 lens_u = sample2D(int(sampler_kind), gl_GlobalInvocationID.xy, uint(dims.x), sample_index, SAMPLE_DIM_LENS, seed);
+        vec2 lens = sampleAperture(lens_u) * aperture_radius;
+        ray.pos = eye + camera_to_world[0].xyz * lens.x + camera_to_world[1].xyz * lens.y;
+        ray.dir = normalize(focus_point - ray.pos);
+    }
+
+    //Near and far are measured along the ray
+    ray.pos += ray.dir * near;
 
-    Ray ray = rayFromProjview(uv);
     RawRay rray;
-    rray.pos = vec4(ray.pos, 0.0);
-    rray.dir = vec4(ray.dir, 0.0);
-    rray.pixel = vec4(pixel_coords, 0.0, 0.0);
-    rray.power = vec4(vec3(1.0), 0.0); //power starts at 1
+    rray.pos = vec4(ray.pos, time);
+    rray.dir = vec4(ray.dir, far - near); //w = max distance
+    rray.pixel = vec4(pixel_coords, near, 0.0); //z = distance along the path, for the pixel cone
+    rray.power = vec4(vec3(filter_x.y * filter_y.y), 0.0); //power starts at the filter weight, which is 1 unless the filter has negative lobes
+    if (!valid) {
+        rray.power = vec4(0.0);
+    }
 
     ray_ssbo[ray_index] = rray;
 }
@@ -4,10 +4,18 @@ layout(local_size_x = DISPATCH_SIZE_X, local_size_y = DISPATCH_SIZE_Y, local_siz
 
 layout(rgba32f, binding = 0) uniform image2D img_input; //New sample
 layout(rgba32f, binding = 1) uniform image2D img_output; //Current total
+#if AOV_COUNT > 0
+layout(rgba32f, binding = 2) uniform image2DArray aov_input; //New sample, all AOVs
+layout(rgba32f, binding = 3) uniform image2DArray aov_output; //Current total, all AOVs
+#endif
 
+uniform vec2 dims;
 uniform float samples;
 
 void main() {
+    //The dispatch is rounded up, so skip anything outside of the image
+    if (gl_GlobalInvocationID.x >= uint(dims.x) || gl_GlobalInvocationID.y >= uint(dims.y)) return;
+
     vec3 current = imageLoad(img_output, ivec2(gl_GlobalInvocationID.xy)).rgb;
     vec3 new = imageLoad(img_input, ivec2(gl_GlobalInvocationID.xy)).rgb;
 
@@ -16,4 +24,21 @@ void main() {
     vec3 final = mix(current, new, m);
 
     imageStore(img_output, ivec2(gl_GlobalInvocationID.xy), vec4(final, 1.0));
+
+#if AOV_COUNT > 0
+    for (int layer = 0; layer < AOV_COUNT; layer++) {
+        ivec3 coords = ivec3(gl_GlobalInvocationID.xy, layer);
+        vec4 aov_current = imageLoad(aov_output, coords);
+        vec4 aov_new = imageLoad(aov_input, coords);
+        float aov_m = m;
+        //Averaging IDs makes no sense, so they only keep the first sample
+    #ifdef AOV_OBJECT_ID
+        if (layer == AOV_OBJECT_ID) aov_m = samples <= 1.0 ? 1.0 : 0.0;
+    #endif
+    #ifdef AOV_MATERIAL_ID
+        if (layer == AOV_MATERIAL_ID) aov_m = samples <= 1.0 ? 1.0 : 0.0;
+    #endif
+        imageStore(aov_output, coords, mix(aov_current, aov_new, aov_m));
+    }
+#endif
 }
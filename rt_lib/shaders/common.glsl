@@ -7,23 +7,41 @@
 struct RawRayHit {
     vec4 pos_id; //w = object id
     vec4 normal_dist; //xyz = normal, w = distance
-    vec4 pixel; //xy = pixel coords
-    vec4 dir; //xyz = ray dir
-    vec4 power; //rgb = power
+    vec4 pixel; //xy = pixel coords, z = material id, w = time
+    vec4 dir; //xyz = ray dir, w = distance along the path up to the hit
+    vec4 power; //rgb = power, w = path depth, see below
+    vec4 uv; //xy = surface uv, 0 for distance fields. z = medium + 1 if the ray scattered in a medium, 0 otherwise.
+             //w = 1 if the ray walked below the surface of a subsurface material, and got out here
 };
 
 //Raw ray for sending through buffers. Vec4's are used instead of vec3's, because of alignment issues
 //TODO: Better packing
 struct RawRay {
-    vec4 pos; //xyz = position
-    vec4 dir; //xyz = ray dir
-    vec4 pixel; //xy = pixel coords
-    vec4 power; //rgb = power
+    vec4 pos; //xyz = position, w = time
+    vec4 dir; //xyz = ray dir, w = max distance, 0 = unlimited
+    vec4 pixel; //xy = pixel coords, z = distance along the path up to the ray origin. w = medium + 1 of the subsurface walk
+                //if the ray went into a subsurface material, 0 otherwise
+    vec4 power; //rgb = power, w = path depth, see below
 };
 
+//Bounces a path took so far of every type, packed as 8 bits each in a float.
+//Floats hold integers exactly up to 2^24, so 3 types fit.
+#define DEPTH_DIFFUSE 0
+#define DEPTH_GLOSSY 1
+#define DEPTH_TRANSMISSION 2
+
+uint getDepth(float depths, int type) {
+    return (uint(depths) >> (8u * uint(type))) & 0xFFu;
+}
+
+float addDepth(float depths, int type) {
+    return depths + float(1u << (8u * uint(type)));
+}
+
 struct Ray {
     vec3 pos;
     vec3 dir;
+    float time; //In seconds, for motion blur
     vec2 pixel; //The pixel this ray is affecting
     vec3 power;
 };
@@ -31,8 +49,10 @@ struct Ray {
 struct RayHit {
     vec3 pos;
     int objectID; //0 = skybox
+    int materialID;
     vec3 normal; //Surface normal
     float dist;
+    vec2 uv; //Surface uv, only meshes have them
     vec2 pixel; //The pixel this ray is affecting
     vec3 power;
 };
@@ -41,6 +61,7 @@ struct RayHit {
 struct MapInfo {
     float dist;
     int objectID;
+    int materialID;
 };
 
 //TODO: Optimize
@@ -52,27 +73,4 @@ MapInfo mapMin(MapInfo a, MapInfo b) {
     }
 }
 
-#include "raytracing/distance_fields.glsl"
-
-MapInfo map(vec3 pos) {
-    // MapInfo m = MapInfo(sdSphere(pos, 2.0), 1);
-    // m = mapMin(m, MapInfo(sdInfHorizPlane(pos - vec3(0.0, -2.0, 0.0)), 2));
-    // m = mapMin(m, MapInfo(sdSphere(pos - vec3(3.0, 2.0, -0.5), 0.5), 3));
-
-    //Box
-    MapInfo m = MapInfo(sdInfHorizPlane(pos - vec3(0.0, -2.0, 0.0)), 1);
-    m = mapMin(m, MapInfo(sdBox(pos - vec3(-5.0, 0.0, 0.0), vec3(0.25, 3.0, 6.0)), 4));
-    m = mapMin(m, MapInfo(sdBox(pos - vec3( 5.0, 0.0, 0.0), vec3(0.25, 3.0, 6.0)), 2));
-    m = mapMin(m, MapInfo(sdBox(pos - vec3( 0.0, 3.0, 0.0), vec3(5.0, 0.25, 6.0)), 1));
-    m = mapMin(m, MapInfo(sdBox(pos - vec3( 0.0, 0.0, 4.0), vec3(5.0, 3.0, 0.25)), 1));
-    m = mapMin(m, MapInfo(sdBox(pos - vec3( 0.0, 0.0,-6.0), vec3(5.0, 3.0, 0.25)), 1));
-
-    //Objects in room
-    m = mapMin(m, MapInfo(sdSphere(pos - vec3(-1.0, -1.0, 1.0), 1.0), 5));
-    m = mapMin(m, MapInfo(sdSphere(pos - vec3(1.5, -0.7, 0.75), 0.75), 1));
-
-    //Lights
-    m = mapMin(m, MapInfo(sdBox(pos - vec3(0.0, 3.0, 0.0), vec3(1.0, 0.26, 1.0)), 3));
-
-    return m;
-}
+//map() lives in scene.glsl
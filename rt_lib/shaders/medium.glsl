@@ -0,0 +1,98 @@
+#ifndef _INCLUDE_MEDIUM_
+#define _INCLUDE_MEDIUM_
+
+//Participating media as uploaded by volume.rs. Keep the layout in sync with GpuMedium!
+
+#include "random.glsl"
+
+#define DENSITY_CONSTANT 0
+#define DENSITY_NOISE 1
+#define DENSITY_GRID 2
+#define MAX_MEDIA 16
+
+struct RawMedium {
+    vec4 absorption_kind; //w = density kind
+    vec4 scattering_g; //w = Henyey-Greenstein anisotropy
+    vec4 density; //x = scale, y = highest density. Noise: z = frequency, w = octaves. Grids: zw = width and height.
+    vec4 grid_min; //Grids only, w = first layer in the atlas
+    vec4 grid_max; //Grids only, w = layers
+};
+
+layout(std140, binding = 0) uniform medium_block {
+    RawMedium media[MAX_MEDIA];
+};
+
+//Every density grid, stacked along Z
+layout(binding = 2) uniform sampler3D density_atlas;
+
+float valueNoise(vec3 p) {
+    vec3 cell = floor(p);
+    vec3 f = fract(p);
+    f = f * f * (3.0 - 2.0 * f);
+    ivec3 i = ivec3(cell);
+
+    float corners[8];
+    for (int c = 0; c < 8; c++) {
+        ivec3 corner = i + ivec3(c & 1, (c >> 1) & 1, (c >> 2) & 1);
+        corners[c] = float(pcgHash(uint(corner.x) ^ pcgHash(uint(corner.y) ^ pcgHash(uint(corner.z)))) >> 8u) * (1.0 / 16777216.0);
+    }
+    return mix(mix(mix(corners[0], corners[1], f.x), mix(corners[2], corners[3], f.x), f.y),
+               mix(mix(corners[4], corners[5], f.x), mix(corners[6], corners[7], f.x), f.y), f.z);
+}
+
+//Between 0 and 1, every octave has twice the frequency and half the weight of the last
+float fractalNoise(vec3 p, int octaves) {
+    float total = 0.0;
+    float weight = 1.0;
+    float weights = 0.0;
+    for (int i = 0; i < octaves; i++) {
+        total += valueNoise(p) * weight;
+        weights += weight;
+        weight *= 0.5;
+        p *= 2.0;
+    }
+    return total / weights;
+}
+
+//p is in object space for volume objects, and in world space for fog
+float mediumDensity(RawMedium m, vec3 p) {
+    int kind = int(m.absorption_kind.w);
+    if (kind == DENSITY_NOISE) {
+        return m.density.x * fractalNoise(p * m.density.z, int(m.density.w));
+    }
+    if (kind == DENSITY_GRID) {
+        vec3 uvw = (p - m.grid_min.xyz) / (m.grid_max.xyz - m.grid_min.xyz);
+        if (any(lessThan(uvw, vec3(0.0))) || any(greaterThan(uvw, vec3(1.0)))) return 0.0;
+        //Stay half a voxel inside the grid, so interpolation doesn't reach into the next one in the atlas
+        vec3 dims = vec3(m.density.zw, m.grid_max.w);
+        vec3 texel = clamp(uvw * dims, vec3(0.5), dims - 0.5) + vec3(0.0, 0.0, m.grid_min.w);
+        return m.density.x * texture(density_atlas, texel / vec3(textureSize(density_atlas, 0))).r;
+    }
+    return m.density.x;
+}
+
+//Highest extinction anywhere in the medium, over all colour channels
+float mediumMajorant(RawMedium m) {
+    vec3 extinction = m.absorption_kind.rgb + m.scattering_g.rgb;
+    return m.density.y * max(extinction.r, max(extinction.g, extinction.b));
+}
+
+//Henyey-Greenstein phase function, sampled exactly, so scattering doesn't change the power.
+//dir is the direction the ray travelled in, positive g keeps it going mostly the same way.
+vec3 sampleHenyeyGreenstein(vec3 dir, float g, vec2 r) {
+    float cos_theta;
+    if (abs(g) < 1e-3) {
+        cos_theta = 1.0 - 2.0 * r.x;
+    } else {
+        float s = (1.0 - g * g) / (1.0 + g - 2.0 * g * r.x);
+        cos_theta = (1.0 + g * g - s * s) / (2.0 * g);
+    }
+    float sin_theta = sqrt(max(0.0, 1.0 - cos_theta * cos_theta));
+    float phi = 2.0 * PI * r.y;
+
+    vec3 u = normalize(cross(dir, abs(dir.x) > 0.5 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0)));
+    vec3 v = cross(dir, u);
+    return normalize(u * cos(phi) * sin_theta + v * sin(phi) * sin_theta + dir * cos_theta);
+}
+
+#endif
@@ -0,0 +1,158 @@
+#ifndef _INCLUDE_MESH_
+#define _INCLUDE_MESH_
+
+//Triangle meshes as uploaded by scene.rs. Keep the layout in sync with GpuVertex!
+//Mesh objects live in the scene buffer after the distance field objects, with the root of their own BVH in the params.x of their shape definition.
+
+#include "scene.glsl"
+
+struct MeshVertex {
+    vec4 pos_u; //w = u
+    vec4 normal_v; //w = v
+};
+
+layout(std430, binding = 6) buffer mesh_vertex_buffer {
+    MeshVertex mesh_vertices[];
+};
+
+//xyz = vertex indices, in the order the leaves of the mesh BVHs use
+layout(std430, binding = 7) buffer mesh_triangle_buffer {
+    uvec4 mesh_triangles[];
+};
+
+uniform float instance_root; //First node of the BVH over mesh objects, -1 without meshes
+
+struct MeshHit {
+    float dist;
+    int object; //Index in the scene buffer, -1 for a miss
+    int triangle;
+    vec2 barycentric;
+};
+
+//Slab test. Only counts as a hit if the box starts before max_dist.
+bool intersectBox(vec3 origin, vec3 inv_dir, BvhNode node, float max_dist) {
+    vec3 t0 = (node.min_first.xyz - origin) * inv_dir;
+    vec3 t1 = (node.max_count.xyz - origin) * inv_dir;
+    vec3 t_min = min(t0, t1);
+    vec3 t_max = max(t0, t1);
+    float entry = max(max(t_min.x, t_min.y), max(t_min.z, 0.0));
+    float exit = min(min(t_max.x, t_max.y), t_max.z);
+    return entry <= exit && entry < max_dist;
+}
+
+//Möller-Trumbore. Returns the distance along the ray, or -1.0 for a miss.
+float intersectTriangle(vec3 origin, vec3 dir, vec3 v0, vec3 v1, vec3 v2, out vec2 barycentric) {
+    vec3 e1 = v1 - v0;
+    vec3 e2 = v2 - v0;
+    vec3 p = cross(dir, e2);
+    float det = dot(e1, p);
+    if (det == 0.0) return -1.0;
+    float inv_det = 1.0 / det;
+
+    vec3 s = origin - v0;
+    float u = dot(s, p) * inv_det;
+    if (u < 0.0 || u > 1.0) return -1.0;
+    vec3 q = cross(s, e1);
+    float v = dot(dir, q) * inv_det;
+    if (v < 0.0 || u + v > 1.0) return -1.0;
+
+    barycentric = vec2(u, v);
+    return dot(e2, q) * inv_det;
+}
+
+//Traces the BVH of a single mesh object, t is the time as a fraction of the shutter interval
+void traceMeshObject(int i, vec3 origin, vec3 dir, float t, inout MeshHit hit) {
+    SceneObject o = objects[i];
+    vec4 translation = mix(o.translation[0], o.translation[1], t);
+    vec4 rotation = normalize(mix(o.rotation[0], o.rotation[1], t));
+    vec4 inverse = vec4(-rotation.xyz, rotation.w);
+
+    //Into object space. The direction gets scaled too, so distances along the ray stay the same as in world space.
+    vec3 local_origin = rotateQuat(inverse, origin - translation.xyz) / translation.w;
+    vec3 local_dir = rotateQuat(inverse, dir) / translation.w;
+    vec3 inv_dir = 1.0 / local_dir;
+
+    int stack[BVH_STACK_SIZE];
+    int stack_size = 0;
+    stack[stack_size++] = int(shapes[int(o.shape.x)].params.x);
+    while (stack_size > 0) {
+        BvhNode node = bvh_nodes[stack[--stack_size]];
+        if (!intersectBox(local_origin, inv_dir, node, hit.dist)) continue;
+
+        int first = int(node.min_first.w);
+        int count = int(node.max_count.w);
+        if (count > 0) {
+            for (int j = first; j < first + count; j++) {
+                uvec4 triangle = mesh_triangles[j];
+                vec2 barycentric;
+                float d = intersectTriangle(local_origin, local_dir,
+                    mesh_vertices[triangle.x].pos_u.xyz,
+                    mesh_vertices[triangle.y].pos_u.xyz,
+                    mesh_vertices[triangle.z].pos_u.xyz,
+                    barycentric);
+                if (d > 0.0 && d < hit.dist) {
+                    hit.dist = d;
+                    hit.object = i;
+                    hit.triangle = j;
+                    hit.barycentric = barycentric;
+                }
+            }
+        } else {
+            stack[stack_size++] = first;
+            stack[stack_size++] = first + 1;
+        }
+    }
+}
+
+//Closest triangle along the ray, up to max_dist
+MeshHit traceMeshes(vec3 origin, vec3 dir, float time, float max_dist) {
+    MeshHit hit = MeshHit(max_dist, -1, 0, vec2(0.0));
+    if (int(instance_root) < 0) return hit;
+
+    float t = shutterFraction(time);
+    vec3 inv_dir = 1.0 / dir;
+
+    //The BVH over mesh objects is in world space, and leads to the BVH of every object
+    int stack[BVH_STACK_SIZE];
+    int stack_size = 0;
+    stack[stack_size++] = int(instance_root);
+    while (stack_size > 0) {
+        BvhNode node = bvh_nodes[stack[--stack_size]];
+        if (!intersectBox(origin, inv_dir, node, hit.dist)) continue;
+
+        int first = int(node.min_first.w);
+        int count = int(node.max_count.w);
+        if (count > 0) {
+            for (int i = first; i < first + count; i++) {
+                traceMeshObject(i, origin, dir, t, hit);
+            }
+        } else {
+            stack[stack_size++] = first;
+            stack[stack_size++] = first + 1;
+        }
+    }
+    return hit;
+}
+
+//Interpolated normal and uv at a mesh hit. Meshes are two sided, so the normal always faces the ray.
+void meshSurface(MeshHit hit, vec3 dir, float time, out vec3 normal, out vec2 uv) {
+    SceneObject o = objects[hit.object];
+    vec4 rotation = normalize(mix(o.rotation[0], o.rotation[1], shutterFraction(time)));
+
+    uvec4 triangle = mesh_triangles[hit.triangle];
+    MeshVertex a = mesh_vertices[triangle.x];
+    MeshVertex b = mesh_vertices[triangle.y];
+    MeshVertex c = mesh_vertices[triangle.z];
+    vec3 w = vec3(1.0 - hit.barycentric.x - hit.barycentric.y, hit.barycentric);
+
+    //Uniform scaling doesn't change the direction of normals, so rotating them is enough
+    vec3 geometric = rotateQuat(rotation, cross(b.pos_u.xyz - a.pos_u.xyz, c.pos_u.xyz - a.pos_u.xyz));
+    vec3 shading = rotateQuat(rotation, a.normal_v.xyz * w.x + b.normal_v.xyz * w.y + c.normal_v.xyz * w.z);
+    if (dot(shading, shading) == 0.0) shading = geometric;
+    if (dot(geometric, dir) > 0.0) shading = -shading;
+    normal = normalize(shading);
+
+    uv = vec2(a.pos_u.w, a.normal_v.w) * w.x + vec2(b.pos_u.w, b.normal_v.w) * w.y + vec2(c.pos_u.w, c.normal_v.w) * w.z;
+}
+
+#endif
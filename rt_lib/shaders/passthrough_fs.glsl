@@ -1,6 +1,9 @@
 #version 450 core
+#include "tonemap.glsl"
 
 uniform sampler2D tex;
+uniform float exposure;
+uniform float tone_curve;
 
 in VS_OUTPUT {
     vec3 Color;
@@ -12,6 +15,5 @@ out vec4 Color;
 void main()
 {
     Color = texture(tex, IN.UV);
-    Color.rgb = clamp(Color.rgb, vec3(0.0), vec3(1.0)); //Clamp the range
-    Color.rgb = pow(Color.rgb, vec3(1.0/2.2)); //Gamma correct (requires the clamp)
+    Color.rgb = viewTransform(Color.rgb, exposure, int(tone_curve));
 }
@@ -0,0 +1,16 @@
+#ifndef _INCLUDE_QUEUE_
+#define _INCLUDE_QUEUE_
+
+//Live ray counts, see queue.rs. Keep the layout in sync!
+layout(std430, binding = 4) buffer ray_queue {
+    uint ray_count; //Rays in the current wave
+    uint next_ray_count; //Rays spawned for the next wave so far
+    //Indirect dispatch arguments for the current wave
+    uint queue_groups_x;
+    uint queue_groups_y;
+    uint queue_groups_z;
+};
+
+#define QUEUE_GROUP_SIZE (DISPATCH_SIZE_X * DISPATCH_SIZE_Y)
+
+#endif
@@ -0,0 +1,13 @@
+#version 450
+#include "queue.glsl"
+
+layout(local_size_x = 1, local_size_y = 1, local_size_z = 1) in;
+
+//Makes the rays spawned during this wave the current wave
+void main() {
+    ray_count = next_ray_count;
+    next_ray_count = 0u;
+    queue_groups_x = (ray_count + uint(QUEUE_GROUP_SIZE) - 1u) / uint(QUEUE_GROUP_SIZE);
+    queue_groups_y = 1u;
+    queue_groups_z = 1u;
+}
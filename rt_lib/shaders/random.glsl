@@ -0,0 +1,38 @@
+#ifndef _INCLUDE_RANDOM_
+#define _INCLUDE_RANDOM_
+
+//Stateless random number generation, based on the PCG hash from
+//"Hash Functions for GPU Rendering" (Jarzynski & Olano, 2020).
+//CPU side twin lives in rt_lib/src/random.rs
+
+uint pcgHash(uint v) {
+    uint state = v * 747796405u + 2891336453u;
+    uint word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+//Seed unique to a pixel, sample and bounce.
+//The same inputs always give the same sequence, so renders are reproducible.
+uint rngSeed(uint pixel_index, uint sample_index, uint bounce, uint seed) {
+    return pcgHash(pixel_index ^ pcgHash(sample_index ^ pcgHash(bounce ^ pcgHash(seed))));
+}
+
+uint randomUint(inout uint state) {
+    state = pcgHash(state);
+    return state;
+}
+
+//Uniform float in [0, 1)
+float randomFloat(inout uint state) {
+    return float(randomUint(state) >> 8u) * (1.0 / 16777216.0);
+}
+
+vec2 randomVec2(inout uint state) {
+    return vec2(randomFloat(state), randomFloat(state));
+}
+
+vec3 randomVec3(inout uint state) {
+    return vec3(randomFloat(state), randomFloat(state), randomFloat(state));
+}
+
+#endif
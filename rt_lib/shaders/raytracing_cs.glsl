@@ -1,7 +1,8 @@
 #version 450
 #include "common.glsl"
 
-layout(local_size_x = DISPATCH_SIZE_X, local_size_y = DISPATCH_SIZE_Y, local_size_z = 1) in;
+//One invocation per live ray, dispatched from the ray queue
+layout(local_size_x = DISPATCH_SIZE_X * DISPATCH_SIZE_Y, local_size_y = 1, local_size_z = 1) in;
 
 //Output
 layout(std430, binding = 0) buffer rayhit_output {
@@ -14,61 +15,169 @@ layout(std430, binding = 1) buffer ray_buffer {
 };
 
 #include "settings.glsl"
-#include "raytracing/distance_fields.glsl"
+#include "scene.glsl"
+#include "mesh.glsl"
+#include "volume.glsl"
+#include "subsurface.glsl"
+#include "queue.glsl"
 
+#if AOV_COUNT > 0
+layout(rgba32f, binding = 2) uniform image2DArray aov_output;
+#endif
+
+uniform float pixel_cone; //Angle covered by half a pixel
+uniform float far; //Max distance for rays that don't have one
+//For the sampler, tracking through media is random
 uniform vec2 dims;
+uniform float bounce;
+uniform uint sample_index;
+uniform uint seed;
+uniform float sampler_kind;
+
+#include "sampler.glsl"
 
 //For distance fields.
-//For polygonal meshes, the normal will come from the mesh data.
-vec3 calcNormal(vec3 p) {
+//For polygonal meshes, the normal comes from the mesh data, see meshSurface.
+vec3 calcNormal(vec3 p, float time) {
     const float h = 0.0001;
     const vec2 k = vec2(1.0, -1.0);
-    return normalize(k.xyy*map(p + k.xyy*h).dist +
-                     k.yyx*map(p + k.yyx*h).dist +
-                     k.yxy*map(p + k.yxy*h).dist +
-                     k.xxx*map(p + k.xxx*h).dist );
+    return normalize(k.xyy*map(p + k.xyy*h, time).dist +
+                     k.yyx*map(p + k.yyx*h, time).dist +
+                     k.yxy*map(p + k.yxy*h, time).dist +
+                     k.xxx*map(p + k.xxx*h, time).dist );
 }
 
-RayHit trace(Ray ray) {
+//Over-relaxed sphere tracing, from "Enhanced Sphere Tracing" (Keinert et al. 2014).
+//path_length is the distance the path travelled before this ray, which makes the pixel cone wider.
+RayHit trace(Ray ray, float max_dist, float path_length, out int steps) {
     RayHit hit;
     hit.pos = ray.pos;
     hit.objectID = 0;
+    hit.materialID = 0;
     hit.normal = vec3(0.0);
     hit.dist = 0.0;
+    hit.uv = vec2(0.0);
     //Passthrough
     hit.pixel = ray.pixel;
     hit.power = ray.power;
 
+    //Meshes are traced first, so marching can stop as soon as it gets past the closest triangle
+    MeshHit mesh = traceMeshes(ray.pos, ray.dir, ray.time, max_dist);
+    max_dist = mesh.dist;
+
+    float omega = RELAXATION;
+    float prev_radius = 0.0;
+    float step_length = 0.0;
+    steps = MAX_STEPS;
     for (int i = 0; i < MAX_STEPS; i++) {
-        MapInfo m = map(ray.pos + ray.dir * hit.dist);
-        float d = m.dist;
-        if (d < DIST_PRECISION) { //TODO: Step scaling based on i and multiplier
-            hit.pos = ray.pos + ray.dir * hit.dist;
-            hit.normal = calcNormal(hit.pos); //TODO: Only for distance fields, see comment on calcNormal function
-            hit.objectID = m.objectID; //TODO: Actual object ID
+        MapInfo m = map(ray.pos + ray.dir * hit.dist, ray.time);
+        float radius = abs(m.dist);
+        //If the spheres of this and the last position don't overlap, the relaxed step may have jumped over a surface.
+        //Step back, and continue with plain sphere tracing.
+        bool relax_fail = omega > 1.0 && radius + prev_radius < step_length;
+        if (relax_fail) {
+            step_length -= omega * step_length;
+            omega = 1.0;
+        } else {
+            //Anything smaller than the pixel footprint can't be seen anyway
+            float epsilon = max(DIST_PRECISION, pixel_cone * (path_length + hit.dist));
+            if (m.dist < epsilon) {
+                hit.pos = ray.pos + ray.dir * hit.dist;
+                hit.normal = calcNormal(hit.pos, ray.time);
+                hit.objectID = m.objectID;
+                hit.materialID = m.materialID;
+                steps = i + 1;
+                return hit;
+            }
+            step_length = m.dist * omega;
+        }
+        prev_radius = radius;
+        hit.dist += step_length;
+        if (hit.dist > max_dist) {
+            steps = i + 1;
             break;
         }
-        hit.dist += d;
+    }
+
+    //No distance field in front of the mesh
+    if (mesh.object >= 0) {
+        SceneObject o = objects[mesh.object];
+        hit.dist = mesh.dist;
+        hit.pos = ray.pos + ray.dir * mesh.dist;
+        hit.objectID = int(o.shape.z);
+        hit.materialID = int(o.shape.y);
+        meshSurface(mesh, ray.dir, ray.time, hit.normal, hit.uv);
     }
     return hit;
 }
 
 void main() {
-    uint ray_index = gl_GlobalInvocationID.x + gl_GlobalInvocationID.y * uint(dims.x);
+    uint ray_index = gl_GlobalInvocationID.x;
+    //The dispatch is rounded up, so skip anything past the last ray
+    if (ray_index >= ray_count) return;
     RawRay rray = ray_ssbo[ray_index];
     Ray ray;
     ray.pos = rray.pos.xyz;
     ray.dir = rray.dir.xyz;
+    ray.time = rray.pos.w;
     ray.pixel = rray.pixel.xy;
     ray.power = rray.power.rgb;
 
-    RayHit hit = trace(ray);
+    vec3 weight = vec3(1.0);
+    int medium = -1;
+    uint dimension = SAMPLE_DIM_BOUNCE + uint(bounce) * SAMPLE_DIMS_PER_BOUNCE + 2u;
+    vec2 u = sample2D(int(sampler_kind), uvec2(ray.pixel), uint(dims.x), sample_index, dimension, seed);
+    uint rng = rngSeed(uint(ray.pixel.x) + uint(ray.pixel.y) * uint(dims.x), sample_index, dimension, seed);
+
+    int steps = 0;
+    RayHit hit;
+    int subsurface = int(rray.pixel.w) - 1;
+    if (subsurface >= 0) {
+        //Rays that went into a subsurface material walk below the surface instead of being traced.
+        //Getting back out counts as a hit on the surface there, flagged in uv.w.
+        vec3 exit_pos;
+        float walked;
+        bool exited = walkSubsurface(ray.pos, ray.dir, ray.time, subsurface, rng, weight, exit_pos, walked);
+        MapInfo m = map(exit_pos, ray.time);
+        hit.pos = exit_pos;
+        hit.objectID = exited ? m.objectID : 0;
+        hit.materialID = m.materialID;
+        hit.normal = calcNormal(exit_pos, ray.time);
+        hit.dist = walked;
+        hit.uv = vec2(0.0);
+        hit.pixel = ray.pixel;
+        hit.power = ray.power;
+    } else {
+        float max_dist = rray.dir.w > 0.0 ? rray.dir.w : far;
+        hit = trace(ray, max_dist, rray.pixel.z, steps);
+
+        //Media between the ray and the surface can scatter it before it gets there.
+        //A scattered ray counts as a hit without an object, with the medium in uv.z.
+        float scatter_dist;
+        if (trackVolumes(ray.pos, ray.dir, ray.time, hit.objectID > 0 ? hit.dist : max_dist, u, rng, weight, scatter_dist, medium)) {
+            hit.dist = scatter_dist;
+            hit.pos = ray.pos + ray.dir * scatter_dist;
+            hit.normal = vec3(0.0);
+            hit.objectID = 0;
+            hit.materialID = -1;
+            hit.uv = vec2(0.0);
+        }
+    }
+
     RawRayHit rhit;
     rhit.pos_id = vec4(hit.pos, float(hit.objectID));
     rhit.normal_dist = vec4(hit.normal, hit.dist);
-    rhit.pixel = vec4(hit.pixel, 0.0, 0.0);
-    rhit.dir = vec4(ray.dir, 0.0);
-    rhit.power = rray.power;
+    rhit.pixel = vec4(hit.pixel, float(hit.materialID), ray.time);
+    rhit.dir = vec4(ray.dir, rray.pixel.z + hit.dist);
+    rhit.power = vec4(rray.power.rgb * weight, rray.power.w);
+    rhit.uv = vec4(hit.uv, float(medium + 1), subsurface >= 0 ? 1.0 : 0.0);
 
     ray_hit[ray_index] = rhit;
+
+#ifdef AOV_STEPS
+    //Every pixel has at most one ray in a wave, so nothing else writes this pixel
+    ivec3 coords = ivec3(ray.pixel, AOV_STEPS);
+    float total = imageLoad(aov_output, coords).r;
+    imageStore(aov_output, coords, vec4(total + float(steps), 0.0, 0.0, 1.0));
+#endif
 }
@@ -0,0 +1,137 @@
+#ifndef _INCLUDE_SAMPLER_
+#define _INCLUDE_SAMPLER_
+
+//Sample sequences for pixel jitter, lens and bounce directions.
+//CPU side twin lives in rt_lib/src/sampler.rs, keep both in sync!
+
+#include "random.glsl"
+
+#define SAMPLER_RANDOM 0
+#define SAMPLER_SOBOL 1
+#define SAMPLER_HALTON 2
+#define SAMPLER_BLUE_NOISE 3
+
+//Every 2D sample a path needs has its own dimension
+#define SAMPLE_DIM_PIXEL 0u
+#define SAMPLE_DIM_LENS 1u
+#define SAMPLE_DIM_TIME 2u
+#define SAMPLE_DIM_BOUNCE 3u //+ bounce index * SAMPLE_DIMS_PER_BOUNCE
+#define SAMPLE_DIMS_PER_BOUNCE 3u //Direction, Russian roulette, volume tracking
+
+float uintToFloat(uint x) {
+    return float(x >> 8u) * (1.0 / 16777216.0);
+}
+
+//Owen scrambling, from "Practical Hash-based Owen Scrambling" (Burley, 2020)
+uint laineKarrasPermutation(uint x, uint seed) {
+    x += seed;
+    x ^= x * 0x6c50b47cu;
+    x ^= x * 0xb82f1e52u;
+    x ^= x * 0xc7afe638u;
+    x ^= x * 0x8d22f6e6u;
+    return x;
+}
+
+uint nestedUniformScramble(uint x, uint seed) {
+    x = bitfieldReverse(x);
+    x = laineKarrasPermutation(x, seed);
+    x = bitfieldReverse(x);
+    return x;
+}
+
+//Second dimension of the Sobol sequence. The first is just the bit reversed index.
+uint sobolDim1(uint index) {
+    uint result = 0u;
+    uint v = 1u << 31u;
+    for (; index != 0u; index >>= 1u) {
+        if ((index & 1u) != 0u) result ^= v;
+        v ^= v >> 1u;
+    }
+    return result;
+}
+
+//Owen scrambled 2D Sobol. Every pixel and dimension gets its own scramble,
+//and a shuffled index, so dimensions are decorrelated ("padding").
+vec2 sampleSobol(uint pixel_index, uint sample_index, uint dimension, uint seed) {
+    uint scramble = rngSeed(pixel_index, 0u, dimension, seed);
+    uint index = nestedUniformScramble(sample_index, scramble);
+    uint x = nestedUniformScramble(bitfieldReverse(index), pcgHash(scramble ^ 0x1u));
+    uint y = nestedUniformScramble(sobolDim1(index), pcgHash(scramble ^ 0x2u));
+    return vec2(uintToFloat(x), uintToFloat(y));
+}
+
+const uint HALTON_PRIMES[16] = uint[](2u, 3u, 5u, 7u, 11u, 13u, 17u, 19u, 23u, 29u, 31u, 37u, 41u, 43u, 47u, 53u);
+
+//Random permutation of a digit, picked by a hash.
+//The permuted digit is the rank of its key among the keys of all digits.
+uint permuteDigit(uint digit, uint base, uint hash) {
+    uint key = pcgHash(hash ^ digit);
+    uint rank = 0u;
+    for (uint v = 0u; v < base; v++) {
+        uint other = pcgHash(hash ^ v);
+        if (other < key || (other == key && v < digit)) rank++;
+    }
+    return rank;
+}
+
+//Radical inverse with every digit permuted based on the digits before it.
+//This is a nested (Owen style) scramble, which breaks up the correlation between higher bases.
+float scrambledRadicalInverse(uint index, uint base, uint seed) {
+    float inv_base = 1.0 / float(base);
+    float f = inv_base;
+    float result = 0.0;
+    uint prefix = seed;
+    //Scrambling turns trailing zeros into digits, so keep going until the float precision runs out
+    while (f > 1.0 / 16777216.0) {
+        uint digit = index % base;
+        result += f * float(permuteDigit(digit, base, prefix));
+        prefix = pcgHash(prefix ^ digit);
+        index /= base;
+        f *= inv_base;
+    }
+    return result;
+}
+
+//Owen scrambled Halton. Every pixel and dimension gets its own scramble.
+vec2 sampleHalton(uint pixel_index, uint sample_index, uint dimension, uint seed) {
+    uint scramble = rngSeed(pixel_index, 0u, dimension, seed);
+    //Past the prime table, higher bases correlate badly anyway
+    if (dimension >= 8u) return randomVec2(scramble);
+    return vec2(
+        scrambledRadicalInverse(sample_index, HALTON_PRIMES[dimension * 2u], pcgHash(scramble ^ 0x1u)),
+        scrambledRadicalInverse(sample_index, HALTON_PRIMES[dimension * 2u + 1u], pcgHash(scramble ^ 0x2u))
+    );
+}
+
+//R2 sequence ("The Unreasonable Effectiveness of Quasirandom Sequences", Roberts 2018)
+#define R2_ALPHA vec2(0.7548776662466927, 0.5698402909980532)
+//Same, as 0.32 fixed point, so the sequence stays exact for any sample count
+#define R2_ALPHA_FIXED uvec2(3242174889u, 2447445414u)
+
+//Rank-1 lattice over samples, shifted per pixel by a blue noise like dither mask,
+//so the error is spread out as high frequency noise over the screen.
+vec2 sampleBlueNoise(uvec2 pixel, uint sample_index, uint dimension, uint seed) {
+    vec2 p = vec2(pixel);
+    float r2_dither = fract(dot(p, R2_ALPHA));
+    float ign = fract(52.9829189 * fract(dot(p, vec2(0.06711056, 0.00583715))));
+    //Same offset for every pixel, so the mask keeps its spectrum
+    uint rng = rngSeed(0u, 0u, dimension, seed);
+    vec2 offset = randomVec2(rng);
+    uvec2 lattice = R2_ALPHA_FIXED * sample_index;
+    return fract(offset + vec2(r2_dither, ign) + vec2(uintToFloat(lattice.x), uintToFloat(lattice.y)));
+}
+
+vec2 sample2D(int kind, uvec2 pixel, uint width, uint sample_index, uint dimension, uint seed) {
+    uint pixel_index = pixel.x + pixel.y * width;
+    if (kind == SAMPLER_SOBOL) {
+        return sampleSobol(pixel_index, sample_index, dimension, seed);
+    } else if (kind == SAMPLER_HALTON) {
+        return sampleHalton(pixel_index, sample_index, dimension, seed);
+    } else if (kind == SAMPLER_BLUE_NOISE) {
+        return sampleBlueNoise(pixel, sample_index, dimension, seed);
+    }
+    uint rng = rngSeed(pixel_index, sample_index, dimension, seed);
+    return randomVec2(rng);
+}
+
+#endif
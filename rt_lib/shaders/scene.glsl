@@ -0,0 +1,164 @@
+#ifndef _INCLUDE_SCENE_
+#define _INCLUDE_SCENE_
+
+//The scene as uploaded by scene.rs. Keep the layouts in sync with GpuShape, GpuObject and GpuBvhNode!
+
+#include "raytracing/distance_fields.glsl"
+#include "brickmap.glsl"
+
+#define SHAPE_SPHERE 0
+#define SHAPE_BOX 1
+#define SHAPE_PLANE 2
+#define SHAPE_MESH 3 //Traced in mesh.glsl
+#define SHAPE_BRICK_MAP 4
+
+#define DOMAIN_REPEAT 0
+#define DOMAIN_REPEAT_LIMITED 1
+#define DOMAIN_MIRROR 2
+#define DOMAIN_POLAR 3
+#define MAX_DOMAIN_OPS 4
+
+//Shared by every object with the same shape and domain, so instances only cost a transform each
+struct ShapeDef {
+    vec4 shape; //x = shape, y = domain operators
+    vec4 params; //Shape parameters, like the radius or half extents
+    vec4 domain[MAX_DOMAIN_OPS * 2]; //2 per operator, x of the first one is the operator
+};
+
+//Binding 3 holds the materials in the passes after tracing, which don't need the shapes
+layout(std430, binding = 3) buffer shape_buffer {
+    ShapeDef shapes[];
+};
+
+struct SceneObject {
+    vec4 shape; //x = shape definition, y = material id, z = object id
+    vec4 translation[2]; //At shutter open and close, w = uniform scale
+    vec4 rotation[2]; //Quaternions, at shutter open and close
+};
+
+//Objects in the BVH come first, in the order its leaves use
+layout(std430, binding = 2) buffer scene_buffer {
+    SceneObject objects[];
+};
+
+struct BvhNode {
+    vec4 min_first; //w = first child for interior nodes, first object for leaves
+    vec4 max_count; //w = objects in a leaf, 0 for interior nodes
+};
+
+layout(std430, binding = 5) buffer bvh_buffer {
+    BvhNode bvh_nodes[];
+};
+
+//Has to be larger than MAX_DEPTH in bvh.rs
+#define BVH_STACK_SIZE 32
+
+uniform float sdf_count; //Distance field objects, the meshes come after them
+uniform float bounded_count; //Objects in the BVH, the rest is infinite
+uniform float bvh_node_count;
+uniform vec2 shutter; //Open and close time, in seconds
+
+//Time as a fraction of the shutter interval, objects move linearly during it
+float shutterFraction(float time) {
+    return shutter.y > shutter.x ? clamp((time - shutter.x) / (shutter.y - shutter.x), 0.0, 1.0) : 0.0;
+}
+
+vec3 rotateQuat(vec4 q, vec3 v) {
+    return v + 2.0 * cross(q.xyz, cross(q.xyz, v) + q.w * v);
+}
+
+float sdShape(int shape, vec4 params, vec3 p) {
+    if (shape == SHAPE_SPHERE) return sdSphere(p, params.x);
+    if (shape == SHAPE_BOX) return sdBox(p, params.xyz);
+    if (shape == SHAPE_BRICK_MAP) return sdBrickMap(p, int(params.x));
+    return sdInfHorizPlane(p);
+}
+
+//Axes with a spacing of 0 don't repeat
+vec3 applyDomain(vec4 a, vec4 b, vec3 p) {
+    int op = int(a.x);
+    if (op == DOMAIN_REPEAT) {
+        vec3 spacing = a.yzw;
+        return mix(p, p - spacing * round(p / spacing), notEqual(spacing, vec3(0.0)));
+    }
+    if (op == DOMAIN_REPEAT_LIMITED) {
+        vec3 spacing = a.yzw;
+        vec3 last = b.xyz - 1.0;
+        vec3 cell = clamp(round(p / spacing + last * 0.5), vec3(0.0), last);
+        return mix(p, p - spacing * (cell - last * 0.5), notEqual(spacing, vec3(0.0)));
+    }
+    if (op == DOMAIN_MIRROR) {
+        return mix(p, abs(p), greaterThan(a.yzw, vec3(0.0)));
+    }
+    //DOMAIN_POLAR, around the Y axis
+    float sector = 2.0 * PI / a.y;
+    float angle = mod(atan(p.z, p.x) + sector * 0.5, sector) - sector * 0.5;
+    return vec3(cos(angle) * length(p.xz), p.y, sin(angle) * length(p.xz));
+}
+
+//Into object space, t is the time as a fraction of the shutter interval
+vec3 objectLocal(int i, vec3 pos, float t) {
+    SceneObject o = objects[i];
+    vec4 translation = mix(o.translation[0], o.translation[1], t);
+    vec4 rotation = normalize(mix(o.rotation[0], o.rotation[1], t));
+    return rotateQuat(vec4(-rotation.xyz, rotation.w), pos - translation.xyz) / translation.w;
+}
+
+MapInfo mapObject(int i, vec3 pos, float t) {
+    SceneObject o = objects[i];
+    float scale = mix(o.translation[0].w, o.translation[1].w, t);
+
+    //Into object space, and scale the distance back out
+    vec3 local = objectLocal(i, pos, t);
+    ShapeDef def = shapes[int(o.shape.x)];
+    for (int j = 0; j < int(def.shape.y); j++) {
+        local = applyDomain(def.domain[j * 2], def.domain[j * 2 + 1], local);
+    }
+    float d = sdShape(int(def.shape.x), def.params, local) * scale;
+    return MapInfo(d, int(o.shape.z), int(o.shape.y));
+}
+
+//Distance from a point to a box, 0 inside of it
+float boxDistance(vec3 pos, BvhNode node) {
+    vec3 d = max(max(node.min_first.xyz - pos, pos - node.max_count.xyz), 0.0);
+    return length(d);
+}
+
+MapInfo map(vec3 pos, float time) {
+    float t = shutterFraction(time);
+
+    MapInfo m = MapInfo(1e20, 0, 0);
+
+    //An object is never closer than its bounds, so any node further away than the closest object so far can be skipped.
+    //Outside of every object, this gives the exact same distance as checking all of them.
+    if (int(bvh_node_count) > 0) {
+        int stack[BVH_STACK_SIZE];
+        int stack_size = 0;
+        stack[stack_size++] = 0;
+        while (stack_size > 0) {
+            BvhNode node = bvh_nodes[stack[--stack_size]];
+            if (boxDistance(pos, node) >= m.dist) continue;
+
+            int first = int(node.min_first.w);
+            int count = int(node.max_count.w);
+            if (count > 0) {
+                for (int i = first; i < first + count; i++) {
+                    m = mapMin(m, mapObject(i, pos, t));
+                }
+            } else {
+                //Visit the closest child first, so the other one gets skipped more often
+                float left = boxDistance(pos, bvh_nodes[first]);
+                float right = boxDistance(pos, bvh_nodes[first + 1]);
+                stack[stack_size++] = left < right ? first + 1 : first;
+                stack[stack_size++] = left < right ? first : first + 1;
+            }
+        }
+    }
+
+    for (int i = int(bounded_count); i < int(sdf_count); i++) {
+        m = mapMin(m, mapObject(i, pos, t));
+    }
+    return m;
+}
+
+#endif
@@ -2,7 +2,25 @@
 #define _INCLUDE_SETTINGS_
 
 // SETTINGS
+//Injected from RenderSettings, these are only fallbacks.
+#ifndef MAX_STEPS
 #define MAX_STEPS 512
+#endif
+#ifndef DIST_PRECISION
 #define DIST_PRECISION 0.01
+#endif
+#ifndef RELAXATION
+#define RELAXATION 1.2
+#endif
+#ifndef RAY_OFFSET
+#define RAY_OFFSET 0.05
+#endif
+#ifndef MAX_VOLUME_STEPS
+#define MAX_VOLUME_STEPS 256
+#endif
+#ifndef MAX_SUBSURFACE_STEPS
+#define MAX_SUBSURFACE_STEPS 256
+#endif
+//FIREFLY_CLAMP is only defined when clamping is enabled
 
 #endif
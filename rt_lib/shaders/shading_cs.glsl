@@ -1,24 +1,46 @@
 #version 450
 #include "common.glsl"
 
-layout(local_size_x = DISPATCH_SIZE_X, local_size_y = DISPATCH_SIZE_Y, local_size_z = 1) in;
+//One invocation per live ray, dispatched from the ray queue
+layout(local_size_x = DISPATCH_SIZE_X * DISPATCH_SIZE_Y, local_size_y = 1, local_size_z = 1) in;
 layout(rgba32f, binding = 0) uniform image2D img_output;
+#if AOV_COUNT > 0
+layout(rgba32f, binding = 2) uniform image2DArray aov_output;
+#endif
 
 layout(std430, binding = 1) buffer rayhit_input {
     RawRayHit ray_hit[];
 };
 
-uniform vec2 dims;
+uniform float bounce;
+
+#include "queue.glsl"
+#include "settings.glsl"
 
 #include "brdf/mat.glsl"
 #include "brdf/lambert.glsl"
+#include "brdf/materials.glsl"
 #include "brdf/generated.glsl"
 
+#if AOV_COUNT > 0
+void storeAov(int layer, ivec2 pixel_coords, vec4 value) {
+    imageStore(aov_output, ivec3(pixel_coords, layer), value);
+}
+
+void addAov(int layer, ivec2 pixel_coords, vec3 value) {
+    vec3 current = imageLoad(aov_output, ivec3(pixel_coords, layer)).rgb;
+    imageStore(aov_output, ivec3(pixel_coords, layer), vec4(current + value, 1.0));
+}
+#endif
+
 void main() {
-    uint ray_index = gl_GlobalInvocationID.x + gl_GlobalInvocationID.y * uint(dims.x);
+    uint ray_index = gl_GlobalInvocationID.x;
+    //The dispatch is rounded up, so skip anything past the last ray
+    if (ray_index >= ray_count) return;
     RawRayHit rhit = ray_hit[ray_index];
 
     int objectID = int(rhit.pos_id.w);
+    int materialID = int(rhit.pixel.z);
 
     ivec2 pixel_coords = ivec2(rhit.pixel.xy);
 
@@ -28,18 +50,61 @@ void main() {
 
     //TODO: Keep track of colour mask so that bounced light is correct
 
-    if (objectID == 0) { //Ray hit the sky
+    if (objectID == 0) { //Ray hit the sky, or scattered in a medium
         // final = vec3(1.0); //Skybox colour
-    } else { //Ray hit another object
+    } else if (rhit.uv.w == 0.0) { //Ray hit another object, rays getting out of a subsurface material already did where they went in
         //We don't care about the lighting bouncing off this object
         //to the current point we are shading, because this is
         //already handled by the hit on that object.
-        if (objectID == 3) {
-            final = vec3(1.0) * 5.0 * rhit.power.rgb;
-        }
+        final = getEmission(materialID) * rhit.power.rgb;
     }
 
+#ifdef FIREFLY_CLAMP
+    //Scale down rather than clamping every channel, so the colour stays the same
+    float brightest = max(final.r, max(final.g, final.b));
+    if (int(bounce) > 0 && brightest > FIREFLY_CLAMP) {
+        final *= FIREFLY_CLAMP / brightest;
+    }
+#endif
+
     //Store the current + final, because to combine all the raywaves, we simply need to add the result together.
     //Every ray only spawns 1 new ray, so we can always just add them together.
     imageStore(img_output, pixel_coords, vec4(current + final, 1.0));
+
+#if AOV_COUNT > 0
+    //The sky leaves the surface AOVs at 0, which they are cleared to every sample
+    if (int(bounce) == 0 && objectID > 0) {
+    #ifdef AOV_ALBEDO
+        storeAov(AOV_ALBEDO, pixel_coords, vec4(getMaterial(materialID).albedo, 1.0));
+    #endif
+    #ifdef AOV_NORMAL
+        storeAov(AOV_NORMAL, pixel_coords, vec4(rhit.normal_dist.xyz, 1.0));
+    #endif
+    #ifdef AOV_DEPTH
+        storeAov(AOV_DEPTH, pixel_coords, vec4(rhit.normal_dist.w, 0.0, 0.0, 1.0));
+    #endif
+    #ifdef AOV_POSITION
+        storeAov(AOV_POSITION, pixel_coords, vec4(rhit.pos_id.xyz, 1.0));
+    #endif
+    #ifdef AOV_UV
+        storeAov(AOV_UV, pixel_coords, vec4(rhit.uv.xy, 0.0, 1.0));
+    #endif
+    #ifdef AOV_OBJECT_ID
+        storeAov(AOV_OBJECT_ID, pixel_coords, vec4(float(objectID), 0.0, 0.0, 1.0));
+    #endif
+    #ifdef AOV_MATERIAL_ID
+        storeAov(AOV_MATERIAL_ID, pixel_coords, vec4(float(materialID), 0.0, 0.0, 1.0));
+    #endif
+    }
+
+    #ifdef AOV_EMISSION
+    if (int(bounce) == 0) addAov(AOV_EMISSION, pixel_coords, final);
+    #endif
+    #ifdef AOV_DIRECT
+    if (int(bounce) == 1) addAov(AOV_DIRECT, pixel_coords, final);
+    #endif
+    #ifdef AOV_INDIRECT
+    if (int(bounce) >= 2) addAov(AOV_INDIRECT, pixel_coords, final);
+    #endif
+#endif
 }
@@ -1,44 +1,37 @@
 #version 450
 #include "common.glsl"
 
-layout(local_size_x = DISPATCH_SIZE_X, local_size_y = DISPATCH_SIZE_Y, local_size_z = 1) in;
+//One invocation per live ray, dispatched from the ray queue
+layout(local_size_x = DISPATCH_SIZE_X * DISPATCH_SIZE_Y, local_size_y = 1, local_size_z = 1) in;
 
-//Input 1
+//Input
 layout(std430, binding = 0) buffer rayhit_input {
     RawRayHit ray_hit[];
 };
 
-//Input 2
-layout(std430, binding = 1) buffer random_input {
-    float random_ssbo[];
-};
-
-//Output
+//Output, packed at the front of the buffer. Only the hits are read here, so this can be the buffer the wave came from.
 layout(std430, binding = 2) buffer ray_buffer {
     RawRay ray_ssbo[];
 };
 
-uniform vec2 dims;
-uniform float samples;
-
-float hash1(inout float seed) {
-    seed = mod( seed*1.1234567893490423, 13. );
-    return fract(sin(seed += 0.1)*43758.5453123);
-}
-
-vec2 hash2(inout float seed) {
-    seed = mod( seed*1.1234567893490423, 13. );
-    return fract(sin(vec2(seed+=0.1,seed+=0.1))*vec2(43758.5453123,22578.1459123));
-}
-
-vec3 hash3(inout float seed) {
-    seed = mod( seed*1.1234567893490423, 13. );
-    return fract(sin(vec3(seed+=0.1,seed+=0.1,seed+=0.1))*vec3(43758.5453123,22578.1459123,19642.3490423));
-}
-
-vec3 sampleHemisphere(const vec3 n, inout float seed ) {
-  	vec2 r = hash2(seed);
-
+uniform vec2 dims; //For the sampler
+uniform float bounce;
+uniform uint sample_index;
+uniform uint seed;
+uniform float sampler_kind;
+//Path depth limits, see PathDepth in settings.rs
+uniform float max_depth;
+uniform float max_diffuse_depth;
+uniform float max_glossy_depth;
+uniform float max_transmission_depth;
+uniform float roulette_depth;
+
+#include "sampler.glsl"
+#include "queue.glsl"
+#include "settings.glsl"
+
+//Cosine weighted direction around n, from 2 uniform random numbers
+vec3 sampleHemisphere(const vec3 n, vec2 r) {
 	vec3  uu = normalize( cross( n, vec3(0.0,1.0,1.0) ) );
 	vec3  vv = cross( uu, n );
 
@@ -51,60 +44,101 @@ vec3 sampleHemisphere(const vec3 n, inout float seed ) {
     return normalize( rr );
 }
 
+#include "brdf/materials.glsl"
 #include "brdf/generated.glsl"
+#include "medium.glsl"
+
+//Materials below this roughness mostly reflect, so their bounces count as glossy
+#define GLOSSY_ROUGHNESS 0.5
+
+//Type of bounce off a material, one of the DEPTH_ defines.
+//Going into a subsurface material is transmission.
+int bounceType(Material mat) {
+    if (mat.subsurface_medium >= 0) return DEPTH_TRANSMISSION;
+    return mat.roughness < GLOSSY_ROUGHNESS ? DEPTH_GLOSSY : DEPTH_DIFFUSE;
+}
+
+bool withinDepth(float depths) {
+    return getDepth(depths, DEPTH_DIFFUSE) <= uint(max_diffuse_depth)
+        && getDepth(depths, DEPTH_GLOSSY) <= uint(max_glossy_depth)
+        && getDepth(depths, DEPTH_TRANSMISSION) <= uint(max_transmission_depth);
+}
 
 void main() {
-    uint ray_index = gl_GlobalInvocationID.x + gl_GlobalInvocationID.y * uint(dims.x);
-    uint random_index = (ray_index + uint(samples)) % uint(dims.x * dims.y);
+    uint ray_index = gl_GlobalInvocationID.x;
+    //The dispatch is rounded up, so skip anything past the last ray
+    if (ray_index >= ray_count) return;
 
     RawRayHit rhit = ray_hit[ray_index];
     int objectID = int(rhit.pos_id.w);
+    int medium = int(rhit.uv.z) - 1;
 
-    if (objectID > 0) {
+    //Rays that left the scene, can't carry any light anymore or went as deep as they may are dropped
+    if ((objectID > 0 || medium >= 0) && any(notEqual(rhit.power.rgb, vec3(0.0))) && bounce < max_depth) {
         vec3 position = rhit.pos_id.xyz;
         vec3 normal = rhit.normal_dist.xyz;
 
-        // vec2 rng = vec2(random_ssbo[random_index], random_ssbo[random_index2]);
-        // uint seed = uint(samples);
-        vec3 hemiDir = sampleHemisphere(normal, random_ssbo[random_index]);
-        vec3 reflectDir = reflect(rhit.dir.xyz, -normal);
-
-        //TODO: Read from material
-        Material mat;
-        mat.albedo = vec3(1.0);
-        mat.roughness = 1.0;
-        if (objectID == 2) {
-            mat.albedo = vec3(1.0, 0.0, 0.0);
-        } else if (objectID == 4) {
-            mat.albedo = vec3(0.0, 1.0, 0.0);
-        } else if (objectID == 5) {
-            mat.roughness = 0.01;
+        //Seeded from the pixel rather than the ray, so every pixel gets its own sequence
+        uint dimension = SAMPLE_DIM_BOUNCE + uint(bounce) * SAMPLE_DIMS_PER_BOUNCE;
+        vec2 r = sample2D(int(sampler_kind), uvec2(rhit.pixel.xy), uint(dims.x), sample_index, dimension, seed);
+
+        float depths;
+        vec3 newDir;
+        //Side of the surface the new ray starts on, subsurface materials let it in
+        float side = 1.0;
+        int subsurface = -1;
+        if (rhit.uv.w > 0.0) {
+            //Got out of a subsurface material. The walk below the surface already took care of the colour,
+            //the BRDF of the material decides how it leaves.
+            Material mat = getMaterial(int(rhit.pixel.z));
+            depths = rhit.power.w;
+            newDir = sampleHemisphere(normal, r);
+            rhit.power.rgb *= material(mat.brdf, mat, newDir, rhit.dir.xyz, normal, vec3(0.0), vec3(0.0));
+        } else if (medium >= 0) {
+            //Tracking already weighted the power by the scattering, and the phase function is sampled exactly
+            depths = addDepth(rhit.power.w, DEPTH_DIFFUSE);
+            if (!withinDepth(depths)) return;
+            newDir = sampleHenyeyGreenstein(rhit.dir.xyz, media[medium].scattering_g.w, r);
+        } else {
+            Material mat = getMaterial(int(rhit.pixel.z));
+            depths = addDepth(rhit.power.w, bounceType(mat));
+            if (!withinDepth(depths)) return;
+
+            if (mat.subsurface_medium >= 0) {
+                //Light goes in through the surface, the walk below it gives it its colour
+                newDir = sampleHemisphere(-normal, r);
+                side = -1.0;
+                subsurface = mat.subsurface_medium;
+            } else {
+                vec3 hemiDir = sampleHemisphere(normal, r);
+                vec3 reflectDir = reflect(rhit.dir.xyz, -normal);
+
+                newDir = mix(reflectDir, hemiDir, mat.roughness);
+            }
+
+            vec3 viewDir = rhit.dir.xyz;
+            vec3 lightDir = newDir;
+            //Contains the power over each colour channel
+            vec3 brdf = material(mat.brdf, mat, lightDir, viewDir, rhit.normal_dist.xyz, vec3(0.0), vec3(0.0));
+            rhit.power.rgb *= brdf;
         }
 
-        vec3 newDir = mix(reflectDir, hemiDir, mat.roughness);
-
-        vec3 viewDir = rhit.dir.xyz;
-        vec3 lightDir = newDir;
-        //TODO: Swap out for other materials
-        //Contains the power over each colour channel
-        vec3 brdf = material(128519978, mat, lightDir, viewDir, rhit.normal_dist.xyz, vec3(0.0), vec3(0.0));
-        rhit.power.rgb *= brdf;
+        //Russian roulette. Paths survive with a chance based on how much light they can still carry,
+        //and the survivors make up for the terminated ones, so the result stays unbiased.
+        if (bounce >= roulette_depth) {
+            vec3 throughput = abs(rhit.power.rgb);
+            float survival = min(max(throughput.r, max(throughput.g, throughput.b)), 1.0);
+            float u = sample2D(int(sampler_kind), uvec2(rhit.pixel.xy), uint(dims.x), sample_index, dimension + 1u, seed).x;
+            if (u >= survival) return;
+            rhit.power.rgb /= survival;
+        }
 
         RawRay ray;
-        ray.pos = vec4(position + normal * 0.05, 0.0);
+        //Scattering in a medium has no surface to get away from, as the normal is 0
+        ray.pos = vec4(position + normal * RAY_OFFSET * side, rhit.pixel.w); //Bounces happen at the same time
         ray.dir = vec4(newDir, 0.0);
-        ray.pixel = rhit.pixel;
-        ray.power = rhit.power;
-        ray_ssbo[ray_index] = ray;
-    } else {
-        RawRay ray;
-        ray.pos = vec4(0.0);
-        //Setting this to 0.0 essentially eliminates the ray entirely, but
-        //the ray will still attempt to take the maximum amount of steps.
-        //Very bad for optimization, needs fixing.
-        //TODO: Fix this
-        ray.dir = vec4(0.0);
-        ray.pixel = vec4(0.0);
-        ray_ssbo[ray_index] = ray;
+        ray.pixel = vec4(rhit.pixel.xy, rhit.dir.w, float(subsurface + 1));
+        ray.power = vec4(rhit.power.rgb, depths);
+        ray_ssbo[atomicAdd(next_ray_count, 1u)] = ray;
     }
 }
@@ -0,0 +1,63 @@
+#ifndef _INCLUDE_SUBSURFACE_
+#define _INCLUDE_SUBSURFACE_
+
+//Random walk of light below the surface of subsurface materials, see Material::subsurface_medium in material.rs.
+//The walk goes through the medium of the material, until it gets back out of the distance fields it went into.
+
+#include "scene.glsl"
+#include "medium.glsl"
+#include "settings.glsl"
+
+//Distance along the ray to where it gets out of the distance fields it is in, up to max_dist.
+//Inside, the distance field is negative, and how negative it is tells how far the ray can safely go.
+float exitDistance(vec3 pos, vec3 dir, float time, float max_dist) {
+    float t = 0.0;
+    for (int i = 0; i < MAX_STEPS; i++) {
+        float dist = map(pos + dir * t, time).dist;
+        if (dist > -DIST_PRECISION) return t;
+        t -= dist;
+        if (t >= max_dist) break;
+    }
+    return max_dist;
+}
+
+//Walks from pos into dir through medium m, until the walk gets back out. Returns whether it did,
+//with where it got out and how far it walked in total.
+//The medium is homogeneous, so distances are sampled exactly for one colour channel, picked by how much light it still carries.
+//The weight is then divided by the chance over all channels (one-sample MIS), which keeps it from blowing up
+//when the radius is very different per channel, see "Practical and Controllable Subsurface Scattering
+//for Production Path Tracing" (Chiang et al. 2016).
+//Only distance fields have an inside, on meshes the walk gets out right where it went in.
+bool walkSubsurface(vec3 pos, vec3 dir, float time, int m, inout uint rng, inout vec3 weight, out vec3 exit_pos, out float walked) {
+    vec3 scattering = media[m].scattering_g.rgb * media[m].density.x;
+    vec3 extinction = media[m].absorption_kind.rgb * media[m].density.x + scattering;
+    walked = 0.0;
+
+    for (int i = 0; i < MAX_SUBSURFACE_STEPS; i++) {
+        vec3 chances = weight / dot(weight, vec3(1.0));
+        vec2 r = randomVec2(rng);
+        int channel = r.x < chances.r ? 0 : (r.x < chances.r + chances.g ? 1 : 2);
+        float collision = -log(1.0 - r.y) / extinction[channel];
+
+        float exit = exitDistance(pos, dir, time, collision);
+        vec3 transmittance = exp(-extinction * min(exit, collision));
+        if (exit < collision) {
+            weight *= transmittance / dot(transmittance, chances);
+            exit_pos = pos + dir * exit;
+            walked += exit;
+            return true;
+        }
+
+        weight *= scattering * transmittance / dot(extinction * transmittance, chances);
+        pos += dir * collision;
+        walked += collision;
+        dir = sampleHenyeyGreenstein(dir, media[m].scattering_g.w, randomVec2(rng));
+        if (all(equal(weight, vec3(0.0)))) break;
+    }
+    //Lost below the surface, as if it got absorbed
+    exit_pos = pos;
+    weight = vec3(0.0);
+    return false;
+}
+
+#endif
@@ -0,0 +1,107 @@
+#ifndef _INCLUDE_TONEMAP_
+#define _INCLUDE_TONEMAP_
+
+//GPU side twin of rt_lib/src/tonemap.rs. Keep both in sync!
+
+#define TONE_CURVE_CLAMP 0
+#define TONE_CURVE_REINHARD 1
+#define TONE_CURVE_ACES_FILMIC 2
+#define TONE_CURVE_FILMIC 3
+#define TONE_CURVE_AGX 4
+
+float srgbOETF(float x) {
+    return x <= 0.0031308 ? x * 12.92 : 1.055 * pow(x, 1.0 / 2.4) - 0.055;
+}
+
+vec3 srgbOETF(vec3 v) {
+    return vec3(srgbOETF(v.r), srgbOETF(v.g), srgbOETF(v.b));
+}
+
+vec3 reinhard(vec3 v) {
+    return v / (1.0 + v);
+}
+
+//Stephen Hill's fit of the ACES RRT + sRGB ODT
+const mat3 ACES_INPUT_MAT = mat3(
+    0.59719, 0.07600, 0.02840,
+    0.35458, 0.90834, 0.13383,
+    0.04823, 0.01566, 0.83777
+);
+
+const mat3 ACES_OUTPUT_MAT = mat3(
+     1.60475, -0.10208, -0.00327,
+    -0.53108,  1.10813, -0.07276,
+    -0.07367, -0.00605,  1.07602
+);
+
+vec3 acesFilmic(vec3 v) {
+    v = ACES_INPUT_MAT * v;
+    v = (v * (v + 0.0245786) - 0.000090537) / (v * (0.983729 * v + 0.4329510) + 0.238081);
+    return ACES_OUTPUT_MAT * v;
+}
+
+//John Hable's filmic curve
+#define HABLE_WHITE 11.2
+
+vec3 hable(vec3 x) {
+    const float A = 0.15;
+    const float B = 0.50;
+    const float C = 0.10;
+    const float D = 0.20;
+    const float E = 0.02;
+    const float F = 0.30;
+    return ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F;
+}
+
+vec3 filmic(vec3 v) {
+    return hable(v * 2.0) / hable(vec3(HABLE_WHITE));
+}
+
+//Polynomial approximation of the AgX base transform
+const mat3 AGX_MAT = mat3(
+    0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+    0.0784335999999992, 0.878468636469772, 0.0784336,
+    0.0792237451477643, 0.0791661274605434, 0.879142973793104
+);
+
+const mat3 AGX_MAT_INV = mat3(
+    1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+    -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+    -0.0990297440797205, -0.0989611768448433, 1.15107367264116
+);
+
+#define AGX_MIN_EV -12.47393
+#define AGX_MAX_EV 4.026069
+
+vec3 agxContrast(vec3 x) {
+    vec3 x2 = x * x;
+    vec3 x4 = x2 * x2;
+    return 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232;
+}
+
+vec3 agx(vec3 v) {
+    v = AGX_MAT * v;
+    //log2(0) is -inf, which the clamp takes care of
+    v = clamp(log2(max(v, vec3(1e-10))), AGX_MIN_EV, AGX_MAX_EV);
+    v = agxContrast((v - AGX_MIN_EV) / (AGX_MAX_EV - AGX_MIN_EV));
+    v = AGX_MAT_INV * v;
+    //The curve outputs display encoded values, convert back to linear for the OETF
+    return pow(max(v, vec3(0.0)), vec3(2.2));
+}
+
+//Applies exposure (in stops), the tone curve and the sRGB OETF to a linear colour
+vec3 viewTransform(vec3 v, float exposure, int curve) {
+    v *= exp2(exposure);
+    if (curve == TONE_CURVE_REINHARD) {
+        v = reinhard(v);
+    } else if (curve == TONE_CURVE_ACES_FILMIC) {
+        v = acesFilmic(v);
+    } else if (curve == TONE_CURVE_FILMIC) {
+        v = filmic(v);
+    } else if (curve == TONE_CURVE_AGX) {
+        v = agx(v);
+    }
+    return srgbOETF(clamp(v, vec3(0.0), vec3(1.0)));
+}
+
+#endif
@@ -0,0 +1,116 @@
+#ifndef _INCLUDE_VOLUME_
+#define _INCLUDE_VOLUME_
+
+//Tracking rays through participating media, see volume.rs.
+//Volume objects come after all other objects, and have their medium where other objects have their material.
+//They aren't part of map(), so their surfaces are never hit.
+
+#include "scene.glsl"
+#include "medium.glsl"
+#include "settings.glsl"
+
+uniform float fog_medium; //-1 without fog
+uniform float volume_first; //First volume object
+uniform float volume_count;
+uniform float volume_nodes; //First of the boxes around every volume object in bvh_nodes
+
+//Where the ray is inside of a box, clamped to start at the origin
+bool boxInterval(vec3 origin, vec3 inv_dir, BvhNode node, out float entry, out float exit) {
+    vec3 t0 = (node.min_first.xyz - origin) * inv_dir;
+    vec3 t1 = (node.max_count.xyz - origin) * inv_dir;
+    vec3 t_min = min(t0, t1);
+    vec3 t_max = max(t0, t1);
+    entry = max(max(t_min.x, t_min.y), max(t_min.z, 0.0));
+    exit = min(min(t_max.x, t_max.y), t_max.z);
+    return entry <= exit;
+}
+
+//Absorption and scattering of all media at a point. Where media overlap, they add up,
+//and the one that scatters the most decides the phase function.
+void mediaAt(vec3 pos, float t, out vec3 absorption, out vec3 scattering, out int medium) {
+    absorption = vec3(0.0);
+    scattering = vec3(0.0);
+    medium = -1;
+    float strongest = 0.0;
+
+    int fog = int(fog_medium);
+    if (fog >= 0) {
+        float density = mediumDensity(media[fog], pos);
+        absorption += media[fog].absorption_kind.rgb * density;
+        scattering += media[fog].scattering_g.rgb * density;
+        medium = fog;
+        strongest = dot(scattering, vec3(1.0));
+    }
+
+    for (int i = int(volume_first); i < int(volume_first) + int(volume_count); i++) {
+        if (mapObject(i, pos, t).dist >= 0.0) continue;
+        int m = int(objects[i].shape.y);
+        float density = mediumDensity(media[m], objectLocal(i, pos, t));
+        vec3 s = media[m].scattering_g.rgb * density;
+        absorption += media[m].absorption_kind.rgb * density;
+        scattering += s;
+        if (dot(s, vec3(1.0)) > strongest) {
+            strongest = dot(s, vec3(1.0));
+            medium = m;
+        }
+    }
+}
+
+//Tracks a ray up to max_dist, and returns whether it scattered before that. u are the first 2 random numbers,
+//the rest comes from rng.
+//Tentative collisions are spaced out by the majorant, the sum of the highest extinction of every medium on the way.
+//Each one either scatters, with a chance following the scattering coefficient, or is a null collision that
+//the ray continues through. The power is weighted by the ratio of the real and sampled chance of every event, per channel.
+//For media that only absorb this is ratio tracking, for grey media that only scatter it is delta tracking
+//(see "Residual Ratio Tracking" (Novák et al. 2014) and "Spectral and Decomposition Tracking" (Kutz et al. 2017)).
+bool trackVolumes(vec3 origin, vec3 dir, float time, float max_dist, vec2 u, inout uint rng, inout vec3 weight, out float scatter_dist, out int scatter_medium) {
+    scatter_dist = max_dist;
+    scatter_medium = -1;
+    float t = shutterFraction(time);
+
+    //Only the part of the ray that goes through media has to be tracked
+    float majorant = 0.0;
+    float start = max_dist;
+    float end = 0.0;
+    int fog = int(fog_medium);
+    if (fog >= 0) {
+        majorant += mediumMajorant(media[fog]);
+        start = 0.0;
+        end = max_dist;
+    }
+    vec3 inv_dir = 1.0 / dir;
+    for (int j = 0; j < int(volume_count); j++) {
+        float entry, exit;
+        if (!boxInterval(origin, inv_dir, bvh_nodes[int(volume_nodes) + j], entry, exit) || entry >= max_dist) continue;
+        majorant += mediumMajorant(media[int(objects[int(volume_first) + j].shape.y)]);
+        start = min(start, entry);
+        end = max(end, min(exit, max_dist));
+    }
+    if (majorant <= 0.0 || start >= end) return false;
+
+    float dist = start;
+    for (int i = 0; i < MAX_VOLUME_STEPS; i++) {
+        vec2 r = i == 0 ? u : randomVec2(rng);
+        dist -= log(1.0 - r.x) / majorant;
+        if (dist >= end) return false;
+
+        vec3 pos = origin + dir * dist;
+        vec3 absorption, scattering;
+        int medium;
+        mediaAt(pos, t, absorption, scattering, medium);
+        vec3 null_collision = max(vec3(majorant) - absorption - scattering, 0.0);
+
+        float scatter_chance = dot(scattering, vec3(1.0 / 3.0)) / majorant;
+        if (r.y < scatter_chance) {
+            weight *= scattering / (majorant * scatter_chance);
+            scatter_dist = dist;
+            scatter_medium = medium;
+            return true;
+        }
+        weight *= null_collision / (majorant * (1.0 - scatter_chance));
+    }
+    //Out of steps, carry on to the surface as if nothing happened
+    return false;
+}
+
+#endif
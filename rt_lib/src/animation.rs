@@ -0,0 +1,375 @@
+//! Keyframe animation. Everything is evaluated on the CPU,
+//! the GPU only ever sees the values at the shutter open and close times.
+
+use glam::*;
+
+use crate::objects::{Camera, CameraPose, Material};
+use crate::scene::Transform;
+
+/// How a keyframe blends into the next one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Holds the value until the next keyframe.
+    Constant,
+    Linear,
+    /// Cubic bezier, with the handles of both keyframes as the inner control points.
+    /// With both handles at their default, this eases in and out.
+    Bezier,
+}
+
+/// Anything that can be keyframed.
+pub trait Animatable: Copy + PartialEq {
+    /// The value a handle has when it doesn't do anything.
+    fn zero_handle() -> Self;
+    fn lerp(self, other: Self, t: f32) -> Self;
+    /// Applies a bezier handle to a value.
+    fn offset(self, handle: Self) -> Self;
+}
+
+impl Animatable for f32 {
+    fn zero_handle() -> Self {
+        0.0
+    }
+
+    fn lerp(self, other: Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+
+    fn offset(self, handle: Self) -> Self {
+        self + handle
+    }
+}
+
+impl Animatable for Vec3 {
+    fn zero_handle() -> Self {
+        Vec3::ZERO
+    }
+
+    fn lerp(self, other: Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+
+    fn offset(self, handle: Self) -> Self {
+        self + handle
+    }
+}
+
+impl Animatable for Quat {
+    fn zero_handle() -> Self {
+        Quat::IDENTITY
+    }
+
+    fn lerp(self, other: Self, t: f32) -> Self {
+        self.slerp(other, t)
+    }
+
+    /// Handles are rotations applied on top of the keyframe.
+    fn offset(self, handle: Self) -> Self {
+        (handle * self).normalize()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keyframe<T: Animatable> {
+    /// In seconds.
+    pub time: f32,
+    pub value: T,
+    /// Interpolation towards the next keyframe.
+    pub interpolation: Interpolation,
+    /// Bezier handle towards the previous keyframe, relative to the value.
+    pub in_handle: T,
+    /// Bezier handle towards the next keyframe, relative to the value.
+    pub out_handle: T,
+}
+
+impl<T: Animatable> Keyframe<T> {
+    pub fn new(time: f32, value: T, interpolation: Interpolation) -> Self {
+        Self {
+            time: time,
+            value: value,
+            interpolation: interpolation,
+            in_handle: T::zero_handle(),
+            out_handle: T::zero_handle(),
+        }
+    }
+
+    pub fn with_handles(mut self, in_handle: T, out_handle: T) -> Self {
+        self.in_handle = in_handle;
+        self.out_handle = out_handle;
+        self
+    }
+}
+
+/// A value changing over time. Before the first and after the last keyframe, the value is held.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Track<T: Animatable> {
+    keys: Vec<Keyframe<T>>,
+}
+
+impl<T: Animatable> Default for Track<T> {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+        }
+    }
+}
+
+impl<T: Animatable> Track<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a keyframe, replacing any keyframe at the exact same time.
+    pub fn insert(&mut self, key: Keyframe<T>) {
+        match self.keys.binary_search_by(|k| k.time.partial_cmp(&key.time).expect("Keyframe time is NaN!")) {
+            Ok(i) => self.keys[i] = key,
+            Err(i) => self.keys.insert(i, key),
+        }
+    }
+
+    /// Builder style `insert`.
+    pub fn key(mut self, time: f32, value: T, interpolation: Interpolation) -> Self {
+        self.insert(Keyframe::new(time, value, interpolation));
+        self
+    }
+
+    pub fn keys(&self) -> &[Keyframe<T>] {
+        &self.keys
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Value at a point in time, in seconds. `None` if the track has no keyframes.
+    pub fn evaluate(&self, time: f32) -> Option<T> {
+        let first = self.keys.first()?;
+        let last = self.keys.last()?;
+        if time <= first.time {
+            return Some(first.value);
+        }
+        if time >= last.time {
+            return Some(last.value);
+        }
+
+        //First keyframe after the time. Can't be 0, as time > first.time
+        let next = self.keys.partition_point(|k| k.time <= time);
+        let a = &self.keys[next - 1];
+        let b = &self.keys[next];
+        let t = (time - a.time) / (b.time - a.time);
+
+        Some(match a.interpolation {
+            Interpolation::Constant => a.value,
+            Interpolation::Linear => a.value.lerp(b.value, t),
+            Interpolation::Bezier => {
+                //De Casteljau, which also works for rotations
+                let p0 = a.value;
+                let p1 = a.value.offset(a.out_handle);
+                let p2 = b.value.offset(b.in_handle);
+                let p3 = b.value;
+                let q0 = p0.lerp(p1, t);
+                let q1 = p1.lerp(p2, t);
+                let q2 = p2.lerp(p3, t);
+                let r0 = q0.lerp(q1, t);
+                let r1 = q1.lerp(q2, t);
+                r0.lerp(r1, t)
+            },
+        })
+    }
+
+    /// Evaluates the track, falling back to a value if it has no keyframes.
+    pub fn evaluate_or(&self, time: f32, default: T) -> T {
+        self.evaluate(time).unwrap_or(default)
+    }
+}
+
+/// Keyframes for a camera. Empty tracks leave that part of the camera alone.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CameraAnimation {
+    pub eye: Track<Vec3>,
+    pub look_at: Track<Vec3>,
+    /// Vertical field of view in degrees.
+    pub fov: Track<f32>,
+}
+
+impl CameraAnimation {
+    /// Poses the camera for a shutter interval, in seconds.
+    /// The pose at shutter close is used for motion blur.
+    pub fn apply(&self, camera: &mut Camera, shutter: (f32, f32)) {
+        let pose_at = |time: f32| CameraPose {
+            eye: self.eye.evaluate_or(time, camera.eye),
+            look_at: self.look_at.evaluate_or(time, camera.look_at),
+            up: camera.up,
+        };
+        let open = pose_at(shutter.0);
+        let close = pose_at(shutter.1);
+
+        camera.eye = open.eye;
+        camera.look_at = open.look_at;
+        camera.motion = if close != open { Some(close) } else { None };
+        camera.fov = self.fov.evaluate_or(shutter.0, camera.fov);
+        camera.shutter = shutter;
+    }
+}
+
+/// Keyframes for an object transform. Empty tracks keep the value of the object's own transform.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TransformAnimation {
+    pub translation: Track<Vec3>,
+    pub rotation: Track<Quat>,
+    pub scale: Track<f32>,
+}
+
+impl TransformAnimation {
+    pub fn evaluate(&self, time: f32, base: &Transform) -> Transform {
+        Transform {
+            translation: self.translation.evaluate_or(time, base.translation),
+            rotation: self.rotation.evaluate_or(time, base.rotation),
+            scale: self.scale.evaluate_or(time, base.scale),
+        }
+    }
+}
+
+/// Keyframes for material parameters. Empty tracks keep the value of the material itself.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MaterialAnimation {
+    pub albedo: Track<Vec3>,
+    pub roughness: Track<f32>,
+    pub metallic: Track<f32>,
+    pub emission: Track<Vec3>,
+    pub subsurface: Track<Vec3>,
+}
+
+impl MaterialAnimation {
+    pub fn evaluate(&self, time: f32, base: &Material) -> Material {
+        Material {
+            albedo: self.albedo.evaluate_or(time, base.albedo),
+            roughness: self.roughness.evaluate_or(time, base.roughness),
+            metallic: self.metallic.evaluate_or(time, base.metallic),
+            emission: self.emission.evaluate_or(time, base.emission),
+            subsurface: self.subsurface.evaluate_or(time, base.subsurface),
+            brdf: base.brdf.clone(),
+            animation: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    /// Rotations are equal when their dot product is +-1, as q and -q are the same rotation.
+    fn same_rotation(a: Quat, b: Quat) -> bool {
+        a.dot(b).abs() > 1.0 - 1e-5
+    }
+
+    #[test]
+    fn empty_track() {
+        let track: Track<f32> = Track::new();
+        assert_eq!(track.evaluate(0.0), None);
+        assert_eq!(track.evaluate_or(1.0, 5.0), 5.0);
+    }
+
+    #[test]
+    fn clamps_outside_keys() {
+        let track = Track::new()
+            .key(1.0, 2.0, Interpolation::Linear)
+            .key(3.0, 4.0, Interpolation::Linear);
+        assert_eq!(track.evaluate(-10.0), Some(2.0));
+        assert_eq!(track.evaluate(1.0), Some(2.0));
+        assert_eq!(track.evaluate(3.0), Some(4.0));
+        assert_eq!(track.evaluate(10.0), Some(4.0));
+    }
+
+    #[test]
+    fn constant_and_linear_midpoints() {
+        let constant = Track::new()
+            .key(0.0, 1.0, Interpolation::Constant)
+            .key(2.0, 3.0, Interpolation::Constant);
+        assert_eq!(constant.evaluate(1.0), Some(1.0));
+        assert_eq!(constant.evaluate(1.999), Some(1.0));
+
+        let linear = Track::new()
+            .key(0.0, vec3(0.0, 2.0, -4.0), Interpolation::Linear)
+            .key(2.0, vec3(4.0, 2.0, 0.0), Interpolation::Linear);
+        assert_eq!(linear.evaluate(1.0), Some(vec3(2.0, 2.0, -2.0)));
+        assert_eq!(linear.evaluate(0.5), Some(vec3(1.0, 2.0, -3.0)));
+    }
+
+    #[test]
+    fn interpolation_of_the_earlier_key_is_used() {
+        let track = Track::new()
+            .key(0.0, 0.0, Interpolation::Linear)
+            .key(1.0, 1.0, Interpolation::Constant)
+            .key(2.0, 3.0, Interpolation::Linear);
+        assert!(close(track.evaluate(0.5).unwrap(), 0.5));
+        assert!(close(track.evaluate(1.5).unwrap(), 1.0));
+    }
+
+    #[test]
+    fn bezier_without_handles() {
+        let track = Track::new()
+            .key(0.0, 0.0, Interpolation::Bezier)
+            .key(1.0, 1.0, Interpolation::Bezier);
+        assert!(close(track.evaluate(0.0).unwrap(), 0.0));
+        assert!(close(track.evaluate(1.0).unwrap(), 1.0));
+        assert!(close(track.evaluate(0.5).unwrap(), 0.5));
+        for i in 1..10 {
+            let t = i as f32 / 10.0;
+            let a = track.evaluate(t).unwrap();
+            let b = track.evaluate(1.0 - t).unwrap();
+            //Eases in and out symmetrically, and never overshoots
+            assert!(close(a + b, 1.0), "Not symmetric at {}: {} and {}", t, a, b);
+            assert!(a > 0.0 && a < 1.0);
+        }
+        //Easing means it starts slower than linear
+        assert!(track.evaluate(0.1).unwrap() < 0.1);
+    }
+
+    #[test]
+    fn bezier_handles() {
+        let key = Keyframe::new(0.0, 0.0, Interpolation::Bezier).with_handles(0.0, 1.0);
+        let mut track = Track::new().key(1.0, 1.0, Interpolation::Bezier);
+        track.insert(key);
+        //The out handle pulls the start of the curve up, past the linear value
+        assert!(track.evaluate(0.1).unwrap() > 0.1);
+        assert!(close(track.evaluate(1.0).unwrap(), 1.0));
+    }
+
+    #[test]
+    fn insert_replaces_same_time() {
+        let mut track = Track::new()
+            .key(0.0, 1.0, Interpolation::Linear)
+            .key(2.0, 3.0, Interpolation::Linear);
+        track.insert(Keyframe::new(1.0, 10.0, Interpolation::Linear));
+        track.insert(Keyframe::new(2.0, 5.0, Interpolation::Constant));
+        let times: Vec<f32> = track.keys().iter().map(|k| k.time).collect();
+        assert_eq!(times, vec![0.0, 1.0, 2.0]);
+        assert_eq!(track.keys()[2].value, 5.0);
+        assert_eq!(track.keys()[2].interpolation, Interpolation::Constant);
+        assert_eq!(track.evaluate(1.0), Some(10.0));
+    }
+
+    #[test]
+    fn quat_slerp() {
+        let a = Quat::IDENTITY;
+        let b = Quat::from_rotation_y(std::f32::consts::FRAC_PI_2);
+        let track = Track::new()
+            .key(0.0, a, Interpolation::Linear)
+            .key(1.0, b, Interpolation::Linear);
+        let half = track.evaluate(0.5).unwrap();
+        assert!(same_rotation(half, Quat::from_rotation_y(std::f32::consts::FRAC_PI_4)));
+        assert!(close(half.length(), 1.0));
+
+        //Without handles, bezier rotations stay on the same arc and end up halfway too
+        let bezier = Track::new()
+            .key(0.0, a, Interpolation::Bezier)
+            .key(1.0, b, Interpolation::Bezier);
+        assert!(same_rotation(bezier.evaluate(0.5).unwrap(), half));
+        assert!(same_rotation(bezier.evaluate(1.0).unwrap(), b));
+    }
+}
@@ -0,0 +1,429 @@
+//! Sparse distance fields for large baked assets, sampled in `shaders/brickmap.glsl`.
+//! Space is split into a grid of bricks, and only the bricks near the surface store distances.
+//! Every other cell of the grid stores how far away the surface is at least, so marching can skip it quickly.
+//! Brick maps are placed in the scene through objects with a `Shape::BrickMap`.
+
+use glam::*;
+
+use crate::bvh::{Aabb, Bvh};
+use crate::mesh::TriangleMesh;
+
+/// Samples along every axis of a brick. Neighbouring bricks share their border samples,
+/// so interpolating never has to look at more than one brick.
+pub const BRICK_SIZE: usize = 8;
+const BRICK_VOXELS: usize = BRICK_SIZE * BRICK_SIZE * BRICK_SIZE;
+/// Values in front of the grid of every brick map on the GPU, see `brickmap.glsl`.
+const HEADER_SIZE: usize = 8;
+/// Bricks along the X and Y axis of the atlas, see `ATLAS_BRICKS` in `brickmap.glsl`.
+const ATLAS_BRICKS: usize = 32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BrickMap {
+    /// Position of the first sample, in object space.
+    pub origin: Vec3,
+    /// Distance between samples.
+    pub voxel_size: f32,
+    /// Bricks are only stored where the surface is closer than this.
+    pub band: f32,
+    /// Bricks along every axis.
+    pub dims: UVec3,
+    /// One entry per brick, X first. Stored bricks have their index, every other cell has a negative value,
+    /// see `BrickMap::empty_cell`.
+    pub grid: Vec<i32>,
+    /// `BRICK_SIZE`³ distances for every stored brick, X first.
+    pub bricks: Vec<f32>,
+}
+
+/// Closest point on a triangle, as weights for its corners. From "Real-Time Collision Detection" (Ericson 2004).
+fn closest_on_triangle(p: Vec3, a: Vec3, b: Vec3, c: Vec3) -> Vec3 {
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return vec3(1.0, 0.0, 0.0);
+    }
+
+    let bp = p - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return vec3(0.0, 1.0, 0.0);
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        let v = d1 / (d1 - d3);
+        return vec3(1.0 - v, v, 0.0);
+    }
+
+    let cp = p - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return vec3(0.0, 0.0, 1.0);
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        let w = d2 / (d2 - d6);
+        return vec3(1.0 - w, 0.0, w);
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+        return vec3(0.0, 1.0 - w, w);
+    }
+
+    let denom = 1.0 / (va + vb + vc);
+    let v = vb * denom;
+    let w = vc * denom;
+    //Degenerate triangles end up here with nothing to divide by
+    if !(v.is_finite() && w.is_finite()) {
+        return vec3(1.0, 0.0, 0.0);
+    }
+    vec3(1.0 - v - w, v, w)
+}
+
+impl BrickMap {
+    /// Bakes a distance function. It has to be a proper distance field, or skipping empty cells can skip the surface,
+    /// and everything inside of it has to be within `bounds`.
+    /// `band` has to be at least one voxel, a few voxels give smoother normals.
+    pub fn from_fn(bounds: Aabb, voxel_size: f32, band: f32, mut distance: impl FnMut(Vec3) -> f32) -> Self {
+        assert!(voxel_size > 0.0 && band >= voxel_size, "The narrow band has to be at least one voxel wide!");
+        let span = voxel_size * (BRICK_SIZE - 1) as f32;
+        //Keeps the surface at least a band away from the edge of the grid, see `distance`
+        let padding = Vec3::splat(band + voxel_size);
+        let origin = bounds.min - padding;
+        let dims = ((bounds.max + padding - origin) / span).ceil().max(Vec3::ONE).as_uvec3();
+        let half_diagonal = span * 3f32.sqrt() * 0.5;
+
+        let mut grid = Vec::with_capacity((dims.x * dims.y * dims.z) as usize);
+        let mut bricks = Vec::new();
+        for z in 0..dims.z {
+            for y in 0..dims.y {
+                for x in 0..dims.x {
+                    let corner = origin + uvec3(x, y, z).as_vec3() * span;
+                    let center = distance(corner + Vec3::splat(span * 0.5));
+                    //Nothing in the brick is closer to the surface than its center, minus half its diagonal
+                    if center.abs() > band + half_diagonal {
+                        grid.push(Self::empty_cell(center.abs() - half_diagonal, center < 0.0, voxel_size));
+                        continue;
+                    }
+
+                    let mut samples = Vec::with_capacity(BRICK_VOXELS);
+                    for k in 0..BRICK_SIZE {
+                        for j in 0..BRICK_SIZE {
+                            for i in 0..BRICK_SIZE {
+                                samples.push(distance(corner + vec3(i as f32, j as f32, k as f32) * voxel_size));
+                            }
+                        }
+                    }
+                    //Every point in the brick is within half a voxel diagonal of a sample
+                    let closest = samples.iter().fold(f32::MAX, |closest, d| closest.min(d.abs()));
+                    if closest > band {
+                        grid.push(Self::empty_cell(closest - voxel_size * 3f32.sqrt() * 0.5, samples[0] < 0.0, voxel_size));
+                        continue;
+                    }
+
+                    grid.push((bricks.len() / BRICK_VOXELS) as i32);
+                    bricks.extend(samples);
+                }
+            }
+        }
+
+        let map = Self {
+            origin: origin,
+            voxel_size: voxel_size,
+            band: band,
+            dims: dims,
+            grid: grid,
+            bricks: bricks,
+        };
+        info!("Brick map built with {} of {} bricks, {:.2} MiB instead of {:.2} MiB dense",
+            map.brick_count(), map.grid.len(), map.memory_usage() as f32 / 1048576.0, map.dense_memory_usage() as f32 / 1048576.0);
+        map
+    }
+
+    /// Bakes a closed mesh. The sign comes from the normals of the mesh, so they have to point outwards.
+    pub fn from_mesh(mesh: &TriangleMesh, voxel_size: f32, band: f32) -> Self {
+        let bvh = Bvh::build(&mesh.triangle_bounds());
+        let corners = |t: u32| {
+            let t = t as usize * 3;
+            [mesh.indices[t] as usize, mesh.indices[t + 1] as usize, mesh.indices[t + 2] as usize]
+        };
+
+        let closest_point = |p: Vec3, t: u32| {
+            let [a, b, c] = corners(t);
+            let weights = closest_on_triangle(p, mesh.positions[a], mesh.positions[b], mesh.positions[c]);
+            (mesh.positions[a] * weights.x + mesh.positions[b] * weights.y + mesh.positions[c] * weights.z, weights)
+        };
+
+        Self::from_fn(mesh.bounds(), voxel_size, band, |p| {
+            let (triangle, dist) = match bvh.closest(p, |t| (closest_point(p, t).0 - p).length()) {
+                Some(hit) => hit,
+                None => return f32::MAX,
+            };
+
+            //Near edges and corners the closest point is shared by several triangles,
+            //where the interpolated normal is a lot more reliable than the face normal
+            let (point, weights) = closest_point(p, triangle);
+            let [a, b, c] = corners(triangle);
+            let mut normal = mesh.normals[a] * weights.x + mesh.normals[b] * weights.y + mesh.normals[c] * weights.z;
+            if normal == Vec3::ZERO {
+                normal = (mesh.positions[b] - mesh.positions[a]).cross(mesh.positions[c] - mesh.positions[a]);
+            }
+            if (p - point).dot(normal) < 0.0 { -dist } else { dist }
+        })
+    }
+
+    /// Bakes a point cloud with outward normals, like a scan. The points should be closer together than the voxels,
+    /// as the distance is measured to the closest point rather than to a surface through them.
+    pub fn from_points(points: &[Vec3], normals: &[Vec3], voxel_size: f32, band: f32) -> Self {
+        assert!(points.len() == normals.len(), "Point cloud needs a normal for every point!");
+        let point_bounds: Vec<Aabb> = points.iter().map(|p| Aabb::new(*p, *p)).collect();
+        let bounds = points.iter().fold(Aabb::empty(), |bounds, p| bounds.grow(*p));
+        let bvh = Bvh::build(&point_bounds);
+
+        Self::from_fn(bounds, voxel_size, band, |p| {
+            match bvh.closest(p, |i| (p - points[i as usize]).length()) {
+                Some((i, dist)) if (p - points[i as usize]).dot(normals[i as usize]) < 0.0 => -dist,
+                Some((_, dist)) => dist,
+                None => f32::MAX,
+            }
+        })
+    }
+
+    /// Grid entry for a cell without a brick, where the surface is at least `dist` away.
+    /// Stored in whole voxels as `-1 - (2 * voxels + inside)`, so it can't be mistaken for a brick index.
+    fn empty_cell(dist: f32, inside: bool, voxel_size: f32) -> i32 {
+        let voxels = ((dist / voxel_size).floor() as i64).max(0).min(1 << 29) as i32;
+        -1 - (2 * voxels + inside as i32)
+    }
+
+    fn empty_distance(entry: i32, voxel_size: f32) -> f32 {
+        let code = -1 - entry;
+        let dist = (code / 2) as f32 * voxel_size;
+        if code % 2 == 1 { -dist } else { dist }
+    }
+
+    /// Size of a brick. Samples on its far side belong to the next brick as well.
+    pub fn brick_span(&self) -> f32 {
+        self.voxel_size * (BRICK_SIZE - 1) as f32
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        Aabb::new(self.origin, self.origin + self.dims.as_vec3() * self.brick_span())
+    }
+
+    pub fn brick_count(&self) -> usize {
+        self.bricks.len() / BRICK_VOXELS
+    }
+
+    /// Bytes on the GPU, for the bricks and the grid.
+    pub fn memory_usage(&self) -> usize {
+        (self.bricks.len() + self.grid.len() + HEADER_SIZE) * 4
+    }
+
+    /// Bytes a dense grid with the same voxels would take.
+    pub fn dense_memory_usage(&self) -> usize {
+        let samples = self.dims * (BRICK_SIZE as u32 - 1) + UVec3::ONE;
+        (samples.x as usize) * (samples.y as usize) * (samples.z as usize) * 4
+    }
+
+    /// Distance at a point in object space, interpolated the same way as `sdBrickMap` in `brickmap.glsl`.
+    pub fn distance(&self, point: Vec3) -> f32 {
+        let span = self.brick_span();
+        let local = (point - self.origin) / span;
+        //Outside of the grid, the surface is at least a band further away than the grid itself
+        let outside = (-local).max(local - self.dims.as_vec3()).max(Vec3::ZERO) * span;
+        if outside != Vec3::ZERO {
+            return outside.length() + self.band;
+        }
+
+        let cell = local.as_uvec3().min(self.dims - UVec3::ONE);
+        let entry = self.grid[(cell.x + self.dims.x * (cell.y + self.dims.y * cell.z)) as usize];
+        if entry < 0 {
+            return Self::empty_distance(entry, self.voxel_size);
+        }
+
+        let brick = &self.bricks[entry as usize * BRICK_VOXELS..(entry as usize + 1) * BRICK_VOXELS];
+        let p = (local - cell.as_vec3()) * (BRICK_SIZE - 1) as f32;
+        let base = p.floor().min(Vec3::splat((BRICK_SIZE - 2) as f32)).as_uvec3();
+        let f = p - base.as_vec3();
+        let sample = |x: u32, y: u32, z: u32| brick[(x + BRICK_SIZE as u32 * (y + BRICK_SIZE as u32 * z)) as usize];
+
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let (x, y, z) = (base.x, base.y, base.z);
+        let x00 = lerp(sample(x, y, z), sample(x + 1, y, z), f.x);
+        let x10 = lerp(sample(x, y + 1, z), sample(x + 1, y + 1, z), f.x);
+        let x01 = lerp(sample(x, y, z + 1), sample(x + 1, y, z + 1), f.x);
+        let x11 = lerp(sample(x, y + 1, z + 1), sample(x + 1, y + 1, z + 1), f.x);
+        lerp(lerp(x00, x10, f.y), lerp(x01, x11, f.y), f.z)
+    }
+}
+
+/// The bricks and grids of every brick map in the scene, on the GPU.
+//TODO: Implement 3D textures and buffer textures in glux, so we don't have to wrap them here
+pub struct BrickAtlas {
+    /// 3D texture with the bricks of all brick maps, `ATLAS_BRICKS` wide and high.
+    pub atlas: u32,
+    /// Buffer texture with the header and grid of every brick map, one after another.
+    pub grid: u32,
+    grid_buffer: u32,
+}
+
+/// Size of the atlas in texels, for an amount of bricks.
+/// The atlas fills up along X and Y first, and only grows along Z.
+fn atlas_size(brick_count: usize) -> [usize; 3] {
+    [
+        brick_count.min(ATLAS_BRICKS) * BRICK_SIZE,
+        ((brick_count + ATLAS_BRICKS - 1) / ATLAS_BRICKS).min(ATLAS_BRICKS) * BRICK_SIZE,
+        ((brick_count + ATLAS_BRICKS * ATLAS_BRICKS - 1) / (ATLAS_BRICKS * ATLAS_BRICKS)) * BRICK_SIZE,
+    ]
+}
+
+/// Amount of bricks that fit in an atlas with a maximum 3D texture size.
+fn atlas_capacity(max_size: usize) -> usize {
+    ATLAS_BRICKS * ATLAS_BRICKS * (max_size / BRICK_SIZE)
+}
+
+fn get_max_3d_texture_size() -> usize {
+    let mut value = 0;
+    unsafe {
+        gl::GetIntegerv(gl::MAX_3D_TEXTURE_SIZE, &mut value);
+    }
+    value as usize
+}
+
+fn get_max_texture_buffer_size() -> usize {
+    let mut value = 0;
+    unsafe {
+        gl::GetIntegerv(gl::MAX_TEXTURE_BUFFER_SIZE, &mut value);
+    }
+    value as usize
+}
+
+impl BrickAtlas {
+    pub fn new() -> Self {
+        let mut atlas = Self {
+            atlas: 0,
+            grid: 0,
+            grid_buffer: 0,
+        };
+        atlas.upload(&[]);
+        atlas
+    }
+
+    /// Replaces the contents with the given brick maps, and returns where the header of every map starts in the grid.
+    pub fn upload(&mut self, maps: &[BrickMap]) -> Vec<usize> {
+        self.delete();
+
+        //Empty textures can't be bound, so there is always at least one brick
+        let brick_count = maps.iter().map(|map| map.brick_count()).sum::<usize>().max(1);
+        let size = atlas_size(brick_count);
+        //Past the limit, creating the texture fails without any error on the CPU side
+        let max_size = get_max_3d_texture_size();
+        assert!(size[2] <= max_size, "Brick maps need {} bricks, but the atlas fits at most {} on this GPU! Use a larger voxel size.", brick_count, atlas_capacity(max_size));
+
+        let mut texels = vec![0.0f32; size[0] * size[1] * size[2]];
+        let mut grid: Vec<i32> = Vec::new();
+        let mut headers = Vec::new();
+        let mut first_brick = 0;
+        for map in maps {
+            headers.push(grid.len());
+            grid.extend(&[
+                map.origin.x.to_bits() as i32, map.origin.y.to_bits() as i32, map.origin.z.to_bits() as i32, map.voxel_size.to_bits() as i32,
+                map.dims.x as i32, map.dims.y as i32, map.dims.z as i32, map.band.to_bits() as i32,
+            ]);
+            grid.extend(map.grid.iter().map(|&entry| if entry >= 0 { entry + first_brick as i32 } else { entry }));
+
+            for (i, brick) in map.bricks.chunks(BRICK_VOXELS).enumerate() {
+                let b = first_brick + i;
+                let corner = [(b % ATLAS_BRICKS) * BRICK_SIZE, (b / ATLAS_BRICKS % ATLAS_BRICKS) * BRICK_SIZE, (b / (ATLAS_BRICKS * ATLAS_BRICKS)) * BRICK_SIZE];
+                for z in 0..BRICK_SIZE {
+                    for y in 0..BRICK_SIZE {
+                        let start = corner[0] + size[0] * (corner[1] + y + size[1] * (corner[2] + z));
+                        let row = BRICK_SIZE * (y + BRICK_SIZE * z);
+                        texels[start..start + BRICK_SIZE].copy_from_slice(&brick[row..row + BRICK_SIZE]);
+                    }
+                }
+            }
+            first_brick += map.brick_count();
+        }
+        if grid.is_empty() {
+            grid.push(0);
+        }
+        let max_grid = get_max_texture_buffer_size();
+        assert!(grid.len() <= max_grid, "Brick map grids need {} cells, but a buffer texture fits at most {} on this GPU! Use a larger voxel size.", grid.len(), max_grid);
+
+        unsafe {
+            gl::CreateTextures(gl::TEXTURE_3D, 1, &mut self.atlas);
+            //Bricks only hold distances close to the surface, which half floats store precisely enough, at half the memory
+            gl::TextureStorage3D(self.atlas, 1, gl::R16F, size[0] as i32, size[1] as i32, size[2] as i32);
+            gl::TextureSubImage3D(self.atlas, 0, 0, 0, 0, size[0] as i32, size[1] as i32, size[2] as i32, gl::RED, gl::FLOAT, texels.as_ptr() as *const std::ffi::c_void);
+            gl::TextureParameteri(self.atlas, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+            gl::TextureParameteri(self.atlas, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+            gl::TextureParameteri(self.atlas, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+            gl::TextureParameteri(self.atlas, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+            gl::TextureParameteri(self.atlas, gl::TEXTURE_WRAP_R, gl::CLAMP_TO_EDGE as i32);
+
+            gl::CreateBuffers(1, &mut self.grid_buffer);
+            gl::NamedBufferData(self.grid_buffer, (grid.len() * 4) as isize, grid.as_ptr() as *const std::ffi::c_void, gl::STATIC_DRAW);
+            gl::CreateTextures(gl::TEXTURE_BUFFER, 1, &mut self.grid);
+            gl::TextureBuffer(self.grid, gl::R32I, self.grid_buffer);
+        }
+
+        if !maps.is_empty() {
+            info!("Brick maps uploaded, {:.2} MiB for {} bricks and {:.2} MiB for the grids",
+                (texels.len() * 2) as f32 / 1048576.0, first_brick, (grid.len() * 4) as f32 / 1048576.0);
+        }
+        headers
+    }
+
+    /// Binds the atlas and the grids to texture units, see `brickmap.glsl`.
+    pub fn bind(&self, atlas_unit: u32, grid_unit: u32) {
+        unsafe {
+            gl::BindTextureUnit(atlas_unit, self.atlas);
+            gl::BindTextureUnit(grid_unit, self.grid);
+        }
+    }
+
+    fn delete(&mut self) {
+        unsafe {
+            if self.atlas != 0 {
+                gl::DeleteTextures(1, &self.atlas);
+                gl::DeleteTextures(1, &self.grid);
+                gl::DeleteBuffers(1, &self.grid_buffer);
+            }
+        }
+    }
+}
+
+impl Drop for BrickAtlas {
+    fn drop(&mut self) {
+        self.delete();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atlas_fills_xy_first() {
+        assert_eq!(atlas_size(1), [8, 8, 8]);
+        assert_eq!(atlas_size(32), [256, 8, 8]);
+        assert_eq!(atlas_size(33), [256, 16, 8]);
+        assert_eq!(atlas_size(1024), [256, 256, 8]);
+        assert_eq!(atlas_size(1025), [256, 256, 16]);
+    }
+
+    #[test]
+    fn atlas_capacity_matches_size() {
+        for &max_size in &[256, 2048, 2047] {
+            let capacity = atlas_capacity(max_size);
+            assert!(atlas_size(capacity)[2] <= max_size);
+            assert!(atlas_size(capacity + 1)[2] > max_size);
+        }
+    }
+}
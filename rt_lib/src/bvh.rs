@@ -0,0 +1,335 @@
+//! Bounding volume hierarchy, built on the CPU and traversed in the shaders.
+//! Splits are picked with the surface area heuristic over binned centroids.
+//! The builder only looks at bounding boxes, so it works for any kind of primitive.
+
+use glam::*;
+
+/// Bins per axis when searching for a split.
+const BINS: usize = 12;
+/// Cost of visiting a node, relative to evaluating a primitive.
+const TRAVERSAL_COST: f32 = 1.0;
+/// Nodes with more primitives than this always get split, even if the heuristic says otherwise.
+const MAX_LEAF_SIZE: usize = 4;
+/// Deeper nodes become leaves. The traversal stack in the shaders has to be larger than this!
+pub const MAX_DEPTH: usize = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self {
+            min: min,
+            max: max,
+        }
+    }
+
+    /// Contains nothing, growing it by anything gives that thing.
+    pub fn empty() -> Self {
+        Self::new(Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY))
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::new(self.min.min(other.min), self.max.max(other.max))
+    }
+
+    pub fn grow(&self, point: Vec3) -> Aabb {
+        Aabb::new(self.min.min(point), self.max.max(point))
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    /// Distance from a point to the box, 0 inside of it.
+    pub fn distance(&self, point: Vec3) -> f32 {
+        (self.min - point).max(point - self.max).max(Vec3::ZERO).length()
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let size = (self.max - self.min).max(Vec3::ZERO);
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BvhNode {
+    pub bounds: Aabb,
+    /// First child for interior nodes, the second child directly follows it.
+    /// First entry in `Bvh::indices` for leaves.
+    pub first: u32,
+    /// Primitives in a leaf, 0 for interior nodes.
+    pub count: u32,
+}
+
+impl BvhNode {
+    pub fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+/// The root is the first node. An empty hierarchy has no nodes at all.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    /// Primitive indices, in the order the leaves refer to them.
+    pub indices: Vec<u32>,
+}
+
+impl Bvh {
+    /// Builds a hierarchy over primitives with the given bounds.
+    pub fn build(bounds: &[Aabb]) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::new(),
+            indices: (0..bounds.len() as u32).collect(),
+        };
+        if bounds.is_empty() {
+            return bvh;
+        }
+
+        let centroids: Vec<Vec3> = bounds.iter().map(|b| b.centroid()).collect();
+        bvh.nodes.push(BvhNode { bounds: Aabb::empty(), first: 0, count: 0 });
+        bvh.build_node(0, 0, bounds.len(), 0, bounds, &centroids);
+        trace!("BVH built with {} nodes over {} primitives", bvh.nodes.len(), bounds.len());
+        bvh
+    }
+
+    /// Finds the closest primitive to a point, with `distance` giving the distance to a single primitive.
+    /// Primitives are only checked if their bounds are closer than the closest one so far.
+    pub fn closest(&self, point: Vec3, mut distance: impl FnMut(u32) -> f32) -> Option<(u32, f32)> {
+        let mut best: Option<(u32, f32)> = None;
+        let mut stack = Vec::with_capacity(MAX_DEPTH + 2);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            if best.map_or(false, |(_, d)| node.bounds.distance(point) >= d) {
+                continue;
+            }
+
+            if node.is_leaf() {
+                for &primitive in &self.indices[node.first as usize..(node.first + node.count) as usize] {
+                    let d = distance(primitive);
+                    if best.map_or(true, |(_, best_d)| d < best_d) {
+                        best = Some((primitive, d));
+                    }
+                }
+            } else {
+                //Visit the closest child first, so the other one gets skipped more often
+                let left = node.first as usize;
+                let (near, far) = if self.nodes[left].bounds.distance(point) <= self.nodes[left + 1].bounds.distance(point) {
+                    (left, left + 1)
+                } else {
+                    (left + 1, left)
+                };
+                stack.push(far);
+                stack.push(near);
+            }
+        }
+        best
+    }
+
+    fn build_node(&mut self, node: usize, start: usize, end: usize, depth: usize, bounds: &[Aabb], centroids: &[Vec3]) {
+        let mut node_bounds = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for &i in &self.indices[start..end] {
+            node_bounds = node_bounds.union(&bounds[i as usize]);
+            centroid_bounds = centroid_bounds.grow(centroids[i as usize]);
+        }
+        self.nodes[node].bounds = node_bounds;
+
+        let count = end - start;
+        if count == 1 || depth >= MAX_DEPTH {
+            self.make_leaf(node, start, end);
+            return;
+        }
+
+        let area = node_bounds.surface_area();
+        let mid = match Self::find_split(&self.indices[start..end], bounds, centroids, &centroid_bounds) {
+            Some((axis, split, cost)) if count > MAX_LEAF_SIZE || TRAVERSAL_COST * area + cost < area * count as f32 => {
+                //Partition in place, everything in a bin up to the split goes left
+                let mut mid = start;
+                for j in start..end {
+                    if Self::bin(centroids[self.indices[j] as usize], &centroid_bounds, axis) <= split {
+                        self.indices.swap(mid, j);
+                        mid += 1;
+                    }
+                }
+                mid
+            },
+            //All centroids are in the same spot, so no split is better than any other
+            None if count > MAX_LEAF_SIZE => start + count / 2,
+            _ => {
+                self.make_leaf(node, start, end);
+                return;
+            },
+        };
+
+        let left = self.nodes.len();
+        self.nodes.push(BvhNode { bounds: Aabb::empty(), first: 0, count: 0 });
+        self.nodes.push(BvhNode { bounds: Aabb::empty(), first: 0, count: 0 });
+        self.nodes[node].first = left as u32;
+        self.build_node(left, start, mid, depth + 1, bounds, centroids);
+        self.build_node(left + 1, mid, end, depth + 1, bounds, centroids);
+    }
+
+    fn make_leaf(&mut self, node: usize, start: usize, end: usize) {
+        self.nodes[node].first = start as u32;
+        self.nodes[node].count = (end - start) as u32;
+    }
+
+    fn bin(centroid: Vec3, centroid_bounds: &Aabb, axis: usize) -> usize {
+        let extent = centroid_bounds.max[axis] - centroid_bounds.min[axis];
+        let bin = ((centroid[axis] - centroid_bounds.min[axis]) / extent * BINS as f32) as usize;
+        bin.min(BINS - 1)
+    }
+
+    /// Returns the axis, the last bin on the left side and the cost of the best split.
+    /// The cost is the area of every side times its primitive count. It isn't divided by the area of the node,
+    /// and doesn't include the traversal.
+    fn find_split(indices: &[u32], bounds: &[Aabb], centroids: &[Vec3], centroid_bounds: &Aabb) -> Option<(usize, usize, f32)> {
+        let mut best: Option<(usize, usize, f32)> = None;
+        for axis in 0..3 {
+            if centroid_bounds.max[axis] - centroid_bounds.min[axis] <= 0.0 {
+                continue;
+            }
+
+            let mut bins = [(Aabb::empty(), 0usize); BINS];
+            for &i in indices {
+                let bin = &mut bins[Self::bin(centroids[i as usize], centroid_bounds, axis)];
+                bin.0 = bin.0.union(&bounds[i as usize]);
+                bin.1 += 1;
+            }
+
+            //Sweep from the right first, so the left sweep can look up the other side
+            let mut right = [(0.0, 0usize); BINS];
+            let mut acc = (Aabb::empty(), 0);
+            for b in (1..BINS).rev() {
+                acc = (acc.0.union(&bins[b].0), acc.1 + bins[b].1);
+                right[b] = (acc.0.surface_area(), acc.1);
+            }
+
+            let mut acc = (Aabb::empty(), 0);
+            for b in 0..BINS - 1 {
+                acc = (acc.0.union(&bins[b].0), acc.1 + bins[b].1);
+                let (right_area, right_count) = right[b + 1];
+                if acc.1 == 0 || right_count == 0 {
+                    continue;
+                }
+                let cost = acc.0.surface_area() * acc.1 as f32 + right_area * right_count as f32;
+                if best.map_or(true, |(_, _, best_cost)| cost < best_cost) {
+                    best = Some((axis, b, cost));
+                }
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xorshift(state: &mut u32) -> f32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        (*state >> 8) as f32 / 16777216.0
+    }
+
+    fn random_boxes(count: usize, seed: u32) -> Vec<Aabb> {
+        let mut state = seed;
+        (0..count).map(|_| {
+            let min = vec3(xorshift(&mut state), xorshift(&mut state), xorshift(&mut state)) * 100.0;
+            let size = vec3(xorshift(&mut state), xorshift(&mut state), xorshift(&mut state)) * 5.0;
+            Aabb::new(min, min + size)
+        }).collect()
+    }
+
+    fn contains(outer: &Aabb, inner: &Aabb) -> bool {
+        outer.min.cmple(inner.min).all() && outer.max.cmpge(inner.max).all()
+    }
+
+    /// Walks the hierarchy, checking the bounds of every node and collecting the primitives of the leaves.
+    /// Returns the depth of the deepest leaf.
+    fn check_node(bvh: &Bvh, node: usize, depth: usize, bounds: &[Aabb], primitives: &mut Vec<u32>) -> usize {
+        let n = &bvh.nodes[node];
+        if n.is_leaf() {
+            for &i in &bvh.indices[n.first as usize..(n.first + n.count) as usize] {
+                assert!(contains(&n.bounds, &bounds[i as usize]), "Leaf {} doesn't contain primitive {}", node, i);
+                primitives.push(i);
+            }
+            return depth;
+        }
+        let left = n.first as usize;
+        let mut deepest = depth;
+        for child in left..left + 2 {
+            assert!(child > node, "Child {} comes before its parent {}", child, node);
+            assert!(contains(&n.bounds, &bvh.nodes[child].bounds), "Node {} doesn't contain its child {}", node, child);
+            deepest = deepest.max(check_node(bvh, child, depth + 1, bounds, primitives));
+        }
+        deepest
+    }
+
+    /// Checks the whole hierarchy, and returns its depth.
+    fn check(bounds: &[Aabb]) -> usize {
+        let bvh = Bvh::build(bounds);
+        let mut primitives = Vec::new();
+        let depth = check_node(&bvh, 0, 0, bounds, &mut primitives);
+        primitives.sort_unstable();
+        assert_eq!(primitives, (0..bounds.len() as u32).collect::<Vec<_>>(), "Every primitive has to be in exactly one leaf");
+        assert!(depth <= MAX_DEPTH, "Leaf at depth {}", depth);
+        depth
+    }
+
+    #[test]
+    fn empty() {
+        let bvh = Bvh::build(&[]);
+        assert!(bvh.nodes.is_empty() && bvh.indices.is_empty());
+        assert_eq!(bvh.closest(Vec3::ZERO, |_| 0.0), None);
+    }
+
+    #[test]
+    fn random_primitives() {
+        for &count in &[1, 2, 5, 100, 2000] {
+            check(&random_boxes(count, count as u32 * 7 + 1));
+        }
+    }
+
+    #[test]
+    fn coincident_centroids() {
+        //No axis can be split, so nodes get halved until they fit in a leaf
+        let bounds = vec![Aabb::new(Vec3::splat(-1.0), Vec3::ONE); 5000];
+        check(&bounds);
+    }
+
+    #[test]
+    fn depth_is_capped() {
+        //Every box is twice as far out as the last, so splits only peel off the outermost few boxes,
+        //which would make the hierarchy far deeper than the cap
+        let bounds: Vec<Aabb> = (0..126).map(|i| {
+            let x = 2f32.powi(i);
+            Aabb::new(vec3(x, 0.0, 0.0), vec3(x * 1.01, 1.0, 1.0))
+        }).collect();
+        assert_eq!(check(&bounds), MAX_DEPTH);
+    }
+
+    #[test]
+    fn closest_matches_brute_force() {
+        let bounds = random_boxes(500, 1234);
+        let bvh = Bvh::build(&bounds);
+        let mut state = 99;
+        for _ in 0..200 {
+            let point = vec3(xorshift(&mut state), xorshift(&mut state), xorshift(&mut state)) * 140.0 - Vec3::splat(20.0);
+            let expected = bounds.iter().map(|b| b.distance(point)).fold(f32::INFINITY, f32::min);
+            let (i, d) = bvh.closest(point, |i| bounds[i as usize].distance(point)).unwrap();
+            //Overlapping boxes can tie, so only the distance has to match
+            assert_eq!(d, expected);
+            assert_eq!(bounds[i as usize].distance(point), d);
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! Denoising of finished renders on the CPU.
+//! The built-in filter is an edge-avoiding à-trous wavelet filter (Dammertz et al. 2010),
+//! guided by the albedo, normal and depth AOVs, and by a variance estimate like SVGF (Schied et al. 2017). It has no native dependencies,
+//! so it works everywhere. Intel's Open Image Denoise is available with the `oidn` feature.
+
+use crate::output::FloatImage;
+
+/// Feature buffers guiding the filter. Any of them can be left out,
+/// but the result gets a lot blurrier around edges without them.
+/// All of them need to be the same resolution as the image being denoised.
+#[derive(Clone, Copy, Default)]
+pub struct Features<'a> {
+    pub albedo: Option<&'a FloatImage>,
+    pub normal: Option<&'a FloatImage>,
+    pub depth: Option<&'a FloatImage>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AtrousSettings {
+    /// Amount of filter passes. Every pass doubles the filter radius.
+    pub iterations: u32,
+    /// How quickly the weight falls off with luminance differences,
+    /// in standard deviations of the estimated noise.
+    pub sigma_luminance: f32,
+    pub sigma_normal: f32,
+    /// Relative to the depth of the center pixel.
+    pub sigma_depth: f32,
+    pub sigma_albedo: f32,
+    /// Divide out the albedo before filtering and multiply it back in afterwards,
+    /// so texture detail doesn't get blurred away.
+    pub demodulate_albedo: bool,
+}
+
+impl Default for AtrousSettings {
+    fn default() -> Self {
+        Self {
+            iterations: 5,
+            sigma_luminance: 4.0,
+            sigma_normal: 0.3,
+            sigma_depth: 0.1,
+            sigma_albedo: 0.1,
+            demodulate_albedo: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Denoiser {
+    Atrous(AtrousSettings),
+    /// Open Image Denoise. Requires the `oidn` feature.
+    #[cfg(feature = "oidn")]
+    Oidn,
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Denoiser::Atrous(AtrousSettings::default())
+    }
+}
+
+impl Denoiser {
+    /// Denoises an image. The result has the same amount of channels as the input,
+    /// anything past RGB is copied over untouched.
+    pub fn denoise(&self, color: &FloatImage, features: &Features) -> FloatImage {
+        match self {
+            Denoiser::Atrous(settings) => atrous(color, features, settings),
+            #[cfg(feature = "oidn")]
+            Denoiser::Oidn => oidn_denoise(color, features),
+        }
+    }
+}
+
+/// The 1D B3 spline kernel, applied separably at increasing step sizes.
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Albedo below this is treated as black, and left out of demodulation.
+const ALBEDO_EPSILON: f32 = 0.001;
+
+fn rgb(image: &FloatImage, i: usize) -> [f32; 3] {
+    let p = &image.data[i * image.channels..];
+    match image.channels {
+        1 | 2 => [p[0], p[0], p[0]],
+        _ => [p[0], p[1], p[2]],
+    }
+}
+
+fn luminance(c: [f32; 3]) -> f32 {
+    0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2]
+}
+
+/// Estimates the noise variance of every pixel from the luminance of its 3x3 neighbourhood.
+fn spatial_variance(image: &[[f32; 3]], width: usize, height: usize) -> Vec<f32> {
+    let mut variance = vec![0.0; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = 0.0;
+            let mut sum_sq = 0.0;
+            let mut count = 0.0;
+            for qy in y.saturating_sub(1)..(y + 2).min(height) {
+                for qx in x.saturating_sub(1)..(x + 2).min(width) {
+                    let l = luminance(image[qx + qy * width]);
+                    sum += l;
+                    sum_sq += l * l;
+                    count += 1.0;
+                }
+            }
+            let mean = sum / count;
+            variance[x + y * width] = (sum_sq / count - mean * mean).max(0.0);
+        }
+    }
+    variance
+}
+
+/// 3x3 gaussian blur of the variance at a single pixel, which makes the estimate a lot more stable.
+fn blurred_variance(variance: &[f32], width: usize, height: usize, x: usize, y: usize) -> f32 {
+    const WEIGHTS: [f32; 2] = [0.25, 0.125];
+    let mut sum = 0.0;
+    let mut weight_sum = 0.0;
+    for qy in y.saturating_sub(1)..(y + 2).min(height) {
+        for qx in x.saturating_sub(1)..(x + 2).min(width) {
+            let w = WEIGHTS[(qx != x) as usize] * WEIGHTS[(qy != y) as usize] * 4.0;
+            sum += variance[qx + qy * width] * w;
+            weight_sum += w;
+        }
+    }
+    sum / weight_sum
+}
+
+fn distance_squared(a: [f32; 3], b: [f32; 3]) -> f32 {
+    let d = [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+    d[0] * d[0] + d[1] * d[1] + d[2] * d[2]
+}
+
+pub fn atrous(color: &FloatImage, features: &Features, settings: &AtrousSettings) -> FloatImage {
+    let (width, height) = (color.width, color.height);
+    for feature in [features.albedo, features.normal, features.depth].iter().flatten() {
+        assert!(feature.width == width && feature.height == height, "Feature buffers must match the image resolution!");
+    }
+
+    let albedo_at = |i: usize| -> Option<[f32; 3]> {
+        if !settings.demodulate_albedo {
+            return None;
+        }
+        let a = rgb(features.albedo?, i);
+        if a[0].max(a[1]).max(a[2]) < ALBEDO_EPSILON {
+            return None;
+        }
+        Some(a)
+    };
+
+    //Demodulate into a 3 channel working buffer
+    let mut current: Vec<[f32; 3]> = (0..width * height).map(|i| {
+        let c = rgb(color, i);
+        match albedo_at(i) {
+            Some(a) => [c[0] / a[0].max(ALBEDO_EPSILON), c[1] / a[1].max(ALBEDO_EPSILON), c[2] / a[2].max(ALBEDO_EPSILON)],
+            None => c,
+        }
+    }).collect();
+    let mut next = current.clone();
+    let mut variance = spatial_variance(&current, width, height);
+    let mut next_variance = variance.clone();
+
+    for iteration in 0..settings.iterations {
+        let step = 1i64 << iteration;
+
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let p = (x + y * width as i64) as usize;
+                let c_p = current[p];
+                let l_p = luminance(c_p);
+                let std_dev = blurred_variance(&variance, width, height, x as usize, y as usize).sqrt();
+                let inv_luminance = 1.0 / (settings.sigma_luminance * std_dev + 1e-6);
+                let n_p = features.normal.map(|n| rgb(n, p));
+                let z_p = features.depth.map(|d| d.data[p * d.channels]);
+                let a_p = features.albedo.map(|a| rgb(a, p));
+
+                let mut sum = [0.0f32; 3];
+                let mut weight_sum = 0.0f32;
+                let mut variance_sum = 0.0f32;
+                for (ky, hy) in KERNEL.iter().enumerate() {
+                    let qy = y + (ky as i64 - 2) * step;
+                    if qy < 0 || qy >= height as i64 {
+                        continue;
+                    }
+                    for (kx, hx) in KERNEL.iter().enumerate() {
+                        let qx = x + (kx as i64 - 2) * step;
+                        if qx < 0 || qx >= width as i64 {
+                            continue;
+                        }
+                        let q = (qx + qy * width as i64) as usize;
+                        let c_q = current[q];
+
+                        let mut exponent = (l_p - luminance(c_q)).abs() * inv_luminance;
+                        if let (Some(n_p), Some(normal)) = (n_p, features.normal) {
+                            exponent += distance_squared(n_p, rgb(normal, q)) / (settings.sigma_normal * settings.sigma_normal);
+                        }
+                        if let (Some(z_p), Some(depth)) = (z_p, features.depth) {
+                            let z_q = depth.data[q * depth.channels];
+                            exponent += (z_p - z_q).abs() / (settings.sigma_depth * z_p.abs().max(1e-3));
+                        }
+                        if let (Some(a_p), Some(albedo)) = (a_p, features.albedo) {
+                            exponent += distance_squared(a_p, rgb(albedo, q)) / (settings.sigma_albedo * settings.sigma_albedo);
+                        }
+
+                        let w = hx * hy * (-exponent).exp();
+                        sum[0] += c_q[0] * w;
+                        sum[1] += c_q[1] * w;
+                        sum[2] += c_q[2] * w;
+                        weight_sum += w;
+                        variance_sum += w * w * variance[q];
+                    }
+                }
+
+                //The center pixel always has a weight of at least 9/64, so this can't divide by 0
+                next[p] = [sum[0] / weight_sum, sum[1] / weight_sum, sum[2] / weight_sum];
+                //Filtering lowers the variance, which the next iteration needs to know about
+                next_variance[p] = variance_sum / (weight_sum * weight_sum);
+            }
+        }
+
+        std::mem::swap(&mut current, &mut next);
+        std::mem::swap(&mut variance, &mut next_variance);
+    }
+
+    //Remodulate, and copy over any extra channels
+    let mut result = color.clone();
+    for (i, c) in current.iter().enumerate() {
+        let c = match albedo_at(i) {
+            Some(a) => [c[0] * a[0].max(ALBEDO_EPSILON), c[1] * a[1].max(ALBEDO_EPSILON), c[2] * a[2].max(ALBEDO_EPSILON)],
+            None => *c,
+        };
+        let out = &mut result.data[i * color.channels..];
+        for channel in 0..color.channels.min(3) {
+            out[channel] = c[channel];
+        }
+    }
+    result
+}
+
+#[cfg(feature = "oidn")]
+fn oidn_denoise(color: &FloatImage, features: &Features) -> FloatImage {
+    let to_rgb = |image: &FloatImage| -> Vec<f32> {
+        (0..image.width * image.height).flat_map(|i| rgb(image, i).to_vec()).collect()
+    };
+
+    let input = to_rgb(color);
+    let mut output = vec![0f32; input.len()];
+    let albedo = features.albedo.map(|a| to_rgb(a));
+    let normal = features.normal.map(|n| to_rgb(n));
+
+    let device = oidn::Device::new();
+    let mut filter = oidn::RayTracing::new(&device);
+    filter.hdr(true).image_dimensions(color.width, color.height);
+    match (&albedo, &normal) {
+        (Some(albedo), Some(normal)) => { filter.albedo_normal(albedo, normal); },
+        (Some(albedo), None) => { filter.albedo(albedo); },
+        _ => {},
+    }
+    filter.filter(&input[..], &mut output[..]).expect("Failed to configure OIDN filter!");
+    if let Err(e) = device.get_error() {
+        error!("OIDN failed to denoise: {:?}", e);
+    }
+
+    let mut result = color.clone();
+    for i in 0..color.width * color.height {
+        let out = &mut result.data[i * color.channels..];
+        for channel in 0..color.channels.min(3) {
+            out[channel] = output[i * 3 + channel];
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 32;
+
+    fn constant(value: [f32; 3]) -> FloatImage {
+        FloatImage::from_data(SIZE, SIZE, 3, value.repeat(SIZE * SIZE))
+    }
+
+    /// Deterministic noise in [-1, 1), so the tests don't need a random number crate.
+    fn noise(state: &mut u32) -> f32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        (*state >> 8) as f32 / (1 << 23) as f32 - 1.0
+    }
+
+    fn luminance_variance(image: &FloatImage) -> f32 {
+        let values: Vec<f32> = (0..image.width * image.height).map(|i| luminance(rgb(image, i))).collect();
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        values.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / values.len() as f32
+    }
+
+    #[test]
+    fn flat_image_is_unchanged() {
+        let color = constant([0.2, 0.5, 0.8]);
+        let albedo = constant([0.5; 3]);
+        let normal = constant([0.0, 1.0, 0.0]);
+        let depth = FloatImage::from_data(SIZE, SIZE, 1, vec![2.0; SIZE * SIZE]);
+        let features = Features { albedo: Some(&albedo), normal: Some(&normal), depth: Some(&depth) };
+
+        for features in &[features, Features::default()] {
+            let result = Denoiser::default().denoise(&color, features);
+            for (a, b) in result.data.iter().zip(color.data.iter()) {
+                assert!((a - b).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn noise_goes_down_on_a_plane() {
+        let mut state = 1234;
+        let mut color = constant([0.0; 3]);
+        for value in color.data.iter_mut() {
+            *value = 0.4 + 0.2 * noise(&mut state);
+        }
+        let albedo = constant([0.8; 3]);
+        let normal = constant([0.0, 1.0, 0.0]);
+        let features = Features { albedo: Some(&albedo), normal: Some(&normal), depth: None };
+
+        let result = Denoiser::default().denoise(&color, &features);
+        assert!(luminance_variance(&result) < luminance_variance(&color) * 0.1);
+    }
+
+    #[test]
+    fn normal_edges_are_kept() {
+        //Left half faces the camera and is lit, right half faces sideways and is dark
+        let mut color = constant([0.0; 3]);
+        let mut normal = constant([1.0, 0.0, 0.0]);
+        for y in 0..SIZE {
+            for x in 0..SIZE / 2 {
+                color.pixel_mut(x, y).copy_from_slice(&[1.0; 3]);
+                normal.pixel_mut(x, y).copy_from_slice(&[0.0, 0.0, 1.0]);
+            }
+        }
+        let features = Features { albedo: None, normal: Some(&normal), depth: None };
+
+        let result = Denoiser::default().denoise(&color, &features);
+        for y in 0..SIZE {
+            assert!(result.pixel(SIZE / 2 - 1, y)[0] > 0.95);
+            assert!(result.pixel(SIZE / 2, y)[0] < 0.05);
+        }
+    }
+
+    #[test]
+    fn albedo_edges_are_kept() {
+        //Same lighting everywhere, so demodulating takes the edge out of the filter completely
+        let mut albedo = constant([0.1; 3]);
+        for y in 0..SIZE {
+            for x in 0..SIZE / 2 {
+                albedo.pixel_mut(x, y).copy_from_slice(&[0.9; 3]);
+            }
+        }
+        let color = FloatImage::from_data(SIZE, SIZE, 3, albedo.data.iter().map(|a| a * 0.5).collect());
+        let features = Features { albedo: Some(&albedo), normal: None, depth: None };
+
+        let result = Denoiser::default().denoise(&color, &features);
+        for (a, b) in result.data.iter().zip(color.data.iter()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn extra_channels_are_copied() {
+        let color = FloatImage::from_data(2, 1, 4, vec![0.5, 0.5, 0.5, 0.25, 0.5, 0.5, 0.5, 0.75]);
+        let result = Denoiser::default().denoise(&color, &Features::default());
+        assert_eq!(result.data[3], 0.25);
+        assert_eq!(result.data[7], 0.75);
+    }
+}
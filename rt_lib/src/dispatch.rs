@@ -0,0 +1,106 @@
+//! Helpers for dispatching the compute shaders over the whole image.
+
+/// Amount of workgroups needed to cover the entire resolution.
+/// Rounds up, so the shaders need to discard invocations outside of the image.
+pub fn dispatch_groups(resolution: (usize, usize), dispatch_size: (u32, u32)) -> (u32, u32) {
+    let x = (resolution.0 as u32 + dispatch_size.0 - 1) / dispatch_size.0;
+    let y = (resolution.1 as u32 + dispatch_size.1 - 1) / dispatch_size.1;
+    (x, y)
+}
+
+/// Dispatches the currently bound compute shader over the entire resolution.
+pub fn dispatch_compute(resolution: (usize, usize), dispatch_size: (u32, u32)) {
+    let groups = dispatch_groups(resolution, dispatch_size);
+    unsafe {
+        gl::DispatchCompute(groups.0, groups.1, 1);
+    }
+}
+
+pub fn get_workgroup_invocations() -> u32 {
+    let mut value = 0;
+    unsafe {
+        gl::GetIntegerv(gl::MAX_COMPUTE_WORK_GROUP_INVOCATIONS, &mut value);
+    }
+    value as u32
+}
+
+pub fn get_workgroup_max_size() -> (u32, u32) {
+    let mut x = 0;
+    let mut y = 0;
+    unsafe {
+        gl::GetIntegeri_v(gl::MAX_COMPUTE_WORK_GROUP_SIZE, 0, &mut x);
+        gl::GetIntegeri_v(gl::MAX_COMPUTE_WORK_GROUP_SIZE, 1, &mut y);
+    }
+    (x as u32, y as u32)
+}
+
+/// Shrinks a dispatch size until it fits within the limits of the current GL context.
+/// Requires a current context.
+pub fn validate_dispatch_size(dispatch_size: (u32, u32)) -> (u32, u32) {
+    let max_invocations = get_workgroup_invocations();
+    let max_size = get_workgroup_max_size();
+    let validated = fit_dispatch_size(dispatch_size, max_invocations, max_size);
+    if validated != dispatch_size {
+        warn!("Dispatch size {:?} is not supported, using {:?} instead!", dispatch_size, validated);
+    }
+    validated
+}
+
+/// The largest square dispatch size the current GL context supports.
+/// Requires a current context.
+pub fn default_dispatch_size() -> (u32, u32) {
+    let side = (get_workgroup_invocations() as f32).sqrt() as u32;
+    validate_dispatch_size((side, side))
+}
+
+fn fit_dispatch_size(dispatch_size: (u32, u32), max_invocations: u32, max_size: (u32, u32)) -> (u32, u32) {
+    let mut x = dispatch_size.0.max(1).min(max_size.0.max(1));
+    let mut y = dispatch_size.1.max(1).min(max_size.1.max(1));
+    //Halve the largest side until it fits, keeping it roughly square
+    while x * y > max_invocations.max(1) {
+        if x >= y {
+            x = (x + 1) / 2;
+        } else {
+            y = (y + 1) / 2;
+        }
+    }
+    (x, y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_round_up() {
+        assert_eq!(dispatch_groups((1281, 721), (32, 30)), (41, 25));
+        assert_eq!(dispatch_groups((1280, 720), (32, 30)), (40, 24));
+        assert_eq!(dispatch_groups((1, 1), (32, 30)), (1, 1));
+    }
+
+    #[test]
+    fn fits_invocation_limit() {
+        assert_eq!(fit_dispatch_size((32, 32), 512, (1024, 1024)), (16, 32));
+        assert_eq!(fit_dispatch_size((32, 30), 1024, (1024, 1024)), (32, 30));
+        //Odd sides round up when halved, and still end up fitting
+        assert_eq!(fit_dispatch_size((33, 33), 256, (1024, 1024)), (9, 17));
+    }
+
+    #[test]
+    fn clamps_to_max_size() {
+        assert_eq!(fit_dispatch_size((0, 0), 1024, (1024, 1024)), (1, 1));
+        assert_eq!(fit_dispatch_size((2000, 1), 1536, (1024, 1024)), (1024, 1));
+        assert_eq!(fit_dispatch_size((4096, 8), 1024, (1024, 64)), (128, 8));
+        assert_eq!(fit_dispatch_size((16, 128), 1024, (1024, 64)), (16, 64));
+    }
+
+    #[test]
+    fn always_fits() {
+        for &(x, y) in &[(1, 1), (7, 300), (64, 64), (1000, 3), (u16::MAX as u32, 2)] {
+            for &max_invocations in &[1, 64, 768, 1024] {
+                let (fx, fy) = fit_dispatch_size((x, y), max_invocations, (1024, 64));
+                assert!(fx >= 1 && fy >= 1 && fx <= 1024 && fy <= 64 && fx * fy <= max_invocations, "({}, {}) with {} invocations became ({}, {})", x, y, max_invocations, fx, fy);
+            }
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Pixel reconstruction filters.
+//! Filters are applied through filter importance sampling (Ernst et al. 2006):
+//! camera rays are offset from the pixel center with a distribution proportional to the filter,
+//! so every sample simply gets averaged. Filters with negative lobes give some samples a negative weight.
+//! All filters are separable, so they are described by a 1D profile.
+
+/// Amount of entries in the table uploaded to the GPU, each 4 floats.
+pub const FILTER_TABLE_SIZE: usize = 256;
+
+/// Steps used to integrate the filter when building the table.
+const INTEGRATION_STEPS: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PixelFilter {
+    /// Uniform over the pixel. Sharp, but aliases the most.
+    Box,
+    Tent {
+        radius: f32,
+    },
+    Gaussian {
+        radius: f32,
+        sigma: f32,
+    },
+    BlackmanHarris {
+        radius: f32,
+    },
+    /// Mitchell-Netravali. Negative lobes keep it sharp, at the cost of slight ringing.
+    Mitchell {
+        radius: f32,
+        b: f32,
+        c: f32,
+    },
+}
+
+impl Default for PixelFilter {
+    fn default() -> Self {
+        PixelFilter::BlackmanHarris { radius: 1.5 }
+    }
+}
+
+impl PixelFilter {
+    /// Radius in pixels, outside of which the filter is 0.
+    pub fn radius(&self) -> f32 {
+        match *self {
+            PixelFilter::Box => 0.5,
+            PixelFilter::Tent { radius } => radius,
+            PixelFilter::Gaussian { radius, .. } => radius,
+            PixelFilter::BlackmanHarris { radius } => radius,
+            PixelFilter::Mitchell { radius, .. } => radius,
+        }
+    }
+
+    /// Evaluates the 1D filter profile at an offset (in pixels) from the pixel center.
+    pub fn evaluate(&self, x: f32) -> f32 {
+        let radius = self.radius();
+        if x.abs() > radius {
+            return 0.0;
+        }
+        match *self {
+            PixelFilter::Box => 1.0,
+            PixelFilter::Tent { radius } => radius - x.abs(),
+            PixelFilter::Gaussian { radius, sigma } => {
+                //Shifted down so it reaches 0 at the radius
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            },
+            PixelFilter::BlackmanHarris { radius } => {
+                let t = 2.0 * std::f32::consts::PI * (x + radius) / (2.0 * radius);
+                0.35875 - 0.48829 * t.cos() + 0.14128 * (2.0 * t).cos() - 0.01168 * (3.0 * t).cos()
+            },
+            PixelFilter::Mitchell { radius, b, c } => {
+                let x = (2.0 * x / radius).abs();
+                if x > 1.0 {
+                    ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+                } else {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)) / 6.0
+                }
+            },
+        }
+    }
+
+    /// Tabulates the inverse CDF of |f|, for `FILTER_TABLE_SIZE` evenly spaced values in [0, 1].
+    /// Every entry is an (offset in pixels, sample weight, sample weight past the sign change, sign change) quad.
+    /// The weight is the sign of the filter at that offset, times ∫|f| / ∫f,
+    /// which keeps the result normalized for filters with negative lobes.
+    /// Offsets are interpolated between entries, so when the filter changes sign before the next entry,
+    /// the offset it does that at is stored too. Otherwise that is the radius, past any offset.
+    pub fn sample_table(&self) -> Vec<f32> {
+        let radius = self.radius();
+        let dx = 2.0 * radius / INTEGRATION_STEPS as f32;
+
+        //CDF of |f|, integrated with the midpoint rule
+        let mut cdf = Vec::with_capacity(INTEGRATION_STEPS + 1);
+        cdf.push(0.0f64);
+        let mut integral = 0.0f64;
+        let mut integral_abs = 0.0f64;
+        for i in 0..INTEGRATION_STEPS {
+            let f = self.evaluate(-radius + (i as f32 + 0.5) * dx) as f64 * dx as f64;
+            integral += f;
+            integral_abs += f.abs();
+            cdf.push(integral_abs);
+        }
+        let normalization = (integral_abs / integral) as f32;
+
+        let mut offsets = Vec::with_capacity(FILTER_TABLE_SIZE);
+        let mut step = 0;
+        for i in 0..FILTER_TABLE_SIZE {
+            let target = i as f64 / (FILTER_TABLE_SIZE - 1) as f64 * integral_abs;
+            while step < INTEGRATION_STEPS - 1 && cdf[step + 1] < target {
+                step += 1;
+            }
+            //Linear inside the step
+            let segment = cdf[step + 1] - cdf[step];
+            let t = if segment > 0.0 { ((target - cdf[step]) / segment).max(0.0).min(1.0) as f32 } else { 0.5 };
+            offsets.push(-radius + (step as f32 + t) * dx);
+        }
+
+        let mut table = Vec::with_capacity(FILTER_TABLE_SIZE * 4);
+        for i in 0..FILTER_TABLE_SIZE {
+            let x = offsets[i];
+            let sign = self.evaluate(x).signum();
+            let next = offsets.get(i + 1).copied().unwrap_or(x);
+            let next_sign = self.evaluate(next).signum();
+            let change = if sign != next_sign { self.sign_change(x, next) } else { radius };
+
+            table.push(x);
+            table.push(sign * normalization);
+            table.push(next_sign * normalization);
+            table.push(change);
+        }
+        table
+    }
+
+    /// Bisects the offset between `a` and `b` where the filter changes sign.
+    fn sign_change(&self, mut a: f32, mut b: f32) -> f32 {
+        let sign = self.evaluate(a).signum();
+        for _ in 0..32 {
+            let mid = (a + b) * 0.5;
+            if self.evaluate(mid).signum() == sign {
+                a = mid;
+            } else {
+                b = mid;
+            }
+        }
+        b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [PixelFilter; 5] = [
+        PixelFilter::Box,
+        PixelFilter::Tent { radius: 1.0 },
+        PixelFilter::Gaussian { radius: 1.5, sigma: 0.5 },
+        PixelFilter::BlackmanHarris { radius: 1.5 },
+        PixelFilter::Mitchell { radius: 2.0, b: 1.0 / 3.0, c: 1.0 / 3.0 },
+    ];
+
+    /// CPU version of `sampleFilter` in `camera_ray_cs.glsl`.
+    fn sample(table: &[f32], u: f32) -> (f32, f32) {
+        let n = table.len() / 4;
+        let fi = u * (n - 1) as f32;
+        let i = (fi as usize).min(n - 2);
+        let segment = &table[i * 4..i * 4 + 4];
+        let offset = segment[0] + (table[(i + 1) * 4] - segment[0]) * (fi - i as f32);
+        let weight = if offset < segment[3] { segment[1] } else { segment[2] };
+        (offset, weight)
+    }
+
+    #[test]
+    fn offsets_are_monotonic_and_within_radius() {
+        for filter in FILTERS.iter() {
+            let table = filter.sample_table();
+            assert_eq!(table.len(), FILTER_TABLE_SIZE * 4);
+            let radius = filter.radius();
+            let offsets: Vec<f32> = table.chunks(4).map(|entry| entry[0]).collect();
+            assert!(offsets.windows(2).all(|w| w[0] <= w[1]), "{:?} has offsets out of order", filter);
+            assert!(offsets.iter().all(|x| x.abs() <= radius), "{:?} has offsets past its radius", filter);
+            assert!(offsets[0] < -radius * 0.9 && offsets[FILTER_TABLE_SIZE - 1] > radius * 0.9, "{:?} doesn't cover its radius", filter);
+        }
+    }
+
+    #[test]
+    fn box_is_uniform() {
+        let table = PixelFilter::Box.sample_table();
+        for (i, entry) in table.chunks(4).enumerate() {
+            let expected = -0.5 + i as f32 / (FILTER_TABLE_SIZE - 1) as f32;
+            assert!((entry[0] - expected).abs() < 1e-4, "Entry {} is at {} instead of {}", i, entry[0], expected);
+            assert_eq!(&entry[1..], &[1.0, 1.0, 0.5]);
+        }
+    }
+
+    #[test]
+    fn positive_filters_have_weight_1() {
+        for filter in FILTERS[..4].iter() {
+            for entry in filter.sample_table().chunks(4) {
+                assert!((entry[1] - 1.0).abs() < 1e-6 && (entry[2] - 1.0).abs() < 1e-6, "{:?} has weight {:?}", filter, entry);
+            }
+        }
+    }
+
+    #[test]
+    fn mitchell_weights() {
+        let filter = PixelFilter::Mitchell { radius: 2.0, b: 1.0 / 3.0, c: 1.0 / 3.0 };
+        let table = filter.sample_table();
+        assert!(table.chunks(4).any(|entry| entry[1] < 0.0), "Mitchell should have negative lobes");
+
+        let count = 100000;
+        let mut sum = 0.0f64;
+        for s in 0..count {
+            let (offset, weight) = sample(&table, (s as f32 + 0.5) / count as f32);
+            let f = filter.evaluate(offset);
+            //Right at a zero crossing, either sign is fine
+            if f.abs() > 1e-6 {
+                assert_eq!(weight.signum(), f.signum(), "Weight {} at offset {}, where the filter is {}", weight, offset, f);
+            }
+            sum += weight as f64;
+        }
+        let mean = sum / count as f64;
+        assert!((mean - 1.0).abs() < 0.01, "Mean weight is {}", mean);
+    }
+
+    /// Weighted samples have to average to the same as integrating against the normalized filter.
+    #[test]
+    fn samples_follow_the_filter() {
+        let g = |x: f32| x * x + 0.5 * x;
+        for filter in FILTERS.iter() {
+            let radius = filter.radius();
+            let steps = 100000;
+            let dx = 2.0 * radius / steps as f32;
+            let (mut fg, mut f) = (0.0f64, 0.0f64);
+            for i in 0..steps {
+                let x = -radius + (i as f32 + 0.5) * dx;
+                fg += (filter.evaluate(x) * g(x)) as f64;
+                f += filter.evaluate(x) as f64;
+            }
+            let expected = fg / f;
+
+            let table = filter.sample_table();
+            let count = 100000;
+            let mut sum = 0.0f64;
+            for s in 0..count {
+                let (offset, weight) = sample(&table, (s as f32 + 0.5) / count as f32);
+                sum += (weight * g(offset)) as f64;
+            }
+            let result = sum / count as f64;
+            assert!((result - expected).abs() < 0.01, "{:?} gives {} instead of {}", filter, result, expected);
+        }
+    }
+}
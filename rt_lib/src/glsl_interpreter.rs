@@ -0,0 +1,617 @@
+//! Interpreter for the small part of GLSL used by shaders with a CPU side twin,
+//! so tests can run the shader code itself and check both agree.
+//! Only scalars, `vec3` and `mat3` are supported. Functions using other types parse, but panic when called.
+
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Int(i32),
+    Uint(u32),
+    Float(f32),
+    Vec3([f32; 3]),
+    Mat3([f32; 9]),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Uint(_) => "uint",
+            Value::Float(_) => "float",
+            Value::Vec3(_) => "vec3",
+            Value::Mat3(_) => "mat3",
+        }
+    }
+
+    pub fn float(&self) -> f32 {
+        match *self {
+            Value::Int(x) => x as f32,
+            Value::Uint(x) => x as f32,
+            Value::Float(x) => x,
+            v => panic!("Expected a scalar, got {:?}", v),
+        }
+    }
+
+    pub fn uint(&self) -> u32 {
+        match *self {
+            Value::Uint(x) => x,
+            v => panic!("Expected a uint, got {:?}", v),
+        }
+    }
+
+    pub fn vec3(&self) -> [f32; 3] {
+        match *self {
+            Value::Vec3(v) => v,
+            v => panic!("Expected a vec3, got {:?}", v),
+        }
+    }
+
+    fn bool(&self) -> bool {
+        match *self {
+            Value::Bool(x) => x,
+            v => panic!("Expected a bool, got {:?}", v),
+        }
+    }
+
+    /// Scalars get copied to every component, like in `vec3(x)`.
+    fn broadcast(&self) -> [f32; 3] {
+        match *self {
+            Value::Vec3(v) => v,
+            v => [v.float(); 3],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(Value),
+    Ident(String),
+    Punct(&'static str),
+}
+
+//Longer operators first, so they win from their first character
+const PUNCTS: [&str; 32] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=", "*=", "/=",
+    "+", "-", "*", "/", "^", "&", "|", "(", ")", "{", "}", ",", ";", "=", "<", ">", "?", ":", ".", "!",
+];
+
+const TYPES: [&str; 9] = ["void", "bool", "int", "uint", "float", "vec2", "vec3", "vec4", "mat3"];
+
+fn parse_number(text: &str) -> Value {
+    if let Some(digits) = text.strip_suffix('u') {
+        Value::Uint(digits.parse().unwrap_or_else(|_| panic!("Invalid number {}", text)))
+    } else if text.contains('.') || text.contains('e') {
+        Value::Float(text.parse().unwrap_or_else(|_| panic!("Invalid number {}", text)))
+    } else {
+        Value::Int(text.parse().unwrap_or_else(|_| panic!("Invalid number {}", text)))
+    }
+}
+
+fn tokenize(line: &str, tokens: &mut Vec<Token>) {
+    let bytes = line.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i] as char;
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || (c == '.' && bytes.get(i + 1).map_or(false, |c| c.is_ascii_digit())) {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'.' || (bytes[i] == b'-' && bytes[i - 1] == b'e')) {
+                i += 1;
+            }
+            tokens.push(Token::Num(parse_number(&line[start..i])));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(line[start..i].to_string()));
+        } else {
+            let punct = PUNCTS.iter().find(|p| line[i..].starts_with(*p)).unwrap_or_else(|| panic!("Unexpected character '{}'", c));
+            tokens.push(Token::Punct(punct));
+            i += punct.len();
+        }
+    }
+}
+
+/// Tokenizes a whole shader, replacing defines by their value. Other preprocessor lines are ignored.
+fn preprocess(src: &str) -> Vec<Token> {
+    let mut defines: HashMap<String, Vec<Token>> = HashMap::new();
+    let mut tokens = Vec::new();
+    for line in src.lines() {
+        let line = match line.find("//") {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut line_tokens = Vec::new();
+        let define = line.trim().strip_prefix("#define");
+        if line.trim().starts_with('#') && define.is_none() {
+            continue;
+        }
+        tokenize(define.unwrap_or(line), &mut line_tokens);
+
+        let mut expanded = Vec::new();
+        for token in line_tokens {
+            match &token {
+                Token::Ident(name) if defines.contains_key(name) => expanded.extend(defines[name].iter().cloned()),
+                _ => expanded.push(token),
+            }
+        }
+        if define.is_some() {
+            match expanded.first() {
+                Some(Token::Ident(name)) => {
+                    defines.insert(name.clone(), expanded[1..].to_vec());
+                },
+                _ => panic!("Invalid define: {}", line),
+            }
+        } else {
+            tokens.extend(expanded);
+        }
+    }
+    tokens
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Literal(Value),
+    Var(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    Member(Box<Expr>, String),
+}
+
+#[derive(Clone, Debug)]
+enum Stmt {
+    Declare(String, Expr),
+    Assign(String, &'static str, Expr),
+    Return(Expr),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+}
+
+struct Param {
+    ty: String,
+    name: String,
+    /// `inout` and `out` parameters get written back to the variable passed in.
+    inout: bool,
+}
+
+struct Function {
+    params: Vec<Param>,
+    body: Vec<Stmt>,
+}
+
+//Binary operators from the loosest to the tightest binding
+const PRECEDENCE: [&[&str]; 10] = [
+    &["||"], &["&&"], &["|"], &["^"], &["&"], &["==", "!="], &["<", ">", "<=", ">="], &["<<", ">>"], &["+", "-"], &["*", "/"],
+];
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + offset)
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens.get(self.pos).cloned().expect("Unexpected end of the shader!");
+        self.pos += 1;
+        token
+    }
+
+    fn eat_punct(&mut self, punct: &str) -> bool {
+        let found = matches!(self.peek(0), Some(Token::Punct(p)) if *p == punct);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn eat_ident(&mut self, name: &str) -> bool {
+        let found = matches!(self.peek(0), Some(Token::Ident(n)) if n == name);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, punct: &str) {
+        assert!(self.eat_punct(punct), "Expected '{}', got {:?}", punct, self.peek(0));
+    }
+
+    fn ident(&mut self) -> String {
+        match self.next() {
+            Token::Ident(name) => name,
+            token => panic!("Expected a name, got {:?}", token),
+        }
+    }
+
+    fn is_type(&self, offset: usize) -> bool {
+        matches!(self.peek(offset), Some(Token::Ident(name)) if TYPES.contains(&name.as_str()))
+    }
+
+    /// A block in braces, or a single statement.
+    fn block(&mut self) -> Vec<Stmt> {
+        if !self.eat_punct("{") {
+            return vec![self.statement()];
+        }
+        let mut body = Vec::new();
+        while !self.eat_punct("}") {
+            body.push(self.statement());
+        }
+        body
+    }
+
+    fn statement(&mut self) -> Stmt {
+        if self.eat_ident("return") {
+            let value = self.expression();
+            self.expect(";");
+            return Stmt::Return(value);
+        }
+        if self.eat_ident("if") {
+            self.expect("(");
+            let condition = self.expression();
+            self.expect(")");
+            let then = self.block();
+            let otherwise = if self.eat_ident("else") { self.block() } else { Vec::new() };
+            return Stmt::If(condition, then, otherwise);
+        }
+        self.eat_ident("const");
+        if self.is_type(0) && matches!(self.peek(1), Some(Token::Ident(_))) {
+            self.pos += 1;
+            let name = self.ident();
+            self.expect("=");
+            let value = self.expression();
+            self.expect(";");
+            return Stmt::Declare(name, value);
+        }
+        let name = self.ident();
+        let op = match self.next() {
+            Token::Punct(op) if ["=", "+=", "-=", "*=", "/="].contains(&op) => op,
+            token => panic!("Expected an assignment, got {:?}", token),
+        };
+        let value = self.expression();
+        self.expect(";");
+        Stmt::Assign(name, op, value)
+    }
+
+    fn expression(&mut self) -> Expr {
+        let condition = self.binary(0);
+        if self.eat_punct("?") {
+            let a = self.expression();
+            self.expect(":");
+            let b = self.expression();
+            return Expr::Ternary(Box::new(condition), Box::new(a), Box::new(b));
+        }
+        condition
+    }
+
+    fn binary(&mut self, level: usize) -> Expr {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1);
+        loop {
+            let op = match self.peek(0) {
+                Some(Token::Punct(op)) if PRECEDENCE[level].contains(op) => *op,
+                _ => break,
+            };
+            self.pos += 1;
+            let right = self.binary(level + 1);
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        left
+    }
+
+    fn unary(&mut self) -> Expr {
+        if self.eat_punct("-") {
+            return Expr::Unary("-", Box::new(self.unary()));
+        }
+        if self.eat_punct("!") {
+            return Expr::Unary("!", Box::new(self.unary()));
+        }
+        let mut expr = self.primary();
+        while self.eat_punct(".") {
+            expr = Expr::Member(Box::new(expr), self.ident());
+        }
+        expr
+    }
+
+    fn primary(&mut self) -> Expr {
+        match self.next() {
+            Token::Num(value) => Expr::Literal(value),
+            Token::Punct("(") => {
+                let expr = self.expression();
+                self.expect(")");
+                expr
+            },
+            Token::Ident(name) => {
+                if !self.eat_punct("(") {
+                    return Expr::Var(name);
+                }
+                let mut args = Vec::new();
+                while !self.eat_punct(")") {
+                    if !args.is_empty() {
+                        self.expect(",");
+                    }
+                    args.push(self.expression());
+                }
+                Expr::Call(name, args)
+            },
+            token => panic!("Unexpected {:?}", token),
+        }
+    }
+}
+
+fn compare(op: &str, a: f64, b: f64) -> Value {
+    Value::Bool(match op {
+        "==" => a == b,
+        "!=" => a != b,
+        "<" => a < b,
+        ">" => a > b,
+        "<=" => a <= b,
+        ">=" => a >= b,
+        _ => panic!("Unsupported operator {}", op),
+    })
+}
+
+fn arithmetic(op: &str, a: f32, b: f32) -> f32 {
+    match op {
+        "+" => a + b,
+        "-" => a - b,
+        "*" => a * b,
+        "/" => a / b,
+        _ => panic!("Unsupported operator {} on floats", op),
+    }
+}
+
+fn binary(op: &str, a: Value, b: Value) -> Value {
+    match (a, b) {
+        (Value::Uint(a), Value::Uint(b)) => match op {
+            "+" => Value::Uint(a.wrapping_add(b)),
+            "-" => Value::Uint(a.wrapping_sub(b)),
+            "*" => Value::Uint(a.wrapping_mul(b)),
+            "/" => Value::Uint(a / b),
+            "^" => Value::Uint(a ^ b),
+            "&" => Value::Uint(a & b),
+            "|" => Value::Uint(a | b),
+            "<<" => Value::Uint(a.wrapping_shl(b)),
+            ">>" => Value::Uint(a.wrapping_shr(b)),
+            _ => compare(op, a as f64, b as f64),
+        },
+        (Value::Int(a), Value::Int(b)) => match op {
+            "+" => Value::Int(a.wrapping_add(b)),
+            "-" => Value::Int(a.wrapping_sub(b)),
+            "*" => Value::Int(a.wrapping_mul(b)),
+            "/" => Value::Int(a / b),
+            _ => compare(op, a as f64, b as f64),
+        },
+        (Value::Bool(a), Value::Bool(b)) => match op {
+            "==" => Value::Bool(a == b),
+            "!=" => Value::Bool(a != b),
+            _ => panic!("Unsupported operator {} on bools", op),
+        },
+        (Value::Mat3(m), Value::Vec3(v)) if op == "*" => {
+            //Column major, like GLSL
+            Value::Vec3([
+                m[0] * v[0] + m[3] * v[1] + m[6] * v[2],
+                m[1] * v[0] + m[4] * v[1] + m[7] * v[2],
+                m[2] * v[0] + m[5] * v[1] + m[8] * v[2],
+            ])
+        },
+        (Value::Vec3(_), _) | (_, Value::Vec3(_)) => {
+            let (a, b) = (a.broadcast(), b.broadcast());
+            Value::Vec3([arithmetic(op, a[0], b[0]), arithmetic(op, a[1], b[1]), arithmetic(op, a[2], b[2])])
+        },
+        _ => match op {
+            "+" | "-" | "*" | "/" => Value::Float(arithmetic(op, a.float(), b.float())),
+            _ => compare(op, a.float() as f64, b.float() as f64),
+        },
+    }
+}
+
+fn map(value: Value, f: fn(f32) -> f32) -> Value {
+    match value {
+        Value::Vec3(v) => Value::Vec3([f(v[0]), f(v[1]), f(v[2])]),
+        v => Value::Float(f(v.float())),
+    }
+}
+
+fn zip(a: Value, b: Value, f: fn(f32, f32) -> f32) -> Value {
+    match (a, b) {
+        (Value::Vec3(_), _) | (_, Value::Vec3(_)) => {
+            let (a, b) = (a.broadcast(), b.broadcast());
+            Value::Vec3([f(a[0], b[0]), f(a[1], b[1]), f(a[2], b[2])])
+        },
+        _ => Value::Float(f(a.float(), b.float())),
+    }
+}
+
+fn builtin(name: &str, args: &[Value]) -> Option<Value> {
+    Some(match name {
+        "float" => Value::Float(args[0].float()),
+        "int" => Value::Int(args[0].float() as i32),
+        "uint" => Value::Uint(match args[0] {
+            Value::Uint(x) => x,
+            Value::Int(x) => x as u32,
+            v => v.float() as u32,
+        }),
+        "vec3" if args.len() == 1 => Value::Vec3(args[0].broadcast()),
+        "vec3" => Value::Vec3([args[0].float(), args[1].float(), args[2].float()]),
+        "mat3" => {
+            let mut m = [0.0; 9];
+            for (i, arg) in args.iter().enumerate() {
+                m[i] = arg.float();
+            }
+            Value::Mat3(m)
+        },
+        "sqrt" => map(args[0], f32::sqrt),
+        "abs" => map(args[0], f32::abs),
+        "log2" => map(args[0], f32::log2),
+        "exp2" => map(args[0], f32::exp2),
+        "pow" => zip(args[0], args[1], f32::powf),
+        "max" => zip(args[0], args[1], f32::max),
+        "min" => zip(args[0], args[1], f32::min),
+        "clamp" => zip(zip(args[0], args[1], f32::max), args[2], f32::min),
+        _ => return None,
+    })
+}
+
+/// The global constants and functions of a shader.
+pub struct Program {
+    functions: HashMap<String, Vec<Function>>,
+    globals: HashMap<String, Value>,
+}
+
+impl Program {
+    pub fn parse(src: &str) -> Self {
+        let mut parser = Parser {
+            tokens: preprocess(src),
+            pos: 0,
+        };
+        let mut program = Self {
+            functions: HashMap::new(),
+            globals: HashMap::new(),
+        };
+        while parser.peek(0).is_some() {
+            parser.eat_ident("const");
+            assert!(parser.is_type(0), "Expected a function or constant, got {:?}", parser.peek(0));
+            parser.pos += 1;
+            let name = parser.ident();
+            if parser.eat_punct("(") {
+                let mut params = Vec::new();
+                while !parser.eat_punct(")") {
+                    if !params.is_empty() {
+                        parser.expect(",");
+                    }
+                    parser.eat_ident("const");
+                    parser.eat_ident("in");
+                    let inout = parser.eat_ident("inout") || parser.eat_ident("out");
+                    let ty = parser.ident();
+                    params.push(Param {
+                        ty: ty,
+                        name: parser.ident(),
+                        inout: inout,
+                    });
+                }
+                let body = parser.block();
+                program.functions.entry(name).or_insert_with(Vec::new).push(Function {
+                    params: params,
+                    body: body,
+                });
+            } else {
+                parser.expect("=");
+                let value = parser.expression();
+                parser.expect(";");
+                let value = program.eval(&value, &mut HashMap::new());
+                program.globals.insert(name, value);
+            }
+        }
+        program
+    }
+
+    /// Calls a function of the shader. Overloads are picked by the exact types of the arguments.
+    pub fn call(&self, name: &str, args: &[Value]) -> Value {
+        self.call_function(name, &mut args.to_vec())
+    }
+
+    /// Arguments for `inout` parameters get the value they end up with.
+    fn call_function(&self, name: &str, args: &mut [Value]) -> Value {
+        let function = self.functions.get(name).and_then(|overloads| overloads.iter().find(|function| {
+            function.params.len() == args.len() && function.params.iter().zip(args.iter()).all(|(param, arg)| param.ty == arg.type_name())
+        })).unwrap_or_else(|| panic!("No function {} for {:?}", name, args));
+
+        let mut locals: HashMap<String, Value> = function.params.iter().map(|param| param.name.clone()).zip(args.iter().copied()).collect();
+        let result = self.exec(&function.body, &mut locals);
+        for (param, arg) in function.params.iter().zip(args.iter_mut()) {
+            if param.inout {
+                *arg = locals[&param.name];
+            }
+        }
+        //Void functions give back a value nobody uses
+        result.unwrap_or(Value::Bool(false))
+    }
+
+    fn exec(&self, body: &[Stmt], locals: &mut HashMap<String, Value>) -> Option<Value> {
+        for stmt in body {
+            match stmt {
+                Stmt::Declare(name, value) => {
+                    let value = self.eval(value, locals);
+                    locals.insert(name.clone(), value);
+                },
+                Stmt::Assign(name, op, value) => {
+                    let value = self.eval(value, locals);
+                    let old = *locals.get(name).unwrap_or_else(|| panic!("Unknown variable {}", name));
+                    let value = if *op == "=" { value } else { binary(&op[..1], old, value) };
+                    locals.insert(name.clone(), value);
+                },
+                Stmt::Return(value) => return Some(self.eval(value, locals)),
+                Stmt::If(condition, then, otherwise) => {
+                    let branch = if self.eval(condition, locals).bool() { then } else { otherwise };
+                    if let Some(result) = self.exec(branch, locals) {
+                        return Some(result);
+                    }
+                },
+            }
+        }
+        None
+    }
+
+    fn eval(&self, expr: &Expr, locals: &mut HashMap<String, Value>) -> Value {
+        match expr {
+            Expr::Literal(value) => *value,
+            Expr::Var(name) => *locals.get(name).or_else(|| self.globals.get(name)).unwrap_or_else(|| panic!("Unknown variable {}", name)),
+            Expr::Unary("!", value) => Value::Bool(!self.eval(value, locals).bool()),
+            Expr::Unary(_, value) => match self.eval(value, locals) {
+                Value::Int(x) => Value::Int(-x),
+                Value::Uint(x) => Value::Uint(x.wrapping_neg()),
+                v => map(v, |x| -x),
+            },
+            Expr::Binary("&&", a, b) => Value::Bool(self.eval(a, locals).bool() && self.eval(b, locals).bool()),
+            Expr::Binary("||", a, b) => Value::Bool(self.eval(a, locals).bool() || self.eval(b, locals).bool()),
+            Expr::Binary(op, a, b) => {
+                let a = self.eval(a, locals);
+                let b = self.eval(b, locals);
+                binary(op, a, b)
+            },
+            Expr::Ternary(condition, a, b) => {
+                if self.eval(condition, locals).bool() {
+                    self.eval(a, locals)
+                } else {
+                    self.eval(b, locals)
+                }
+            },
+            Expr::Member(value, field) => {
+                let v = self.eval(value, locals).vec3();
+                Value::Float(match field.as_str() {
+                    "x" | "r" => v[0],
+                    "y" | "g" => v[1],
+                    "z" | "b" => v[2],
+                    _ => panic!("Unsupported swizzle .{}", field),
+                })
+            },
+            Expr::Call(name, args) => {
+                let mut values: Vec<Value> = args.iter().map(|arg| self.eval(arg, locals)).collect();
+                if let Some(result) = builtin(name, &values) {
+                    return result;
+                }
+                let result = self.call_function(name, &mut values);
+                for (arg, value) in args.iter().zip(values) {
+                    if let Expr::Var(name) = arg {
+                        if locals.contains_key(name) {
+                            locals.insert(name.clone(), value);
+                        }
+                    }
+                }
+                result
+            },
+        }
+    }
+}
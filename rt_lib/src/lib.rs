@@ -3,7 +3,6 @@
 use std::sync::Mutex;
 use std::collections::HashMap;
 
-use glux::gl_types::ShaderStorageBuffer;
 use glux::{
     mesh::Mesh,
     shader::{Shader, ShaderProgram},
@@ -12,84 +11,124 @@ use glux::{
 
 pub mod shader_processor;
 pub mod objects;
+pub mod output;
+pub mod tonemap;
+pub mod settings;
+pub mod denoise;
+pub mod dispatch;
+pub mod random;
+pub mod sampler;
+pub mod filter;
+pub mod scene;
+pub mod animation;
+pub mod sequence;
+pub mod queue;
+pub mod bvh;
+pub mod mesh;
+pub mod brickmap;
+pub mod volume;
+#[cfg(test)]
+mod glsl_interpreter;
 
 use objects::{
     Camera,
+    CameraState,
+    Focus,
 
     IsBRDF,
 };
+use tonemap::ViewTransform;
+use settings::{RenderSettings, MAX_TYPE_DEPTH};
+use scene::Scene;
 
 const PASSTHROUGH_VS_SRC: &str = include_str!("../shaders/passthrough_vs.glsl");
-const PASSTHROUGH_FS_SRC: &str = include_str!("../shaders/passthrough_fs.glsl");
 
+const OUTPUT_FS_PATH:     &str = "rt_lib/shaders/passthrough_fs.glsl";
 const RAYTRACING_CS_PATH: &str = "rt_lib/shaders/raytracing_cs.glsl";
 const COMBINE_CS_PATH:    &str = "rt_lib/shaders/combine_cs.glsl";
 const SHADING_CS_PATH:    &str = "rt_lib/shaders/shading_cs.glsl";
 const WAVE_CS_PATH:       &str = "rt_lib/shaders/spawn_wave_cs.glsl";
+const QUEUE_CS_PATH:      &str = "rt_lib/shaders/queue_cs.glsl";
 
-fn string_to_id(input: String) -> u32 {
-    let mut result = 0;
-    let mut mult = 1;
+/// Id of a BRDF in `material()`, from its signature. Wraps around for long signatures,
+/// and stays positive so it fits in a GLSL int.
+pub(crate) fn string_to_id(input: String) -> u32 {
+    let mut result: u32 = 0;
+    let mut mult: u32 = 1;
     for c in input.chars() {
-        result += c as u32 * mult;
-        mult *= 10;
+        result = result.wrapping_add((c as u32).wrapping_mul(mult));
+        mult = mult.wrapping_mul(10);
+    }
+    result & 0x7FFFFFFF
+}
+
+/// Sets an unsigned integer uniform on the currently bound program.
+//TODO: Implement this in glux, so we don't have to wrap it here
+pub(crate) fn set_uniform_u32(name: &str, value: u32) {
+    let name = std::ffi::CString::new(name).expect("Uniform name contains a null byte!");
+    unsafe {
+        let mut program = 0;
+        gl::GetIntegerv(gl::CURRENT_PROGRAM, &mut program);
+        let location = gl::GetUniformLocation(program as u32, name.as_ptr());
+        gl::Uniform1ui(location, value);
     }
-    result
 }
 
 pub struct Raytracer {
     raytrace_program: ShaderProgram,
     shading_program: ShaderProgram,
     wave_program: ShaderProgram,
+    queue_program: ShaderProgram,
     combine_program: ShaderProgram,
     output_program: ShaderProgram,
 
     brdf_src: HashMap<String, (String, String)>,
 
-    rng_ssbo: ShaderStorageBuffer,
+    /// Camera the accumulated samples belong to.
+    camera_state: Option<CameraState>,
+
+    settings: RenderSettings,
 
     dispatch_size: (u32, u32),
-    bounces: u32,
     samples: u32,
+
+    /// Transform used to display the render. Use the same one when saving LDR images.
+    pub view_transform: ViewTransform,
 }
 
 impl Raytracer {
-    pub fn new(dispatch_size: (u32, u32)) -> Self {
-        use rand::Rng;
+    pub fn new(dispatch_size: (u32, u32), settings: RenderSettings) -> Self {
+        let dispatch_size = dispatch::validate_dispatch_size(dispatch_size);
+        settings.validate();
 
         let output_vs = Shader::from_source(PASSTHROUGH_VS_SRC, gl::VERTEX_SHADER).expect("Failed to compile shader!");
-        let output_fs = Shader::from_source(PASSTHROUGH_FS_SRC, gl::FRAGMENT_SHADER).expect("Failed to compile shader!");
+        let output_fs_src = shader_processor::preprocessor(std::path::Path::new(OUTPUT_FS_PATH), dispatch_size);
+        let output_fs = Shader::from_source(&output_fs_src, gl::FRAGMENT_SHADER).expect("Failed to compile shader!");
         let output_program = ShaderProgram::from_shaders(vec![&output_vs, &output_fs]);
         debug!("Output shader loaded!");
 
-        let raytracing_cs_src = shader_processor::preprocessor(std::path::Path::new(RAYTRACING_CS_PATH), dispatch_size);
-        let raytracing_cs = Shader::from_source(&raytracing_cs_src, gl::COMPUTE_SHADER).expect("Failed to compile shader!");
-        let raytracing_program = ShaderProgram::from_shader(&raytracing_cs);
+        let defines = settings.shader_defines();
+
+        let raytracing_program = Self::compile_raytracing_program(dispatch_size, &defines);
         debug!("Raytracing shader loaded!");
 
-        let shading_cs_src = shader_processor::preprocessor(std::path::Path::new(SHADING_CS_PATH), dispatch_size);
+        let shading_cs_src = shader_processor::preprocessor_with_defines(std::path::Path::new(SHADING_CS_PATH), dispatch_size, &defines);
         let shading_cs = Shader::from_source(&shading_cs_src, gl::COMPUTE_SHADER).expect("Failed to compile shader!");
         let shading_program = ShaderProgram::from_shader(&shading_cs);
         debug!("Shading shader loaded!");
 
-        let wave_cs_src = shader_processor::preprocessor(std::path::Path::new(WAVE_CS_PATH), dispatch_size);
+        let wave_cs_src = shader_processor::preprocessor_with_defines(std::path::Path::new(WAVE_CS_PATH), dispatch_size, &defines);
         let wave_cs = Shader::from_source(&wave_cs_src, gl::COMPUTE_SHADER).expect("Failed to compile shader!");
         let wave_program = ShaderProgram::from_shader(&wave_cs);
         debug!("Wave spawn shader loaded!");
 
-        let combine_cs_src = shader_processor::preprocessor(std::path::Path::new(COMBINE_CS_PATH), dispatch_size);
-        let combine_cs = Shader::from_source(&combine_cs_src, gl::COMPUTE_SHADER).expect("Failed to compile shader!");
-        let combine_program = ShaderProgram::from_shader(&combine_cs);
-        debug!("Combine shader loaded!");
+        let queue_cs_src = shader_processor::preprocessor(std::path::Path::new(QUEUE_CS_PATH), dispatch_size);
+        let queue_cs = Shader::from_source(&queue_cs_src, gl::COMPUTE_SHADER).expect("Failed to compile shader!");
+        let queue_program = ShaderProgram::from_shader(&queue_cs);
+        debug!("Ray queue shader loaded!");
 
-        let mut rng = rand::thread_rng();
-
-        let rng_ssbo = glux::gl_types::ShaderStorageBuffer::new();
-        let rng_vec: Vec<f32> = (0..1280*720).map(|_| rng.gen_range(0f32..2048f32)).collect();
-        rng_ssbo.bind();
-        rng_ssbo.data(&rng_vec[..], gl::DYNAMIC_COPY);
-        rng_ssbo.unbind();
-        debug!("RNG ssbo generated!");
+        let combine_program = Self::compile_combine_program(dispatch_size, &defines);
+        debug!("Combine shader loaded!");
 
         debug!("Raytracer loaded!");
 
@@ -97,23 +136,66 @@ impl Raytracer {
             raytrace_program: raytracing_program,
             shading_program: shading_program,
             wave_program: wave_program,
+            queue_program: queue_program,
             combine_program: combine_program,
             output_program: output_program,
 
             brdf_src: HashMap::new(),
 
-            rng_ssbo: rng_ssbo,
+            camera_state: None,
 
-            dispatch_size: dispatch_size, //TODO: Connect this + workgroup size in shader together
-            bounces: 4,
+            settings: settings,
+
+            dispatch_size: dispatch_size,
             samples: 0,
+
+            view_transform: ViewTransform::default(),
         }
     }
 
+    fn compile_raytracing_program(dispatch_size: (u32, u32), defines: &[(String, String)]) -> ShaderProgram {
+        let raytracing_cs_src = shader_processor::preprocessor_with_defines(std::path::Path::new(RAYTRACING_CS_PATH), dispatch_size, defines);
+        let raytracing_cs = Shader::from_source(&raytracing_cs_src, gl::COMPUTE_SHADER).expect("Failed to compile shader!");
+        ShaderProgram::from_shader(&raytracing_cs)
+    }
+
+    fn compile_combine_program(dispatch_size: (u32, u32), defines: &[(String, String)]) -> ShaderProgram {
+        let combine_cs_src = shader_processor::preprocessor_with_defines(std::path::Path::new(COMBINE_CS_PATH), dispatch_size, defines);
+        let combine_cs = Shader::from_source(&combine_cs_src, gl::COMPUTE_SHADER).expect("Failed to compile shader!");
+        ShaderProgram::from_shader(&combine_cs)
+    }
+
+    pub fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    /// Changes the render settings.
+    /// Warning: recompiles every shader that depends on them.
+    pub fn set_settings(&mut self, settings: RenderSettings) {
+        settings.validate();
+        self.settings = settings;
+        self.update_brdf_general();
+        self.raytrace_program = Self::compile_raytracing_program(self.dispatch_size, &self.settings.shader_defines());
+        debug!("Raytracing shader reloaded!");
+        self.combine_program = Self::compile_combine_program(self.dispatch_size, &self.settings.shader_defines());
+        debug!("Combine shader reloaded!");
+        self.reset_accumulation();
+    }
+
+    /// Throws away all samples, so the next sample starts a new render.
+    /// This happens automatically when the camera, scene, settings or BRDFs change,
+    /// but anything else affecting the image (like shader edits) needs to call this.
+    pub fn reset_accumulation(&mut self) {
+        self.samples = 0;
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
     /// Adds/updates a brdf in the shader.
     /// Warning: recompiles the entire shader.
     /// Not too heavy however to recompile.
-    //TODO: reset the renderer, otherwise it'll use samples with a different BRDF
     pub fn add_brdf(&mut self, brdf: &dyn IsBRDF) {
         if !self.brdf_src.contains_key(&brdf.signature()) {
             self.brdf_src.insert(brdf.signature(), (brdf.signature(), brdf.code()));
@@ -121,6 +203,7 @@ impl Raytracer {
             todo!("Overwrite the old brdf");
         }
         self.update_brdf_general();
+        self.reset_accumulation();
     }
 
     fn update_brdf_general(&mut self) {
@@ -166,7 +249,7 @@ vec3 material(int id, Material mat, vec3 light, vec3 view, vec3 normal, vec3 tan
 	return builtin_lambert(mat, light, view, normal, tangent, binormal);
 }";
 
-        let shading_cs_src = shader_processor::preprocessor(std::path::Path::new(SHADING_CS_PATH), self.dispatch_size);
+        let shading_cs_src = shader_processor::preprocessor_with_defines(std::path::Path::new(SHADING_CS_PATH), self.dispatch_size, &self.settings.shader_defines());
         let shading_cs = match Shader::from_source(&shading_cs_src, gl::COMPUTE_SHADER) {
             Ok(cs) => cs,
             Err(_) => {
@@ -180,7 +263,7 @@ vec3 material(int id, Material mat, vec3 light, vec3 view, vec3 normal, vec3 tan
         debug!("Shading shader reloaded!");
         self.shading_program = shading_program;
 
-        let wave_cs_src = shader_processor::preprocessor(std::path::Path::new(WAVE_CS_PATH), self.dispatch_size);
+        let wave_cs_src = shader_processor::preprocessor_with_defines(std::path::Path::new(WAVE_CS_PATH), self.dispatch_size, &self.settings.shader_defines());
         println!("{}", wave_cs_src);
         let wave_cs = match Shader::from_source(&wave_cs_src, gl::COMPUTE_SHADER) {
             Ok(cs) => cs,
@@ -202,67 +285,147 @@ vec3 material(int id, Material mat, vec3 light, vec3 view, vec3 normal, vec3 tan
         }
     }
 
-    pub fn render_sample(&mut self, camera: &mut Camera) {
-        use rand::Rng;
+    /// Traces the rays in the camera's ray buffer, writing the hits to its hit buffer.
+    fn trace(&self, camera: &Camera, scene: &Scene, bounce: u32) {
+        self.raytrace_program.bind();
+        camera.hit_ssbo.bind_buffer_base(0);
+        camera.ray_ssbo.bind_buffer_base(1);
+        scene.ssbo.bind_buffer_base(2);
+        scene.shape_ssbo.bind_buffer_base(3);
+        camera.ray_queue.bind_buffer_base(4);
+        scene.bvh_ssbo.bind_buffer_base(5);
+        scene.vertex_ssbo.bind_buffer_base(6);
+        scene.triangle_ssbo.bind_buffer_base(7);
+        scene.brick_atlas.bind(0, 1);
+        scene.medium_table.bind(0, 2);
+        camera.bind_aov_sample_textures(2);
+        self.raytrace_program.uniform("sdf_count", scene.sdf_count() as f32);
+        self.raytrace_program.uniform("bounded_count", scene.bounded_count() as f32);
+        self.raytrace_program.uniform("bvh_node_count", scene.bvh_node_count() as f32);
+        self.raytrace_program.uniform("instance_root", scene.instance_root() as f32);
+        self.raytrace_program.uniform("shutter", f32_f32::from(camera.shutter));
+        self.raytrace_program.uniform("pixel_cone", camera.pixel_cone());
+        self.raytrace_program.uniform("far", camera.far);
+        self.raytrace_program.uniform("fog_medium", scene.fog_medium() as f32);
+        self.raytrace_program.uniform("volume_first", scene.volume_first() as f32);
+        self.raytrace_program.uniform("volume_count", scene.volume_count() as f32);
+        self.raytrace_program.uniform("volume_nodes", scene.volume_nodes() as f32);
+        self.raytrace_program.uniform("dims", f32_f32::from( (camera.resolution.0 as f32, camera.resolution.1 as f32) ));
+        self.raytrace_program.uniform("bounce", bounce as f32);
+        set_uniform_u32("sample_index", self.samples);
+        set_uniform_u32("seed", self.settings.seed);
+        self.raytrace_program.uniform("sampler_kind", self.settings.sampler as i32 as f32);
+        camera.ray_queue.dispatch();
+        camera.ray_ssbo.bind_buffer_base(0);
+        self.raytrace_program.unbind();
 
-        // trace!("RNG ssbo updated!");
-        // trace!("Time since start: {:?}", Instant::now() - func_start);
+        unsafe {
+            gl::MemoryBarrier(gl::SHADER_STORAGE_BARRIER_BIT | gl::SHADER_IMAGE_ACCESS_BARRIER_BIT);
+        }
+    }
 
-        camera.generate_rays(self.dispatch_size);
-        camera.clear_sample_texture();
+    /// Light only gets in and out of subsurface materials through their BRDF.
+    /// Without one that lets it through, like `Subsurface`, they silently render black.
+    fn check_subsurface_brdfs(&self, scene: &Scene) {
+        for (i, material) in scene.materials.iter().enumerate() {
+            if material.subsurface.max_element() > 0.0 {
+                let registered = material.brdf.as_ref().map_or(false, |brdf| self.brdf_src.contains_key(brdf));
+                assert!(registered, "Material {} is a subsurface material, but its BRDF {:?} isn't added to the raytracer! Add `Subsurface` with `add_brdf`.", i, material.brdf);
+            }
+        }
+    }
 
-        // trace!("Rays generated and sample texture cleared!");
+    /// Traces camera rays once to find the focus distance, before the first sample.
+    fn autofocus(&self, camera: &mut Camera, scene: &Scene) {
+        camera.generate_rays(self.dispatch_size, self.samples, &self.settings);
+        self.trace(camera, scene, 0);
+        unsafe {
+            //The step count AOV gets written here too, and has to be cleared after
+            gl::MemoryBarrier(gl::BUFFER_UPDATE_BARRIER_BIT | gl::TEXTURE_UPDATE_BARRIER_BIT);
+        }
+        if !camera.autofocus_from_hits() {
+            trace!("Autofocus kept the focus distance at {}", camera.focus_distance);
+        }
+    }
+
+    pub fn render_sample(&mut self, camera: &mut Camera, scene: &mut Scene) {
         // trace!("Time since start: {:?}", Instant::now() - func_start);
 
-        if self.samples % 32 == 0 {
-            // let mut rng = rand::thread_rng();
-            // let rng_vec: Vec<f32> = (0..128).map(|_| rng.gen_range(1f32..2048f32)).collect();
-            // self.rng_ssbo.bind();
-            // self.rng_ssbo.data(&rng_vec[..], gl::DYNAMIC_COPY);
-            // self.rng_ssbo.unbind();
-            debug!("Refreshed RNG buffer! Samples: {}", self.samples);
+        let camera_state = camera.state();
+        if self.camera_state.as_ref() != Some(&camera_state) {
+            //Samples from another viewpoint or resolution can't be combined with new ones
+            self.camera_state = Some(camera_state);
+            self.reset_accumulation();
+        }
+        if scene.update(camera.shutter) {
+            self.check_subsurface_brdfs(scene);
+            self.reset_accumulation();
+        }
+        if self.samples == 0 {
+            camera.clear_render_texture();
         }
 
-        for _i in 0..self.bounces {
-            self.raytrace_program.bind();
-            camera.hit_ssbo.bind_buffer_base(0);
-            camera.ray_ssbo.bind_buffer_base(1);
-            self.raytrace_program.uniform("dims", f32_f32::from( (camera.resolution.0 as f32, camera.resolution.1 as f32) ));
-            unsafe {
-                gl::DispatchCompute(camera.resolution.0 as u32 / self.dispatch_size.0, camera.resolution.1 as u32 / self.dispatch_size.1, 1);
-            }
-            camera.ray_ssbo.bind_buffer_base(0);
-            self.raytrace_program.unbind();
+        camera.set_aovs(&self.settings.aovs);
+        //Without a lens opening everything is in focus, so there is nothing to focus on
+        let has_depth_of_field = camera.lens.aperture_radius(camera.focal_length()) > 0.0;
+        if self.samples == 0 && camera.lens.focus == Focus::Auto && has_depth_of_field {
+            self.autofocus(camera, scene);
+        }
+        camera.generate_rays(self.dispatch_size, self.samples, &self.settings);
+        camera.clear_sample_texture();
 
-            unsafe {
-                gl::MemoryBarrier(gl::SHADER_STORAGE_BARRIER_BIT);
-            }
+        // trace!("Rays generated and sample texture cleared!");
+        // trace!("Time since start: {:?}", Instant::now() - func_start);
+
+        let depth = self.settings.path_depth;
+        for bounce in 0..=depth.max {
+            self.trace(camera, scene, bounce);
 
             //Generate new rays from hits
             self.wave_program.bind();
             self.wave_program.uniform("dims", f32_f32::from( (camera.resolution.0 as f32, camera.resolution.1 as f32) ));
-            self.wave_program.uniform("samples", self.samples as f32);
+            self.wave_program.uniform("bounce", bounce as f32);
+            set_uniform_u32("sample_index", self.samples);
+            set_uniform_u32("seed", self.settings.seed);
+            self.wave_program.uniform("sampler_kind", self.settings.sampler as i32 as f32);
+            self.wave_program.uniform("max_depth", depth.max as f32);
+            self.wave_program.uniform("max_diffuse_depth", depth.diffuse.min(MAX_TYPE_DEPTH) as f32);
+            self.wave_program.uniform("max_glossy_depth", depth.glossy.min(MAX_TYPE_DEPTH) as f32);
+            self.wave_program.uniform("max_transmission_depth", depth.transmission.min(MAX_TYPE_DEPTH) as f32);
+            self.wave_program.uniform("roulette_depth", depth.roulette.map_or(f32::MAX, |d| d as f32));
             camera.hit_ssbo.bind_buffer_base(0);
-            self.rng_ssbo.bind_buffer_base(1);
             camera.ray_ssbo.bind_buffer_base(2);
-            unsafe {
-                gl::DispatchCompute(camera.resolution.0 as u32 / self.dispatch_size.0, camera.resolution.1 as u32 / self.dispatch_size.1, 1);
-            }
+            scene.material_ssbo.bind_buffer_base(3);
+            camera.ray_queue.bind_buffer_base(4);
+            scene.medium_table.bind(0, 2);
+            camera.ray_queue.dispatch();
             camera.hit_ssbo.bind_buffer_base(0);
-            self.rng_ssbo.bind_buffer_base(0);
             camera.ray_ssbo.bind_buffer_base(0);
             self.wave_program.unbind();
 
             //Shade hits and output to texture
             self.shading_program.bind();
             camera.bind_sample_texture(0);
-            self.shading_program.uniform("dims", f32_f32::from( (camera.resolution.0 as f32, camera.resolution.1 as f32) ));
+            camera.bind_aov_sample_textures(2);
+            self.shading_program.uniform("bounce", bounce as f32);
             camera.hit_ssbo.bind_buffer_base(1);
-            unsafe {
-                gl::DispatchCompute(camera.resolution.0 as u32 / self.dispatch_size.0, camera.resolution.1 as u32 / self.dispatch_size.1, 1);
-            }
+            scene.material_ssbo.bind_buffer_base(3);
+            camera.ray_queue.bind_buffer_base(4);
+            camera.ray_queue.dispatch();
             camera.hit_ssbo.bind_buffer_base(0);
             self.shading_program.unbind();
+
+            //The rays spawned above become the next wave, once everything is done with the current one
+            unsafe {
+                gl::MemoryBarrier(gl::SHADER_STORAGE_BARRIER_BIT);
+            }
+            self.queue_program.bind();
+            camera.ray_queue.bind_buffer_base(4);
+            unsafe {
+                gl::DispatchCompute(1, 1, 1);
+                gl::MemoryBarrier(gl::SHADER_STORAGE_BARRIER_BIT | gl::COMMAND_BARRIER_BIT);
+            }
+            self.queue_program.unbind();
         }
 
         self.samples += 1;
@@ -270,12 +433,13 @@ vec3 material(int id, Material mat, vec3 light, vec3 view, vec3 normal, vec3 tan
         // println!("Samples: {}", samples);
 
         self.combine_program.bind();
+        self.combine_program.uniform("dims", f32_f32::from( (camera.resolution.0 as f32, camera.resolution.1 as f32) ));
         self.combine_program.uniform("samples", self.samples as f32);
         camera.bind_sample_texture(0);
         camera.bind_final_texture(1);
-        unsafe {
-            gl::DispatchCompute(camera.resolution.0 as u32 / self.dispatch_size.0, camera.resolution.1 as u32 / self.dispatch_size.1, 1);
-        }
+        camera.bind_aov_sample_textures(2);
+        camera.bind_aov_final_textures(3);
+        dispatch::dispatch_compute(camera.resolution, self.dispatch_size);
         self.combine_program.unbind();
     }
 
@@ -285,6 +449,8 @@ vec3 material(int id, Material mat, vec3 light, vec3 view, vec3 normal, vec3 tan
         }
 
         self.output_program.bind();
+        self.output_program.uniform("exposure", self.view_transform.exposure);
+        self.output_program.uniform("tone_curve", self.view_transform.curve as i32 as f32);
         camera.render_buffer.bind();
         // camera.sample_buffer.bind();
         mesh.draw();
@@ -0,0 +1,197 @@
+//! Triangle meshes, traced directly instead of being turned into a distance field.
+//! Meshes are placed in the scene through objects with a `Shape::Mesh`, so one mesh can be used many times.
+
+use std::io;
+use std::path::Path;
+
+use glam::*;
+
+use crate::bvh::Aabb;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TriangleMesh {
+    pub positions: Vec<Vec3>,
+    /// One for every position.
+    pub normals: Vec<Vec3>,
+    /// One for every position, or empty.
+    pub uvs: Vec<Vec2>,
+    /// 3 positions for every triangle.
+    pub indices: Vec<u32>,
+}
+
+fn invalid_data(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Checks indices from a file before they get used, as a broken file would otherwise panic deep inside `compute_normals`.
+fn check_indices(indices: &[u32], vertex_count: usize) -> io::Result<()> {
+    if indices.len() % 3 != 0 {
+        return Err(invalid_data(format!("Mesh has {} indices, which isn't a multiple of 3", indices.len())));
+    }
+    if let Some(index) = indices.iter().find(|i| **i as usize >= vertex_count) {
+        return Err(invalid_data(format!("Mesh index {} is out of bounds, there are only {} vertices", index, vertex_count)));
+    }
+    Ok(())
+}
+
+/// Checks that a vertex attribute has a value for every position.
+fn check_attribute(name: &str, count: usize, vertex_count: usize) -> io::Result<()> {
+    if count != vertex_count {
+        return Err(invalid_data(format!("Mesh has {} {}, but {} positions", count, name, vertex_count)));
+    }
+    Ok(())
+}
+
+impl TriangleMesh {
+    /// Creates a mesh with smooth normals and without uvs.
+    pub fn new(positions: Vec<Vec3>, indices: Vec<u32>) -> Self {
+        let mut mesh = Self {
+            normals: Vec::new(),
+            positions: positions,
+            uvs: Vec::new(),
+            indices: indices,
+        };
+        mesh.compute_normals();
+        mesh
+    }
+
+    /// Loads every mesh in an OBJ or glTF (.gltf/.glb) file.
+    /// Materials in the file are ignored, and so are glTF node transforms.
+    pub fn load(path: &Path) -> io::Result<Vec<TriangleMesh>> {
+        let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+        let meshes = match extension.as_deref() {
+            Some("obj") => Self::load_obj(path)?,
+            Some("gltf") | Some("glb") => Self::load_gltf(path)?,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unsupported mesh format: {:?}", path))),
+        };
+        debug!("Loaded {} meshes from {:?}", meshes.len(), path);
+        Ok(meshes)
+    }
+
+    fn load_obj(path: &Path) -> io::Result<Vec<TriangleMesh>> {
+        let options = tobj::LoadOptions {
+            triangulate: true,
+            single_index: true,
+            ..Default::default()
+        };
+        let (models, _) = tobj::load_obj(path, &options).map_err(invalid_data)?;
+
+        models.into_iter().map(|model| {
+            let mesh = model.mesh;
+            let positions: Vec<Vec3> = mesh.positions.chunks(3).map(|p| vec3(p[0], p[1], p[2])).collect();
+            check_indices(&mesh.indices, positions.len())?;
+            let mut result = Self::new(positions, mesh.indices);
+            if mesh.normals.len() == mesh.positions.len() {
+                result.normals = mesh.normals.chunks(3).map(|n| vec3(n[0], n[1], n[2]).normalize_or_zero()).collect();
+            }
+            if !mesh.texcoords.is_empty() {
+                check_attribute("uvs", mesh.texcoords.len() / 2, result.positions.len())?;
+                result.uvs = mesh.texcoords.chunks(2).map(|uv| vec2(uv[0], uv[1])).collect();
+            }
+            Ok(result)
+        }).collect()
+    }
+
+    fn load_gltf(path: &Path) -> io::Result<Vec<TriangleMesh>> {
+        let (document, buffers, _) = gltf::import(path).map_err(invalid_data)?;
+
+        let mut meshes = Vec::new();
+        for mesh in document.meshes() {
+            for primitive in mesh.primitives() {
+                if primitive.mode() != gltf::mesh::Mode::Triangles {
+                    warn!("Skipping glTF primitive that isn't made of triangles");
+                    continue;
+                }
+                let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+                let positions: Vec<Vec3> = match reader.read_positions() {
+                    Some(positions) => positions.map(Vec3::from).collect(),
+                    None => continue,
+                };
+                let indices = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect(),
+                    None => (0..positions.len() as u32).collect(),
+                };
+                check_indices(&indices, positions.len())?;
+
+                let mut result = Self::new(positions, indices);
+                if let Some(normals) = reader.read_normals() {
+                    result.normals = normals.map(Vec3::from).collect();
+                    check_attribute("normals", result.normals.len(), result.positions.len())?;
+                }
+                if let Some(uvs) = reader.read_tex_coords(0) {
+                    result.uvs = uvs.into_f32().map(Vec2::from).collect();
+                    check_attribute("uvs", result.uvs.len(), result.positions.len())?;
+                }
+                meshes.push(result);
+            }
+        }
+        Ok(meshes)
+    }
+
+    /// Replaces the normals with smooth normals, weighted by the area of every triangle.
+    pub fn compute_normals(&mut self) {
+        let mut normals = vec![Vec3::ZERO; self.positions.len()];
+        for triangle in self.indices.chunks(3) {
+            let (a, b, c) = (triangle[0] as usize, triangle[1] as usize, triangle[2] as usize);
+            //Not normalized, so larger triangles count more
+            let normal = (self.positions[b] - self.positions[a]).cross(self.positions[c] - self.positions[a]);
+            normals[a] += normal;
+            normals[b] += normal;
+            normals[c] += normal;
+        }
+        self.normals = normals.into_iter().map(|n| n.normalize_or_zero()).collect();
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.positions.iter().fold(Aabb::empty(), |bounds, p| bounds.grow(*p))
+    }
+
+    pub(crate) fn triangle_bounds(&self) -> Vec<Aabb> {
+        self.indices.chunks(3).map(|triangle| {
+            triangle.iter().fold(Aabb::empty(), |bounds, i| bounds.grow(self.positions[*i as usize]))
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_indices() {
+        assert!(check_indices(&[0, 1, 2, 2, 1, 3], 4).is_ok());
+        assert!(check_indices(&[], 0).is_ok());
+    }
+
+    #[test]
+    fn incomplete_triangle() {
+        let error = check_indices(&[0, 1, 2, 0], 3).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn index_out_of_bounds() {
+        let error = check_indices(&[0, 1, 3], 3).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn attribute_count() {
+        assert!(check_attribute("normals", 3, 3).is_ok());
+        assert_eq!(check_attribute("uvs", 2, 3).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn smooth_normals() {
+        //Two triangles facing +z, sharing an edge
+        let mesh = TriangleMesh::new(vec![vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0), vec3(1.0, 1.0, 0.0)], vec![0, 1, 2, 2, 1, 3]);
+        assert_eq!(mesh.triangle_count(), 2);
+        for normal in &mesh.normals {
+            assert!((*normal - Vec3::Z).length() < 1e-6);
+        }
+    }
+}
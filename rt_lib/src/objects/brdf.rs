@@ -19,3 +19,19 @@ impl IsBRDF for Lambert {
 }".to_string();
     }
 }
+
+/// Lets light in and out through the surface, for materials with `subsurface`. Light is transmitted diffusely,
+/// the colour comes from the random walk below the surface.
+pub struct Subsurface;
+impl IsBRDF for Subsurface {
+    fn signature(&self) -> String {
+        "subsurface".to_string()
+    }
+    fn code(&self) -> String {
+        return
+"vec3 subsurface(Material mat, vec3 light, vec3 view, vec3 normal, vec3 tangent, vec3 binormal) {
+    //Directions through the surface are cosine sampled, so the cosine and the pdf cancel out
+    return vec3(1.0);
+}".to_string();
+    }
+}
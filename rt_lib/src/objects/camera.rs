@@ -7,6 +7,14 @@ use glux::gl_types::Texture;
 
 use glam::*;
 
+use crate::output::FloatImage;
+use crate::tonemap::ViewTransform;
+use crate::settings::{Aov, RenderSettings};
+use crate::filter::PixelFilter;
+use crate::queue::RayQueue;
+use super::lens::{Lens, ApertureShape, Focus, SENSOR_HEIGHT};
+use super::projection::Projection;
+
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
 struct Ray {
@@ -35,6 +43,7 @@ struct RawRayHit {
     pixel:       glux::gl_types::f32_f32_f32_f32,
     dir_pow:     glux::gl_types::f32_f32_f32_f32,
     power:       glux::gl_types::f32_f32_f32_f32,
+    uv:          glux::gl_types::f32_f32_f32_f32,
 }
 
 impl RawRayHit {
@@ -45,31 +54,146 @@ impl RawRayHit {
             pixel:       glux::gl_types::f32_f32_f32_f32::new(0.0,0.0,0.0,0.0),
             dir_pow:     glux::gl_types::f32_f32_f32_f32::new(0.0,0.0,0.0,1.0),
             power:       glux::gl_types::f32_f32_f32_f32::new(0.0,0.0,0.0,1.0),
+            uv:          glux::gl_types::f32_f32_f32_f32::new(0.0,0.0,0.0,0.0),
         }
     }
 }
 
 const RAY_CS_PATH: &str = "rt_lib/shaders/camera_ray_cs.glsl";
 
+/// 2D array textures holding one layer per AOV.
+//TODO: Implement texture arrays in glux, so we don't have to wrap it here
+pub struct AovTargets {
+    pub aovs: Vec<Aov>,
+    pub sample_buffer: u32, //Output buffer for current sample
+    pub render_buffer: u32, //Output buffer for final result
+}
+
+impl AovTargets {
+    fn new(aovs: &[Aov], resolution: (usize, usize)) -> Self {
+        Self {
+            aovs: aovs.to_vec(),
+            sample_buffer: Self::create_texture(aovs.len(), resolution),
+            render_buffer: Self::create_texture(aovs.len(), resolution),
+        }
+    }
+
+    fn create_texture(layers: usize, resolution: (usize, usize)) -> u32 {
+        let mut id = 0;
+        unsafe {
+            gl::GenTextures(1, &mut id);
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, id);
+            gl::TexStorage3D(gl::TEXTURE_2D_ARRAY, 1, gl::RGBA32F, resolution.0 as i32, resolution.1 as i32, layers as i32);
+            gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
+            gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, 0);
+            gl::ClearTexImage(id, 0, gl::RGBA, gl::FLOAT, std::ptr::null());
+        }
+        id
+    }
+}
+
+impl Drop for AovTargets {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteTextures(1, &self.sample_buffer);
+            gl::DeleteTextures(1, &self.render_buffer);
+        }
+    }
+}
+
+/// Physical camera body, as an alternative to setting the field of view directly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sensor {
+    /// Sensor width in millimeters. The height follows from the aspect ratio of the image.
+    pub width: f32,
+    /// Focal length of the lens in millimeters.
+    pub focal_length: f32,
+}
+
+/// Where a camera is and where it's looking.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CameraPose {
+    pub eye: Vec3,
+    pub look_at: Vec3,
+    pub up: Vec3,
+}
+
+/// Everything about a camera that affects the image.
+/// Samples rendered with a different state can't be accumulated together.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CameraState {
+    eye: Vec3,
+    look_at: Vec3,
+    up: Vec3,
+    shutter: (f32, f32),
+    motion: Option<CameraPose>,
+    fov: f32,
+    sensor: Option<Sensor>,
+    near: f32,
+    far: f32,
+    projection: Projection,
+    lens: Lens,
+    resolution: (usize, usize),
+}
+
+impl CameraPose {
+    /// Right, up and forward vectors, in world space.
+    pub fn basis(&self) -> (Vec3, Vec3, Vec3) {
+        let forward = (self.look_at - self.eye).normalize();
+        let right = forward.cross(self.up).normalize();
+        let up = right.cross(forward);
+        (right, up, forward)
+    }
+
+    /// The columns are right, up, backward and the eye position.
+    pub fn camera_to_world(&self) -> Mat4 {
+        let (right, up, forward) = self.basis();
+        Mat4::from_cols(right.extend(0.0), up.extend(0.0), (-forward).extend(0.0), self.eye.extend(1.0))
+    }
+}
+
 pub struct Camera {
     pub eye: Vec3,
     pub look_at: Vec3,
+    pub up: Vec3,
 
-    pub fov: f32, //In degrees
+    /// Time the shutter opens and closes, in seconds. Every sample picks a time in between, for motion blur.
+    pub shutter: (f32, f32),
+    /// Pose when the shutter closes. The camera moves linearly from its current pose towards this one.
+    pub motion: Option<CameraPose>,
+
+    pub fov: f32, //Vertical, in degrees. Ignored if a sensor is set.
+    pub sensor: Option<Sensor>,
+    /// Rays start this far from the camera, and stop after reaching far.
+    pub near: f32,
+    pub far: f32,
+    pub projection: Projection,
+
+    pub lens: Lens,
+    /// Distance to the focal plane. Updated by the raytracer when the lens uses autofocus.
+    pub focus_distance: f32,
 
     pub resolution: (usize, usize), //Output resolution
     pub sample_buffer: Texture,     //Output buffer for current sample
     pub render_buffer: Texture,     //Output buffer for final result
+    pub aov_targets: Option<AovTargets>,
 
     pub ray_ssbo: ShaderStorageBuffer,
     pub hit_ssbo: ShaderStorageBuffer,
-    pub jitter: f32_f32,
+    pub ray_queue: RayQueue, //Live rays in ray_ssbo
+    pub filter_ssbo: ShaderStorageBuffer,
+    filter: Option<PixelFilter>, //Filter currently in filter_ssbo
+    pub aperture_ssbo: ShaderStorageBuffer,
+    aperture: Option<ApertureShape>, //Shape currently in aperture_ssbo
 
     pub ray_program: ShaderProgram,
 }
 
 impl Camera {
     pub fn new(resolution: (usize, usize), dispatch_size: (u32, u32)) -> Self {
+        let dispatch_size = crate::dispatch::validate_dispatch_size(dispatch_size);
+
         let sample_texture = Texture::from_ptr((resolution.0 as i32, resolution.1 as i32), std::ptr::null(), gl::RGBA32F as i32, gl::RGBA);
         trace!("Sample texture constructed!");
 
@@ -91,29 +215,118 @@ impl Camera {
         let mut camera = Self {
             eye: vec3(0.0,0.0,-5.0),
             look_at: vec3(0.0,0.0,0.0),
+            up: vec3(0.0,1.0,0.0),
+
+            shutter: (0.0, 0.0),
+            motion: None,
 
             fov: 60.0,
+            sensor: None,
+            near: 0.02,
+            far: 1024.0,
+            projection: Projection::default(),
+
+            lens: Lens::default(),
+            focus_distance: 5.0,
 
             resolution: resolution,
             sample_buffer: sample_texture,
             render_buffer: output_texture,
+            aov_targets: None,
 
             ray_ssbo: ray_ssbo,
             hit_ssbo: hit_ssbo,
-            jitter: (0.0, 0.0).into(),
+            ray_queue: RayQueue::new(),
+            filter_ssbo: ShaderStorageBuffer::new(),
+            filter: None,
+            aperture_ssbo: ShaderStorageBuffer::new(),
+            aperture: None,
 
             ray_program: ray_program,
         };
 
-        camera.generate_rays(dispatch_size);
+        camera.generate_rays(dispatch_size, 0, &RenderSettings::default());
 
         camera
     }
 
+    /// Changes the output resolution, reallocating every per-pixel resource.
+    /// This throws away the current render, so the raytracer needs to start over.
+    pub fn set_resolution(&mut self, resolution: (usize, usize)) {
+        if resolution == self.resolution {
+            return;
+        }
+        self.resolution = resolution;
+
+        self.sample_buffer = Texture::from_ptr((resolution.0 as i32, resolution.1 as i32), std::ptr::null(), gl::RGBA32F as i32, gl::RGBA);
+        self.render_buffer = Texture::from_ptr((resolution.0 as i32, resolution.1 as i32), std::ptr::null(), gl::RGBA32F as i32, gl::RGBA);
+        self.clear_render_texture();
+
+        self.hit_ssbo.bind();
+        self.hit_ssbo.data(&vec![RawRayHit::empty(); resolution.0 * resolution.1][..], gl::DYNAMIC_COPY);
+        self.hit_ssbo.unbind();
+
+        //The ray buffer gets reallocated in generate_rays anyway
+
+        if let Some(targets) = self.aov_targets.take() {
+            self.aov_targets = Some(AovTargets::new(&targets.aovs, resolution));
+        }
+        debug!("Camera resolution changed to {:?}", resolution);
+    }
+
+    pub fn clear_render_texture(&self) {
+        unsafe {
+            gl::ClearTexImage(self.render_buffer.id, 0, gl::RGBA, gl::FLOAT, std::ptr::null());
+            if let Some(targets) = &self.aov_targets {
+                gl::ClearTexImage(targets.render_buffer, 0, gl::RGBA, gl::FLOAT, std::ptr::null());
+            }
+        }
+    }
+
     //TODO: Implement this in glux for textures, so we don't have to wrap it here
     pub fn clear_sample_texture(&self) {
         unsafe {
             gl::ClearTexImage(self.sample_buffer.id, 0, gl::RGBA, gl::UNSIGNED_BYTE, std::ptr::null());
+            if let Some(targets) = &self.aov_targets {
+                gl::ClearTexImage(targets.sample_buffer, 0, gl::RGBA, gl::FLOAT, std::ptr::null());
+            }
+        }
+    }
+
+    /// Makes sure there is a render target for every AOV.
+    /// Reallocating clears all AOVs, so only do this when the layers change.
+    pub fn set_aovs(&mut self, aovs: &[Aov]) {
+        let unchanged = match &self.aov_targets {
+            Some(targets) => targets.aovs == aovs,
+            None => aovs.is_empty(),
+        };
+        if unchanged {
+            return;
+        }
+
+        self.aov_targets = if aovs.is_empty() {
+            None
+        } else {
+            Some(AovTargets::new(aovs, self.resolution))
+        };
+        trace!("AOV textures constructed!");
+    }
+
+    /// Binds all AOV layers of the current sample as a single image2DArray.
+    pub fn bind_aov_sample_textures(&self, id: u32) {
+        if let Some(targets) = &self.aov_targets {
+            unsafe {
+                gl::BindImageTexture(id, targets.sample_buffer, 0, gl::TRUE, 0, gl::READ_WRITE, gl::RGBA32F);
+            }
+        }
+    }
+
+    /// Binds all final AOV layers as a single image2DArray.
+    pub fn bind_aov_final_textures(&self, id: u32) {
+        if let Some(targets) = &self.aov_targets {
+            unsafe {
+                gl::BindImageTexture(id, targets.render_buffer, 0, gl::TRUE, 0, gl::READ_WRITE, gl::RGBA32F);
+            }
         }
     }
 
@@ -129,49 +342,215 @@ impl Camera {
         }
     }
 
-    pub fn get_texture_as_pixels(&self) -> Vec<u8> {
+    /// Reads back the render buffer as 8 bit RGBA, with rows stored top to bottom.
+    /// The view transform should match the one used for display.
+    pub fn get_texture_as_pixels(&self, view_transform: &ViewTransform) -> Vec<u8> {
+        view_transform.to_ldr(&self.get_texture_as_float_image())
+    }
+
+    /// Reads back the full precision render buffer, without any clamping.
+    /// The result is RGBA, with rows stored top to bottom.
+    pub fn get_texture_as_float_image(&self) -> FloatImage {
         self.render_buffer.bind();
-        let mut pixels = vec![0u8; self.resolution.0 * self.resolution.1 * 4];
+        let mut pixels = vec![0f32; self.resolution.0 * self.resolution.1 * 4];
         unsafe {
-            gl::GetTexImage(gl::TEXTURE_2D, 0, gl::RGBA, gl::UNSIGNED_BYTE, pixels.as_mut_ptr() as *mut std::ffi::c_void);
+            gl::GetTexImage(gl::TEXTURE_2D, 0, gl::RGBA, gl::FLOAT, pixels.as_mut_ptr() as *mut std::ffi::c_void);
         }
         self.render_buffer.unbind();
 
-        return pixels;
+        let mut image = FloatImage::from_data(self.resolution.0, self.resolution.1, 4, pixels);
+        image.flip_vertical();
+        image
+    }
+
+    /// Reads back a single AOV, with rows stored top to bottom.
+    /// Returns `None` if the AOV is not being rendered.
+    pub fn get_aov_as_float_image(&self, aov: Aov) -> Option<FloatImage> {
+        let targets = self.aov_targets.as_ref()?;
+        let layer = targets.aovs.iter().position(|a| *a == aov)?;
+
+        let (width, height) = self.resolution;
+        let mut pixels = vec![0f32; width * height * 4];
+        unsafe {
+            gl::GetTextureSubImage(targets.render_buffer, 0, 0, 0, layer as i32, width as i32, height as i32, 1, gl::RGBA, gl::FLOAT, (pixels.len() * 4) as i32, pixels.as_mut_ptr() as *mut std::ffi::c_void);
+        }
+
+        //Drop the channels that don't mean anything for this AOV
+        let channels = aov.channels();
+        let data = pixels.chunks(4).flat_map(|p| p[..channels].to_vec()).collect();
+
+        let mut image = FloatImage::from_data(width, height, channels, data);
+        image.flip_vertical();
+        Some(image)
+    }
+
+    pub fn state(&self) -> CameraState {
+        CameraState {
+            eye: self.eye,
+            look_at: self.look_at,
+            up: self.up,
+            shutter: self.shutter,
+            motion: self.motion,
+            fov: self.fov,
+            sensor: self.sensor,
+            near: self.near,
+            far: self.far,
+            projection: self.projection,
+            lens: self.lens.clone(),
+            resolution: self.resolution,
+        }
+    }
+
+    pub fn aspect_ratio(&self) -> f32 {
+        self.resolution.0 as f32 / self.resolution.1 as f32
+    }
+
+    /// Vertical field of view in degrees, taking the sensor into account.
+    pub fn vertical_fov(&self) -> f32 {
+        match self.sensor {
+            Some(sensor) => {
+                let sensor_height = sensor.width / self.aspect_ratio();
+                2.0 * (sensor_height / (2.0 * sensor.focal_length)).atan().to_degrees()
+            },
+            None => self.fov,
+        }
+    }
+
+    /// Angle covered by half a pixel in radians, so how fast the footprint of a pixel grows with distance.
+    /// Parallel projections don't grow, and give 0.
+    pub fn pixel_cone(&self) -> f32 {
+        let height = self.resolution.1 as f32;
+        match self.projection {
+            Projection::Perspective => (self.vertical_fov().to_radians() * 0.5).tan() / height,
+            Projection::Orthographic { .. } => 0.0,
+            Projection::Equirectangular => std::f32::consts::PI / height * 0.5,
+            Projection::Fisheye { fov } => fov.to_radians() / height * 0.5,
+            //Every row of faces covers 90 degrees
+            Projection::Cubemap => std::f32::consts::FRAC_PI_2 / height,
+        }
+    }
+
+    /// Focal length in millimeters. Without a sensor, this is derived from the field of view on a full frame sensor.
+    pub fn focal_length(&self) -> f32 {
+        match self.sensor {
+            Some(sensor) => sensor.focal_length,
+            None => 0.5 * SENSOR_HEIGHT / (self.fov.to_radians() * 0.5).tan(),
+        }
+    }
+
+    pub fn pose(&self) -> CameraPose {
+        CameraPose {
+            eye: self.eye,
+            look_at: self.look_at,
+            up: self.up,
+        }
+    }
+
+    /// Right, up and forward vectors of the camera, in world space.
+    pub fn basis(&self) -> (Vec3, Vec3, Vec3) {
+        self.pose().basis()
     }
 
     pub fn get_projection_matrix(&self, aspect_ratio: f32) -> Mat4 {
-        Mat4::perspective_rh_gl(self.fov / 180.0 * std::f32::consts::PI, aspect_ratio, 0.02, 1024.0)
+        Mat4::perspective_rh_gl(self.vertical_fov().to_radians(), aspect_ratio, self.near, self.far)
     }
 
     pub fn get_view_matrix(&self) -> Mat4 {
-        Mat4::look_at_rh(self.eye, self.look_at, vec3(0.0,1.0,0.0))
+        Mat4::look_at_rh(self.eye, self.look_at, self.up)
     }
 
-    //TODO: Move ray generation to a shader
-    pub fn generate_rays(&mut self, dispatch_size: (u32, u32)) {
-        use rand::Rng;
-
-        let inv_proj_view = (self.get_projection_matrix(self.resolution.0 as f32 / self.resolution.1 as f32) * self.get_view_matrix()).inverse();
+    /// Inverse of the view matrix. The columns are right, up, backward and the eye position.
+    pub fn get_camera_to_world_matrix(&self) -> Mat4 {
+        self.pose().camera_to_world()
+    }
 
+    pub fn generate_rays(&mut self, dispatch_size: (u32, u32), sample_index: u32, settings: &RenderSettings) {
         let data = vec![Ray::default(); self.resolution.0 * self.resolution.1];
 
         self.ray_ssbo.bind();
         self.ray_ssbo.data(&data[..], gl::DYNAMIC_COPY);
         self.ray_ssbo.unbind();
 
-        let mut rng = rand::thread_rng();
-        self.jitter.d0 = rng.gen::<f32>();
-        self.jitter.d1 = rng.gen::<f32>();
+        if self.filter != Some(settings.pixel_filter) {
+            self.filter_ssbo.bind();
+            self.filter_ssbo.data(&settings.pixel_filter.sample_table()[..], gl::STATIC_DRAW);
+            self.filter_ssbo.unbind();
+            self.filter = Some(settings.pixel_filter);
+            debug!("Pixel filter changed to {:?}", settings.pixel_filter);
+        }
+
+        if self.aperture.as_ref() != Some(&self.lens.shape) {
+            self.aperture_ssbo.bind();
+            self.aperture_ssbo.data(&self.lens.shape.sample_table()[..], gl::STATIC_DRAW);
+            self.aperture_ssbo.unbind();
+            self.aperture = Some(self.lens.shape.clone());
+            debug!("Aperture shape changed!");
+        }
+
+        if let Focus::Distance(distance) = self.lens.focus {
+            self.focus_distance = distance;
+        }
+
+        let (blades, rotation, aperture_dims) = match &self.lens.shape {
+            ApertureShape::Circle => (0, 0.0, (0.0, 0.0)),
+            ApertureShape::Polygon { blades, rotation } => (*blades, *rotation, (0.0, 0.0)),
+            ApertureShape::Texture(mask) => (0, 0.0, (mask.width as f32, mask.height as f32)),
+        };
 
         self.ray_program.bind();
         self.ray_ssbo.bind_buffer_base(0);
+        self.filter_ssbo.bind_buffer_base(1);
+        self.aperture_ssbo.bind_buffer_base(2);
         self.ray_program.uniform("dims", f32_f32::from( (self.resolution.0 as f32, self.resolution.1 as f32) ));
-        self.ray_program.uniform("invprojview", inv_proj_view);
-        self.ray_program.uniform("jitter", self.jitter);
+        crate::set_uniform_u32("sample_index", sample_index);
+        crate::set_uniform_u32("seed", settings.seed);
+        self.ray_program.uniform("sampler_kind", settings.sampler as i32 as f32);
+        self.ray_program.uniform("camera_to_world_open", self.get_camera_to_world_matrix());
+        self.ray_program.uniform("camera_to_world_close", self.motion.unwrap_or(self.pose()).camera_to_world());
+        self.ray_program.uniform("shutter", f32_f32::from(self.shutter));
+        self.ray_program.uniform("tan_half_fov", (self.vertical_fov().to_radians() * 0.5).tan());
+        self.ray_program.uniform("near", self.near);
+        self.ray_program.uniform("far", self.far);
+        self.ray_program.uniform("projection", self.projection.id() as f32);
+        self.ray_program.uniform("projection_param", self.projection.parameter());
+        self.ray_program.uniform("aperture_radius", self.lens.aperture_radius(self.focal_length()));
+        self.ray_program.uniform("focus_distance", self.focus_distance);
+        self.ray_program.uniform("aperture_shape", self.lens.shape.id() as f32);
+        self.ray_program.uniform("aperture_blades", blades as f32);
+        self.ray_program.uniform("aperture_rotation", rotation.to_radians());
+        self.ray_program.uniform("aperture_dims", f32_f32::from(aperture_dims));
+        crate::dispatch::dispatch_compute(self.resolution, dispatch_size);
+        self.ray_program.unbind();
+
+        //Every pixel starts out with a live ray
+        self.ray_queue.reset((self.resolution.0 * self.resolution.1) as u32, dispatch_size);
+    }
+
+    /// Focuses on the first hit of the center pixel. Needs the hits of camera rays in `hit_ssbo`.
+    /// Keeps the current focus distance if the center pixel doesn't hit anything.
+    /// Returns whether the focus distance changed.
+    //TODO: Implement buffer readback in glux, so we don't have to wrap it here
+    pub fn autofocus_from_hits(&mut self) -> bool {
+        let center = self.resolution.0 / 2 + self.resolution.1 / 2 * self.resolution.0;
+        //Only pos_id is needed, which is the first vec4 of the hit
+        let mut pos_id = [0f32; 4];
+        self.hit_ssbo.bind();
         unsafe {
-            gl::DispatchCompute(self.resolution.0 as u32 / dispatch_size.0, self.resolution.1 as u32 / dispatch_size.1, 1);
+            gl::GetBufferSubData(gl::SHADER_STORAGE_BUFFER, (center * std::mem::size_of::<RawRayHit>()) as isize, std::mem::size_of_val(&pos_id) as isize, pos_id.as_mut_ptr() as *mut std::ffi::c_void);
         }
-        self.ray_program.unbind();
+        self.hit_ssbo.unbind();
+
+        //Object 0 is the sky
+        if pos_id[3] == 0.0 {
+            return false;
+        }
+        let (_, _, forward) = self.basis();
+        let distance = (vec3(pos_id[0], pos_id[1], pos_id[2]) - self.eye).dot(forward);
+        if (distance - self.focus_distance).abs() <= self.focus_distance * 0.001 {
+            return false;
+        }
+        trace!("Autofocus distance: {}", distance);
+        self.focus_distance = distance;
+        true
     }
 }
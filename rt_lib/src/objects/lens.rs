@@ -0,0 +1,128 @@
+use crate::output::FloatImage;
+
+/// Height of the virtual sensor in millimeters, used to turn the field of view into a focal length
+/// when the camera doesn't have a sensor set.
+/// 24mm matches a full frame 35mm camera.
+pub const SENSOR_HEIGHT: f32 = 24.0;
+
+/// Size of the lens opening. Scene units are assumed to be meters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aperture {
+    /// Everything is in focus.
+    Pinhole,
+    /// Radius of the lens in scene units.
+    Radius(f32),
+    /// Photographic f-number. The radius follows from the focal length of the camera.
+    FStop(f32),
+}
+
+#[derive(Clone, Debug)]
+pub enum ApertureShape {
+    Circle,
+    /// Regular polygon, like the blades of a real diaphragm. Rotation is in degrees.
+    Polygon {
+        blades: u32,
+        rotation: f32,
+    },
+    /// Any shape, given as a grayscale mask. Only the first channel is used.
+    /// The mask is stretched to fit the aperture.
+    Texture(FloatImage),
+}
+
+impl PartialEq for ApertureShape {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ApertureShape::Circle, ApertureShape::Circle) => true,
+            (ApertureShape::Polygon { blades: a, rotation: ra }, ApertureShape::Polygon { blades: b, rotation: rb }) => a == b && ra == rb,
+            (ApertureShape::Texture(a), ApertureShape::Texture(b)) => a.width == b.width && a.height == b.height && a.channels == b.channels && a.data == b.data,
+            _ => false,
+        }
+    }
+}
+
+impl ApertureShape {
+    /// Id of the shape in `camera_ray_cs.glsl`.
+    pub(crate) fn id(&self) -> i32 {
+        match self {
+            ApertureShape::Circle => 0,
+            ApertureShape::Polygon { .. } => 1,
+            ApertureShape::Texture(_) => 2,
+        }
+    }
+
+    /// Builds the table used to importance sample a texture aperture.
+    /// It starts with the CDF over rows (height + 1 entries),
+    /// followed by the CDF over every row (width + 1 entries each).
+    /// Other shapes are sampled analytically and get a dummy table.
+    pub(crate) fn sample_table(&self) -> Vec<f32> {
+        let mask = match self {
+            ApertureShape::Texture(mask) => mask,
+            _ => return vec![0.0, 1.0],
+        };
+        let (width, height) = (mask.width, mask.height);
+
+        let mut rows = Vec::with_capacity((width + 1) * height);
+        let mut marginal = Vec::with_capacity(height + 1);
+        marginal.push(0.0);
+        for y in 0..height {
+            let start = rows.len();
+            let mut sum = 0.0;
+            rows.push(0.0);
+            for x in 0..width {
+                sum += mask.pixel(x, y)[0].max(0.0);
+                rows.push(sum);
+            }
+            if sum > 0.0 {
+                for value in &mut rows[start..] {
+                    *value /= sum;
+                }
+            }
+            marginal.push(marginal[y] + sum);
+        }
+
+        let total = marginal[height];
+        assert!(total > 0.0, "Aperture texture is completely black!");
+        for value in &mut marginal {
+            *value /= total;
+        }
+
+        marginal.extend(rows);
+        marginal
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Focus {
+    /// Distance from the camera to the focal plane.
+    Distance(f32),
+    /// Focus on whatever is behind the center pixel.
+    Auto,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lens {
+    pub aperture: Aperture,
+    pub shape: ApertureShape,
+    pub focus: Focus,
+}
+
+impl Default for Lens {
+    fn default() -> Self {
+        Self {
+            aperture: Aperture::Pinhole,
+            shape: ApertureShape::Circle,
+            focus: Focus::Auto,
+        }
+    }
+}
+
+impl Lens {
+    /// Radius of the lens in scene units, for a camera with the given focal length in millimeters.
+    pub fn aperture_radius(&self, focal_length: f32) -> f32 {
+        match self.aperture {
+            Aperture::Pinhole => 0.0,
+            Aperture::Radius(radius) => radius,
+            Aperture::FStop(f_number) => focal_length / (2.0 * f_number) / 1000.0,
+        }
+    }
+}
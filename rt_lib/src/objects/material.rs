@@ -0,0 +1,88 @@
+use glam::*;
+
+use crate::animation::MaterialAnimation;
+use super::Medium;
+
+/// Surface parameters, passed to the BRDF. Matches `Material` in `brdf/mat.glsl`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Material {
+    pub albedo: Vec3,
+    pub roughness: f32,
+    pub metallic: f32,
+    /// Light given off by the surface. Anything above 0 makes this a light source.
+    pub emission: Vec3,
+    /// How far light travels below the surface before it scatters, per colour channel.
+    /// Anything above 0 lets light in, where it walks around until it leaves the surface again, see `subsurface_medium`.
+    /// The BRDF decides how light goes in and out, see `Subsurface`. Only works on distance fields and brick maps, not on meshes.
+    pub subsurface: Vec3,
+    /// Signature of a BRDF added with `Raytracer::add_brdf`. `None` uses Lambert.
+    pub brdf: Option<String>,
+    pub animation: Option<MaterialAnimation>,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            albedo: Vec3::ONE,
+            roughness: 1.0,
+            metallic: 0.0,
+            emission: Vec3::ZERO,
+            subsurface: Vec3::ZERO,
+            brdf: None,
+            animation: None,
+        }
+    }
+}
+
+impl Material {
+    pub fn diffuse(albedo: Vec3) -> Self {
+        Self {
+            albedo: albedo,
+            ..Default::default()
+        }
+    }
+
+    pub fn emissive(emission: Vec3) -> Self {
+        Self {
+            emission: emission,
+            ..Default::default()
+        }
+    }
+
+    /// Skin, wax, marble and the like. `albedo` is the colour the surface ends up with,
+    /// `radius` how far light gets below the surface per colour channel.
+    /// Needs `Subsurface` to be added to the raytracer.
+    pub fn subsurface(albedo: Vec3, radius: Vec3) -> Self {
+        Self {
+            albedo: albedo,
+            subsurface: radius,
+            brdf: Some("subsurface".to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// The medium below the surface, for the random walk. `None` if light doesn't get in.
+    /// The walk has to end up with the albedo after many scattering events, so the albedo of a single event is higher,
+    /// see "Practical and Controllable Subsurface Scattering for Production Path Tracing" (Chiang et al. 2016).
+    pub fn subsurface_medium(&self) -> Option<Medium> {
+        if self.subsurface.max_element() <= 0.0 {
+            return None;
+        }
+        let a = self.albedo.clamp(Vec3::ZERO, Vec3::ONE);
+        let root = (Vec3::splat(9.59217) + 41.6808 * a + 17.7126 * a * a).powf(0.5);
+        let single_albedo = Vec3::ONE - (Vec3::splat(4.09712) + 4.20863 * a - root).powf(2.0);
+        let extinction = Vec3::ONE / self.subsurface.max(Vec3::splat(1e-4));
+        Some(Medium::homogeneous(extinction * (Vec3::ONE - single_albedo), extinction * single_albedo))
+    }
+
+    /// The material at a point in time, in seconds.
+    pub fn at(&self, time: f32) -> Material {
+        match &self.animation {
+            Some(animation) => animation.evaluate(time, self),
+            None => Material {
+                animation: None,
+                ..self.clone()
+            },
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use glam::*;
+
+/// Where a medium is, and how dense it is there. The values match the defines in `medium.glsl`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Density {
+    /// The same everywhere, which is a homogeneous medium.
+    Constant(f32),
+    /// Fractal value noise between 0 and `scale`, for smoke and clouds.
+    Noise {
+        scale: f32,
+        /// Noise cells per unit of distance.
+        frequency: f32,
+        octaves: u32,
+    },
+    /// Index into the density grids of the scene, multiplied by `scale`.
+    Grid {
+        grid: u32,
+        scale: f32,
+    },
+}
+
+/// Participating media, like fog, smoke or wax. Matches `RawMedium` in `medium.glsl`.
+/// The coefficients are per unit of distance at a density of 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Medium {
+    pub absorption: Vec3,
+    pub scattering: Vec3,
+    /// Henyey-Greenstein asymmetry between -1 and 1. Positive values scatter forward, 0 scatters evenly.
+    pub anisotropy: f32,
+    pub density: Density,
+}
+
+impl Default for Medium {
+    fn default() -> Self {
+        Self {
+            absorption: Vec3::ZERO,
+            scattering: Vec3::ONE,
+            anisotropy: 0.0,
+            density: Density::Constant(1.0),
+        }
+    }
+}
+
+impl Medium {
+    pub fn homogeneous(absorption: Vec3, scattering: Vec3) -> Self {
+        Self {
+            absorption: absorption,
+            scattering: scattering,
+            ..Default::default()
+        }
+    }
+
+    /// White fog that scatters slightly forward, like haze.
+    pub fn fog(density: f32) -> Self {
+        Self {
+            anisotropy: 0.3,
+            density: Density::Constant(density),
+            ..Default::default()
+        }
+    }
+}
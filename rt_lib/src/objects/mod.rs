@@ -1,5 +1,17 @@
 mod camera;
-pub use camera::Camera;
+pub use camera::{Camera, CameraPose, CameraState, Sensor};
+
+mod lens;
+pub use lens::{Lens, Aperture, ApertureShape, Focus};
+
+mod projection;
+pub use projection::Projection;
 
 mod brdf;
-pub use brdf::{IsBRDF, Lambert};
+pub use brdf::{IsBRDF, Lambert, Subsurface};
+
+mod material;
+pub use material::Material;
+
+mod medium;
+pub use medium::{Medium, Density};
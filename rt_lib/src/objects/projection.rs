@@ -0,0 +1,50 @@
+/// How camera rays are laid out over the image.
+/// Every projection looks down the camera's forward axis, with the camera's up vector pointing up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    /// Regular pinhole camera, using the camera's field of view. The only projection that supports depth of field.
+    Perspective,
+    /// Parallel rays. `height` is the visible height in scene units.
+    Orthographic {
+        height: f32,
+    },
+    /// Full 360 x 180 degree panorama in latitude/longitude layout. The image should be twice as wide as it is high.
+    Equirectangular,
+    /// Equidistant fisheye. `fov` is the angle covered by the image circle in degrees, and can go past 180.
+    /// The circle fits the height of the image, pixels outside of it stay black.
+    Fisheye {
+        fov: f32,
+    },
+    /// All six faces of a cube in a 3x2 layout, each covering 90 degrees.
+    /// The top row holds right, left and up, the bottom row down, back and front.
+    /// The image should be 3:2.
+    Cubemap,
+}
+
+impl Default for Projection {
+    fn default() -> Self {
+        Projection::Perspective
+    }
+}
+
+impl Projection {
+    /// Id of the projection in `camera_ray_cs.glsl`.
+    pub(crate) fn id(&self) -> i32 {
+        match self {
+            Projection::Perspective => 0,
+            Projection::Orthographic { .. } => 1,
+            Projection::Equirectangular => 2,
+            Projection::Fisheye { .. } => 3,
+            Projection::Cubemap => 4,
+        }
+    }
+
+    /// Single parameter of the projection, as passed to the shader.
+    pub(crate) fn parameter(&self) -> f32 {
+        match *self {
+            Projection::Orthographic { height } => height,
+            Projection::Fisheye { fov } => fov.to_radians(),
+            _ => 0.0,
+        }
+    }
+}
@@ -0,0 +1,344 @@
+//! Minimal OpenEXR writer.
+//! Writes single part, uncompressed scanline images, with any number of layers.
+//! Layers other than the beauty layer get their channels prefixed with the layer name,
+//! like `albedo.R`, which is what compositing packages expect for multi-layer EXRs.
+
+use std::path::Path;
+
+use super::FloatImage;
+
+const MAGIC: u32 = 20000630;
+const VERSION: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelType {
+    /// 16 bit float, half the size on disk. Plenty for colour data.
+    Half,
+    /// 32 bit float. Use this for depth and position data.
+    Float,
+}
+
+impl PixelType {
+    fn id(&self) -> i32 {
+        match self {
+            PixelType::Half => 1,
+            PixelType::Float => 2,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            PixelType::Half => 2,
+            PixelType::Float => 4,
+        }
+    }
+}
+
+pub struct Layer<'a> {
+    /// Empty for the beauty layer.
+    pub name: &'a str,
+    pub image: &'a FloatImage,
+    pub pixel_type: PixelType,
+    /// Names for each channel in the image. If `None`, they are picked based on the channel count.
+    pub channel_names: Option<&'a [&'a str]>,
+}
+
+impl<'a> Layer<'a> {
+    pub fn new(name: &'a str, image: &'a FloatImage, pixel_type: PixelType) -> Self {
+        Self {
+            name: name,
+            image: image,
+            pixel_type: pixel_type,
+            channel_names: None,
+        }
+    }
+
+    pub fn with_channel_names(mut self, names: &'a [&'a str]) -> Self {
+        assert_eq!(names.len(), self.image.channels, "Every channel needs a name!");
+        self.channel_names = Some(names);
+        self
+    }
+
+    fn channel_name(&self, channel: usize) -> String {
+        let base = match self.channel_names {
+            Some(names) => names[channel].to_string(),
+            None => match self.image.channels {
+                1 => "Y".to_string(),
+                2 => ["X", "Y"][channel].to_string(),
+                3 | 4 => ["R", "G", "B", "A"][channel].to_string(),
+                _ => format!("C{}", channel),
+            }
+        };
+        if self.name.is_empty() {
+            base
+        } else {
+            format!("{}.{}", self.name, base)
+        }
+    }
+}
+
+struct Channel<'a> {
+    name: String,
+    layer: &'a Layer<'a>,
+    index: usize,
+}
+
+/// Encodes all layers into a single EXR file in memory.
+/// All layers must have the same dimensions.
+pub fn encode(layers: &[Layer]) -> Vec<u8> {
+    assert!(!layers.is_empty(), "Can't write an EXR without any layers!");
+    let width = layers[0].image.width;
+    let height = layers[0].image.height;
+    for layer in layers {
+        assert!(layer.image.width == width && layer.image.height == height, "All layers must have the same resolution!");
+    }
+
+    //EXR requires the channel list to be sorted by name
+    let mut channels: Vec<Channel> = layers.iter().flat_map(|layer| {
+        (0..layer.image.channels).map(move |i| Channel {
+            name: layer.channel_name(i),
+            layer: layer,
+            index: i,
+        })
+    }).collect();
+    channels.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
+
+    let mut out = Vec::new();
+    write_u32(&mut out, MAGIC);
+    write_u32(&mut out, VERSION);
+
+    //Header
+    let mut chlist = Vec::new();
+    for channel in &channels {
+        write_str(&mut chlist, &channel.name);
+        write_i32(&mut chlist, channel.layer.pixel_type.id());
+        chlist.extend_from_slice(&[0, 0, 0, 0]); //pLinear + reserved
+        write_i32(&mut chlist, 1); //xSampling
+        write_i32(&mut chlist, 1); //ySampling
+    }
+    chlist.push(0);
+    write_attribute(&mut out, "channels", "chlist", &chlist);
+
+    write_attribute(&mut out, "compression", "compression", &[0]); //NO_COMPRESSION
+
+    let mut window = Vec::new();
+    write_i32(&mut window, 0);
+    write_i32(&mut window, 0);
+    write_i32(&mut window, width as i32 - 1);
+    write_i32(&mut window, height as i32 - 1);
+    write_attribute(&mut out, "dataWindow", "box2i", &window);
+    write_attribute(&mut out, "displayWindow", "box2i", &window);
+
+    write_attribute(&mut out, "lineOrder", "lineOrder", &[0]); //INCREASING_Y
+    write_attribute(&mut out, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+    let mut center = Vec::new();
+    center.extend_from_slice(&0f32.to_le_bytes());
+    center.extend_from_slice(&0f32.to_le_bytes());
+    write_attribute(&mut out, "screenWindowCenter", "v2f", &center);
+    write_attribute(&mut out, "screenWindowWidth", "float", &1f32.to_le_bytes());
+    out.push(0); //End of header
+
+    //Offset table. Uncompressed files store a single scanline per chunk.
+    let line_size: usize = channels.iter().map(|c| c.layer.pixel_type.size() * width).sum();
+    let chunk_size = 8 + line_size;
+    let table_end = out.len() + height * 8;
+    for y in 0..height {
+        write_u64(&mut out, (table_end + y * chunk_size) as u64);
+    }
+
+    //Scanlines
+    for y in 0..height {
+        write_i32(&mut out, y as i32);
+        write_i32(&mut out, line_size as i32);
+        for channel in &channels {
+            let image = channel.layer.image;
+            for x in 0..width {
+                let value = image.data[(x + y * width) * image.channels + channel.index];
+                match channel.layer.pixel_type {
+                    PixelType::Half => out.extend_from_slice(&f32_to_half(value).to_le_bytes()),
+                    PixelType::Float => out.extend_from_slice(&value.to_le_bytes()),
+                }
+            }
+        }
+    }
+
+    out
+}
+
+pub fn write<P: AsRef<Path>>(path: P, layers: &[Layer]) -> std::io::Result<()> {
+    std::fs::write(path, encode(layers))
+}
+
+fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_i32(out: &mut Vec<u8>, value: i32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(value.as_bytes());
+    out.push(0);
+}
+
+fn write_attribute(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    write_str(out, name);
+    write_str(out, kind);
+    write_i32(out, value.len() as i32);
+    out.extend_from_slice(value);
+}
+
+/// Converts an f32 to an IEEE 754 half float, rounding to nearest even.
+pub fn f32_to_half(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7fffff;
+
+    if exp == 0xff {
+        //Inf or NaN
+        let nan = if mantissa != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+
+    let half_exp = exp - 127 + 15;
+    if half_exp >= 0x1f {
+        //Too large, becomes infinity
+        return sign | 0x7c00;
+    }
+
+    if half_exp <= 0 {
+        //Subnormal or zero
+        if half_exp < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x800000;
+        let shift = (14 - half_exp) as u32;
+        let mut half_mantissa = mantissa >> shift;
+        let remainder = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        if remainder > halfway || (remainder == halfway && (half_mantissa & 1) != 0) {
+            half_mantissa += 1;
+        }
+        return sign | half_mantissa as u16;
+    }
+
+    let mut half = ((half_exp as u32) << 10) | (mantissa >> 13);
+    let remainder = mantissa & 0x1fff;
+    if remainder > 0x1000 || (remainder == 0x1000 && (half & 1) != 0) {
+        //Rounding can carry into the exponent, which correctly rounds up to infinity
+        half += 1;
+    }
+    sign | half as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    fn read_str(data: &[u8], pos: &mut usize) -> String {
+        let end = *pos + data[*pos..].iter().position(|&b| b == 0).unwrap();
+        let s = String::from_utf8(data[*pos..end].to_vec()).unwrap();
+        *pos = end + 1;
+        s
+    }
+
+    fn read_i32(data: &[u8], pos: &mut usize) -> i32 {
+        let value = i32::from_le_bytes([data[*pos], data[*pos + 1], data[*pos + 2], data[*pos + 3]]);
+        *pos += 4;
+        value
+    }
+
+    /// Reads the header attributes, and returns them with the position of the offset table.
+    fn read_header(data: &[u8]) -> (Vec<(String, String, Vec<u8>)>, usize) {
+        let mut pos = 8;
+        let mut attributes = Vec::new();
+        loop {
+            let name = read_str(data, &mut pos);
+            if name.is_empty() {
+                return (attributes, pos);
+            }
+            let kind = read_str(data, &mut pos);
+            let size = read_i32(data, &mut pos) as usize;
+            attributes.push((name, kind, data[pos..pos + size].to_vec()));
+            pos += size;
+        }
+    }
+
+    #[test]
+    fn magic_and_version() {
+        let image = FloatImage::new(2, 2, 3);
+        let data = encode(&[Layer::new("", &image, PixelType::Half)]);
+        assert_eq!(&data[..4], &[0x76, 0x2f, 0x31, 0x01]);
+        assert_eq!(&data[4..8], &[2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn header_attributes() {
+        let image = FloatImage::new(3, 2, 3);
+        let albedo = FloatImage::new(3, 2, 3);
+        let data = encode(&[Layer::new("", &image, PixelType::Half), Layer::new("albedo", &albedo, PixelType::Float)]);
+        let (attributes, _) = read_header(&data);
+        let names: Vec<&str> = attributes.iter().map(|(name, _, _)| name.as_str()).collect();
+        for required in &["channels", "compression", "dataWindow", "displayWindow", "lineOrder", "pixelAspectRatio", "screenWindowCenter", "screenWindowWidth"] {
+            assert!(names.contains(required), "Missing attribute {}", required);
+        }
+
+        //Channels are sorted by name, with their pixel type
+        let chlist = &attributes.iter().find(|(name, _, _)| name == "channels").unwrap().2;
+        let mut pos = 0;
+        let mut channels = Vec::new();
+        loop {
+            let name = read_str(chlist, &mut pos);
+            if name.is_empty() {
+                break;
+            }
+            let pixel_type = read_i32(chlist, &mut pos);
+            pos += 12;
+            channels.push((name, pixel_type));
+        }
+        let expected = [("B", 1), ("G", 1), ("R", 1), ("albedo.B", 2), ("albedo.G", 2), ("albedo.R", 2)];
+        assert_eq!(channels, expected.iter().map(|&(name, t)| (name.to_string(), t)).collect::<Vec<_>>());
+
+        let window = &attributes.iter().find(|(name, _, _)| name == "dataWindow").unwrap().2;
+        assert_eq!(window, &[0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn channel_data() {
+        let image = FloatImage::from_data(2, 2, 1, vec![1.0, 2.0, 3.0, 4.0]);
+        let data = encode(&[Layer::new("depth", &image, PixelType::Float)]);
+        let (_, mut pos) = read_header(&data);
+
+        let first = u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap()) as usize;
+        let second = u64::from_le_bytes(data[pos + 8..pos + 16].try_into().unwrap()) as usize;
+        assert_eq!(first, pos + 16);
+        assert_eq!(second, first + 8 + 2 * 4);
+
+        pos = second;
+        assert_eq!(read_i32(&data, &mut pos), 1);
+        assert_eq!(read_i32(&data, &mut pos), 8);
+        assert_eq!(&data[pos..pos + 8], &[3f32.to_le_bytes(), 4f32.to_le_bytes()].concat()[..]);
+        assert_eq!(data.len(), pos + 8);
+    }
+
+    #[test]
+    fn half_conversion() {
+        assert_eq!(f32_to_half(0.0), 0x0000);
+        assert_eq!(f32_to_half(-0.0), 0x8000);
+        assert_eq!(f32_to_half(1.0), 0x3c00);
+        assert_eq!(f32_to_half(-2.0), 0xc000);
+        assert_eq!(f32_to_half(65504.0), 0x7bff);
+        assert_eq!(f32_to_half(1e6), 0x7c00);
+        assert_eq!(f32_to_half(f32::NAN) & 0x7e00, 0x7e00);
+        //Smallest subnormal
+        assert_eq!(f32_to_half(5.960464e-8), 0x0001);
+    }
+}
@@ -0,0 +1,184 @@
+//! Radiance .hdr (RGBE) writer.
+//! Scanlines are written with the "new" run length encoding whenever the width allows it.
+
+use std::path::Path;
+
+use super::FloatImage;
+
+/// Encodes the first 3 channels of an image as a Radiance HDR file in memory.
+pub fn encode(image: &FloatImage) -> Vec<u8> {
+    assert!(image.channels >= 3, "HDR files need at least 3 channels!");
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n");
+    out.extend_from_slice(format!("-Y {} +X {}\n", image.height, image.width).as_bytes());
+
+    let mut scanline = vec![[0u8; 4]; image.width];
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let p = image.pixel(x, y);
+            scanline[x] = float_to_rgbe(p[0], p[1], p[2]);
+        }
+        write_scanline(&mut out, &scanline);
+    }
+
+    out
+}
+
+pub fn write<P: AsRef<Path>>(path: P, image: &FloatImage) -> std::io::Result<()> {
+    std::fs::write(path, encode(image))
+}
+
+/// Converts a linear colour to the shared exponent RGBE format.
+pub fn float_to_rgbe(r: f32, g: f32, b: f32) -> [u8; 4] {
+    let v = r.max(g).max(b);
+    //Also catches NaN, which would otherwise end up as garbage
+    if !(v > 1e-32) {
+        return [0, 0, 0, 0];
+    }
+    if v.is_infinite() {
+        return [255, 255, 255, 255];
+    }
+    let (mantissa, exponent) = frexp(v);
+    let scale = mantissa * 256.0 / v;
+    [
+        (r.max(0.0) * scale) as u8,
+        (g.max(0.0) * scale) as u8,
+        (b.max(0.0) * scale) as u8,
+        (exponent + 128) as u8,
+    ]
+}
+
+/// Inverse of `float_to_rgbe`.
+pub fn rgbe_to_float(rgbe: [u8; 4]) -> [f32; 3] {
+    if rgbe[3] == 0 {
+        return [0.0; 3];
+    }
+    let scale = 2f32.powi(rgbe[3] as i32 - (128 + 8));
+    [
+        (rgbe[0] as f32 + 0.5) * scale,
+        (rgbe[1] as f32 + 0.5) * scale,
+        (rgbe[2] as f32 + 0.5) * scale,
+    ]
+}
+
+/// Splits a positive, finite float into a mantissa in [0.5, 1) and a power of 2.
+fn frexp(v: f32) -> (f32, i32) {
+    let mut exponent = v.log2().floor() as i32 + 1;
+    let mut mantissa = v / 2f32.powi(exponent);
+    //Correct for rounding errors in log2
+    if mantissa >= 1.0 {
+        mantissa *= 0.5;
+        exponent += 1;
+    } else if mantissa < 0.5 {
+        mantissa *= 2.0;
+        exponent -= 1;
+    }
+    (mantissa, exponent)
+}
+
+fn write_scanline(out: &mut Vec<u8>, scanline: &[[u8; 4]]) {
+    let width = scanline.len();
+    //The new RLE format only supports these widths, anything else is written flat
+    if width < 8 || width > 0x7fff {
+        for pixel in scanline {
+            out.extend_from_slice(pixel);
+        }
+        return;
+    }
+
+    out.extend_from_slice(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8]);
+    let mut component = vec![0u8; width];
+    for c in 0..4 {
+        for (i, pixel) in scanline.iter().enumerate() {
+            component[i] = pixel[c];
+        }
+        write_rle(out, &component);
+    }
+}
+
+/// Run length encodes a single component of a scanline.
+/// Runs are stored as `128 + length` followed by the value,
+/// everything else is stored as `length` followed by the raw values.
+fn write_rle(out: &mut Vec<u8>, data: &[u8]) {
+    const MIN_RUN: usize = 4;
+
+    let mut i = 0;
+    while i < data.len() {
+        //Find the next run long enough to be worth encoding
+        let mut run_start = i;
+        let mut run_len = 0;
+        while run_start < data.len() {
+            run_len = 1;
+            while run_len < 127 && run_start + run_len < data.len() && data[run_start + run_len] == data[run_start] {
+                run_len += 1;
+            }
+            if run_len >= MIN_RUN {
+                break;
+            }
+            run_start += run_len;
+        }
+        if run_len < MIN_RUN {
+            run_start = data.len();
+        }
+
+        //Everything before the run gets written raw
+        while i < run_start {
+            let count = (run_start - i).min(128);
+            out.push(count as u8);
+            out.extend_from_slice(&data[i..i + count]);
+            i += count;
+        }
+
+        if run_start < data.len() {
+            out.push((128 + run_len) as u8);
+            out.push(data[run_start]);
+            i = run_start + run_len;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header() {
+        let image = FloatImage::new(2, 3, 3);
+        let data = encode(&image);
+        let header = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 3 +X 2\n";
+        assert_eq!(&data[..header.len()], &header[..]);
+        //Too narrow for RLE, so every pixel is stored flat
+        assert_eq!(data.len(), header.len() + 2 * 3 * 4);
+    }
+
+    #[test]
+    fn known_pixel() {
+        assert_eq!(float_to_rgbe(1.0, 0.5, 0.25), [128, 64, 32, 129]);
+        assert_eq!(float_to_rgbe(0.0, 0.0, 0.0), [0, 0, 0, 0]);
+        assert_eq!(float_to_rgbe(f32::NAN, 0.0, 0.0), [0, 0, 0, 0]);
+
+        let image = FloatImage::from_data(1, 1, 3, vec![1.0, 0.5, 0.25]);
+        let data = encode(&image);
+        assert_eq!(&data[data.len() - 4..], &[128, 64, 32, 129]);
+    }
+
+    #[test]
+    fn round_trip() {
+        for &(r, g, b) in &[(1.0, 0.5, 0.25), (100.0, 3.0, 0.01), (0.001, 0.002, 0.003)] {
+            let [r2, g2, b2] = rgbe_to_float(float_to_rgbe(r, g, b));
+            //8 bits of mantissa, relative to the brightest channel
+            let tolerance = r.max(g).max(b) / 128.0;
+            assert!((r - r2).abs() <= tolerance && (g - g2).abs() <= tolerance && (b - b2).abs() <= tolerance);
+        }
+    }
+
+    #[test]
+    fn run_length_encoding() {
+        let image = FloatImage::from_data(8, 1, 3, [1.0, 0.5, 0.25].repeat(8));
+        let data = encode(&image);
+        let header_len = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 8\n".len();
+        //Every component is a single run of 8
+        assert_eq!(&data[header_len..], &[2, 2, 0, 8, 136, 128, 136, 64, 136, 32, 136, 129]);
+    }
+}
@@ -0,0 +1,76 @@
+pub mod exr;
+pub mod hdr;
+
+/// A CPU side float image.
+/// Channels are interleaved per pixel, and rows are stored top to bottom.
+#[derive(Clone, Debug)]
+pub struct FloatImage {
+    pub width: usize,
+    pub height: usize,
+    pub channels: usize,
+    pub data: Vec<f32>,
+}
+
+impl FloatImage {
+    pub fn new(width: usize, height: usize, channels: usize) -> Self {
+        Self {
+            width: width,
+            height: height,
+            channels: channels,
+            data: vec![0.0; width * height * channels],
+        }
+    }
+
+    pub fn from_data(width: usize, height: usize, channels: usize, data: Vec<f32>) -> Self {
+        assert_eq!(data.len(), width * height * channels, "Image data does not match the image dimensions!");
+        Self {
+            width: width,
+            height: height,
+            channels: channels,
+            data: data,
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> &[f32] {
+        let i = (x + y * self.width) * self.channels;
+        &self.data[i..i + self.channels]
+    }
+
+    pub fn pixel_mut(&mut self, x: usize, y: usize) -> &mut [f32] {
+        let i = (x + y * self.width) * self.channels;
+        &mut self.data[i..i + self.channels]
+    }
+
+    /// Flips the rows of the image. OpenGL returns textures bottom to top,
+    /// so anything read back from the GPU needs this before being written out.
+    pub fn flip_vertical(&mut self) {
+        let row = self.width * self.channels;
+        for y in 0..self.height / 2 {
+            let (top, bottom) = self.data.split_at_mut((self.height - 1 - y) * row);
+            top[y * row..(y + 1) * row].swap_with_slice(&mut bottom[..row]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flip_vertical_swaps_rows() {
+        //3 rows, so the middle one has to stay where it is
+        let mut image = FloatImage::from_data(2, 3, 1, vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+        image.flip_vertical();
+        assert_eq!(image.data, vec![4.0, 5.0, 2.0, 3.0, 0.0, 1.0]);
+        image.flip_vertical();
+        assert_eq!(image.data, vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn pixel_indexing() {
+        let mut image = FloatImage::new(3, 2, 2);
+        image.pixel_mut(2, 1).copy_from_slice(&[7.0, 8.0]);
+        assert_eq!(image.pixel(2, 1), &[7.0, 8.0]);
+        assert_eq!(&image.data[10..12], &[7.0, 8.0]);
+    }
+}
@@ -0,0 +1,62 @@
+//! Queue of live rays, so passes after ray generation only run for rays that are still going.
+//! Rays that leave the scene or lose all their power are dropped when the next wave spawns,
+//! and the remaining ones are packed together at the front of the ray buffer.
+//! The GPU keeps track of the count itself, and sizes the next dispatch with it,
+//! so nothing has to be read back.
+
+const COUNT_OFFSET: usize = 0;
+/// Offset of the indirect dispatch arguments. See `queue.glsl` for the full layout.
+const DISPATCH_OFFSET: usize = 8;
+const QUEUE_SIZE: usize = 20;
+
+//TODO: Implement indirect dispatch buffers in glux, so we don't have to wrap it here
+pub struct RayQueue {
+    pub id: u32,
+}
+
+impl RayQueue {
+    pub fn new() -> Self {
+        let mut id = 0;
+        unsafe {
+            gl::CreateBuffers(1, &mut id);
+            gl::NamedBufferData(id, QUEUE_SIZE as isize, std::ptr::null(), gl::DYNAMIC_COPY);
+        }
+        Self {
+            id: id,
+        }
+    }
+
+    /// Starts a new wave of `count` rays, packed at the start of the ray buffer.
+    pub fn reset(&self, count: u32, dispatch_size: (u32, u32)) {
+        let group_size = dispatch_size.0 * dispatch_size.1;
+        let data: [u32; 5] = [count, 0, (count + group_size - 1) / group_size, 1, 1];
+        unsafe {
+            gl::NamedBufferSubData(self.id, COUNT_OFFSET as isize, QUEUE_SIZE as isize, data.as_ptr() as *const std::ffi::c_void);
+        }
+    }
+
+    pub fn bind_buffer_base(&self, binding: u32) {
+        unsafe {
+            gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, binding, self.id);
+        }
+    }
+
+    /// Dispatches the currently bound compute program once for every ray in the queue.
+    /// The program needs a 1D workgroup with `DISPATCH_SIZE_X * DISPATCH_SIZE_Y` invocations.
+    pub fn dispatch(&self) {
+        unsafe {
+            gl::MemoryBarrier(gl::COMMAND_BARRIER_BIT);
+            gl::BindBuffer(gl::DISPATCH_INDIRECT_BUFFER, self.id);
+            gl::DispatchComputeIndirect(DISPATCH_OFFSET as isize);
+            gl::BindBuffer(gl::DISPATCH_INDIRECT_BUFFER, 0);
+        }
+    }
+}
+
+impl Drop for RayQueue {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.id);
+        }
+    }
+}
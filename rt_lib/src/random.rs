@@ -0,0 +1,74 @@
+//! Stateless random number generation, based on the PCG hash from
+//! "Hash Functions for GPU Rendering" (Jarzynski & Olano, 2020).
+//! This is the CPU side twin of `shaders/random.glsl`, the tests check both produce the same numbers.
+
+pub fn pcg_hash(v: u32) -> u32 {
+    let state = v.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+/// Seed unique to a pixel, sample and bounce.
+pub fn rng_seed(pixel_index: u32, sample_index: u32, bounce: u32, seed: u32) -> u32 {
+    pcg_hash(pixel_index ^ pcg_hash(sample_index ^ pcg_hash(bounce ^ pcg_hash(seed))))
+}
+
+pub fn random_u32(state: &mut u32) -> u32 {
+    *state = pcg_hash(*state);
+    *state
+}
+
+/// Uniform float in [0, 1)
+pub fn random_f32(state: &mut u32) -> f32 {
+    (random_u32(state) >> 8) as f32 * (1.0 / 16777216.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glsl_interpreter::{Program, Value};
+
+    //From the paper's reference code:
+    //  uint state = input * 747796405u + 2891336453u;
+    //  uint word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    //  return (word >> 22u) ^ word;
+    #[test]
+    fn pcg_hash_known_answers() {
+        assert_eq!(pcg_hash(0), 129708002);
+        assert_eq!(pcg_hash(1), 2831084092);
+        assert_eq!(pcg_hash(2), 2055130248);
+        assert_eq!(pcg_hash(42), 1223963391);
+        assert_eq!(pcg_hash(0xdeadbeef), 1730779506);
+        assert_eq!(pcg_hash(u32::MAX), 3861530882);
+    }
+
+    #[test]
+    fn random_f32_range() {
+        //The largest hash must still stay below 1
+        assert!((u32::MAX >> 8) as f32 * (1.0 / 16777216.0) < 1.0);
+
+        let mut state = rng_seed(0, 0, 0, 0);
+        let mut sum = 0.0;
+        for _ in 0..100000 {
+            let x = random_f32(&mut state);
+            assert!((0.0..1.0).contains(&x), "{} is out of [0, 1)", x);
+            sum += x as f64;
+        }
+        assert!((sum / 100000.0 - 0.5).abs() < 0.01, "Mean is {}", sum / 100000.0);
+    }
+
+    #[test]
+    fn matches_shader() {
+        let shader = Program::parse(include_str!("../shaders/random.glsl"));
+        for &v in &[0, 1, 2, 42, 0xdeadbeef, u32::MAX, 1 << 31, 123456789] {
+            assert_eq!(shader.call("pcgHash", &[Value::Uint(v)]).uint(), pcg_hash(v), "pcgHash({})", v);
+
+            let seed = shader.call("rngSeed", &[Value::Uint(v), Value::Uint(7), Value::Uint(3), Value::Uint(v ^ 99)]).uint();
+            assert_eq!(seed, rng_seed(v, 7, 3, v ^ 99));
+
+            let mut state = seed;
+            let expected = shader.call("randomFloat", &[Value::Uint(state)]).float();
+            assert_eq!(random_f32(&mut state), expected);
+        }
+    }
+}
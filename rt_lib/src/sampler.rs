@@ -0,0 +1,256 @@
+//! Sample sequences for pixel jitter, lens and bounce directions.
+//! This is the CPU side twin of `shaders/sampler.glsl`, so both produce the same points.
+
+use crate::random::{pcg_hash, rng_seed, random_f32};
+
+/// The values match the defines in `sampler.glsl`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SamplerKind {
+    /// Independent random numbers from the PCG hash.
+    Random = 0,
+    /// Owen scrambled Sobol, scrambled separately for every pixel and dimension.
+    Sobol = 1,
+    /// Owen scrambled Halton, scrambled separately for every pixel and dimension.
+    Halton = 2,
+    /// R2 rank-1 lattice over samples, dithered over the screen with a blue noise like mask.
+    BlueNoise = 3,
+}
+
+impl Default for SamplerKind {
+    fn default() -> Self {
+        SamplerKind::Sobol
+    }
+}
+
+/// Every 2D sample a path needs has its own dimension.
+pub const SAMPLE_DIM_PIXEL: u32 = 0;
+pub const SAMPLE_DIM_LENS: u32 = 1;
+pub const SAMPLE_DIM_TIME: u32 = 2;
+/// Bounce `n` uses `SAMPLE_DIM_BOUNCE + n * SAMPLE_DIMS_PER_BOUNCE` for its direction,
+/// the dimension after that for Russian roulette, and the one after that for tracking through media.
+pub const SAMPLE_DIM_BOUNCE: u32 = 3;
+pub const SAMPLE_DIMS_PER_BOUNCE: u32 = 3;
+
+impl SamplerKind {
+    /// Returns a 2D point in [0, 1)² for the given pixel, sample and dimension.
+    pub fn sample_2d(&self, pixel: (u32, u32), width: u32, sample_index: u32, dimension: u32, seed: u32) -> [f32; 2] {
+        let pixel_index = pixel.0 + pixel.1 * width;
+        match self {
+            SamplerKind::Random => {
+                let mut rng = rng_seed(pixel_index, sample_index, dimension, seed);
+                [random_f32(&mut rng), random_f32(&mut rng)]
+            },
+            SamplerKind::Sobol => sample_sobol(pixel_index, sample_index, dimension, seed),
+            SamplerKind::Halton => sample_halton(pixel_index, sample_index, dimension, seed),
+            SamplerKind::BlueNoise => sample_blue_noise(pixel, sample_index, dimension, seed),
+        }
+    }
+}
+
+fn u32_to_f32(x: u32) -> f32 {
+    (x >> 8) as f32 * (1.0 / 16777216.0)
+}
+
+/// Owen scrambling, from "Practical Hash-based Owen Scrambling" (Burley, 2020)
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+/// Second dimension of the Sobol sequence. The first is just the bit reversed index.
+fn sobol_dim_1(mut index: u32) -> u32 {
+    let mut result = 0;
+    let mut v = 1 << 31;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        v ^= v >> 1;
+        index >>= 1;
+    }
+    result
+}
+
+fn sample_sobol(pixel_index: u32, sample_index: u32, dimension: u32, seed: u32) -> [f32; 2] {
+    let scramble = rng_seed(pixel_index, 0, dimension, seed);
+    let index = nested_uniform_scramble(sample_index, scramble);
+    let x = nested_uniform_scramble(index.reverse_bits(), pcg_hash(scramble ^ 0x1));
+    let y = nested_uniform_scramble(sobol_dim_1(index), pcg_hash(scramble ^ 0x2));
+    [u32_to_f32(x), u32_to_f32(y)]
+}
+
+const HALTON_PRIMES: [u32; 16] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53];
+
+/// Random permutation of a digit, picked by a hash.
+/// The permuted digit is the rank of its key among the keys of all digits.
+fn permute_digit(digit: u32, base: u32, hash: u32) -> u32 {
+    let key = pcg_hash(hash ^ digit);
+    (0..base).filter(|&v| {
+        let other = pcg_hash(hash ^ v);
+        other < key || (other == key && v < digit)
+    }).count() as u32
+}
+
+/// Radical inverse with every digit permuted based on the digits before it.
+/// This is a nested (Owen style) scramble, which breaks up the correlation between higher bases.
+fn scrambled_radical_inverse(mut index: u32, base: u32, seed: u32) -> f32 {
+    let inv_base = 1.0 / base as f32;
+    let mut f = inv_base;
+    let mut result = 0.0;
+    let mut prefix = seed;
+    //Scrambling turns trailing zeros into digits, so keep going until the float precision runs out
+    while f > 1.0 / 16777216.0 {
+        let digit = index % base;
+        result += f * permute_digit(digit, base, prefix) as f32;
+        prefix = pcg_hash(prefix ^ digit);
+        index /= base;
+        f *= inv_base;
+    }
+    result
+}
+
+fn fract(x: f32) -> f32 {
+    x - x.floor()
+}
+
+fn sample_halton(pixel_index: u32, sample_index: u32, dimension: u32, seed: u32) -> [f32; 2] {
+    let mut scramble = rng_seed(pixel_index, 0, dimension, seed);
+    //Past the prime table, higher bases correlate badly anyway
+    if dimension >= 8 {
+        return [random_f32(&mut scramble), random_f32(&mut scramble)];
+    }
+    let d = dimension as usize * 2;
+    [
+        scrambled_radical_inverse(sample_index, HALTON_PRIMES[d], pcg_hash(scramble ^ 0x1)),
+        scrambled_radical_inverse(sample_index, HALTON_PRIMES[d + 1], pcg_hash(scramble ^ 0x2)),
+    ]
+}
+
+/// R2 sequence ("The Unreasonable Effectiveness of Quasirandom Sequences", Roberts 2018)
+const R2_ALPHA: [f32; 2] = [0.7548776662466927, 0.5698402909980532];
+/// Same, as 0.32 fixed point, so the sequence stays exact for any sample count
+const R2_ALPHA_FIXED: [u32; 2] = [3242174889, 2447445414];
+
+fn sample_blue_noise(pixel: (u32, u32), sample_index: u32, dimension: u32, seed: u32) -> [f32; 2] {
+    let p = [pixel.0 as f32, pixel.1 as f32];
+    let r2_dither = fract(p[0] * R2_ALPHA[0] + p[1] * R2_ALPHA[1]);
+    let ign = fract(52.9829189 * fract(p[0] * 0.06711056 + p[1] * 0.00583715));
+    //Same offset for every pixel, so the mask keeps its spectrum
+    let mut rng = rng_seed(0, 0, dimension, seed);
+    let offset = [random_f32(&mut rng), random_f32(&mut rng)];
+    [
+        fract(offset[0] + r2_dither + u32_to_f32(R2_ALPHA_FIXED[0].wrapping_mul(sample_index))),
+        fract(offset[1] + ign + u32_to_f32(R2_ALPHA_FIXED[1].wrapping_mul(sample_index))),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 64;
+    const PIXELS: [(u32, u32); 4] = [(0, 0), (1, 0), (17, 5), (63, 63)];
+
+    fn points(kind: SamplerKind, pixel: (u32, u32), dimension: u32, count: u32) -> Vec<[f32; 2]> {
+        (0..count).map(|i| kind.sample_2d(pixel, WIDTH, i, dimension, 7)).collect()
+    }
+
+    /// Whether every cell of a `cells_x` by `cells_y` grid has exactly one point in it.
+    fn one_per_cell(points: &[[f32; 2]], cells_x: u32, cells_y: u32) -> bool {
+        let mut counts = vec![0; (cells_x * cells_y) as usize];
+        for p in points {
+            let x = (p[0] * cells_x as f32) as u32;
+            let y = (p[1] * cells_y as f32) as u32;
+            counts[(x + y * cells_x) as usize] += 1;
+        }
+        counts.iter().all(|&c| c == 1)
+    }
+
+    /// Star discrepancy, the worst difference between the fraction of points in a box anchored at the origin
+    /// and the area of that box. Only the boxes touching points matter, open and closed.
+    fn star_discrepancy(points: &[[f32; 2]]) -> f32 {
+        let n = points.len() as f32;
+        let mut xs: Vec<f32> = points.iter().map(|p| p[0]).chain(std::iter::once(1.0)).collect();
+        let mut ys: Vec<f32> = points.iter().map(|p| p[1]).chain(std::iter::once(1.0)).collect();
+        xs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        ys.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let mut worst = 0.0f32;
+        for &x in &xs {
+            for &y in &ys {
+                let open = points.iter().filter(|p| p[0] < x && p[1] < y).count() as f32 / n;
+                let closed = points.iter().filter(|p| p[0] <= x && p[1] <= y).count() as f32 / n;
+                worst = worst.max(x * y - open).max(closed - x * y);
+            }
+        }
+        worst
+    }
+
+    #[test]
+    fn sobol_elementary_intervals() {
+        for &pixel in &PIXELS {
+            for &dimension in &[SAMPLE_DIM_PIXEL, SAMPLE_DIM_BOUNCE + 4] {
+                for k in 0..=8 {
+                    let points = points(SamplerKind::Sobol, pixel, dimension, 1 << k);
+                    for a in 0..=k {
+                        assert!(one_per_cell(&points, 1 << a, 1 << (k - a)), "Pixel {:?}, dimension {}: not a net in {}x{}", pixel, dimension, 1 << a, 1 << (k - a));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn halton_strata() {
+        for &pixel in &PIXELS {
+            //2 and 3 for the pixel, 5 and 7 for the lens
+            for &(a, b) in &[(1, 1), (3, 2), (4, 1), (2, 3)] {
+                let points = points(SamplerKind::Halton, pixel, SAMPLE_DIM_PIXEL, 2u32.pow(a) * 3u32.pow(b));
+                assert!(one_per_cell(&points, 2u32.pow(a), 3u32.pow(b)), "Pixel {:?}: not stratified in {}x{}", pixel, 2u32.pow(a), 3u32.pow(b));
+            }
+            for &(a, b) in &[(1, 1), (2, 1), (1, 2)] {
+                let points = points(SamplerKind::Halton, pixel, SAMPLE_DIM_LENS, 5u32.pow(a) * 7u32.pow(b));
+                assert!(one_per_cell(&points, 5u32.pow(a), 7u32.pow(b)), "Pixel {:?}: not stratified in {}x{}", pixel, 5u32.pow(a), 7u32.pow(b));
+            }
+        }
+    }
+
+    #[test]
+    fn scrambling_differs_per_pixel() {
+        let first = points(SamplerKind::Sobol, PIXELS[0], SAMPLE_DIM_PIXEL, 16);
+        let second = points(SamplerKind::Sobol, PIXELS[1], SAMPLE_DIM_PIXEL, 16);
+        assert_ne!(first, second);
+        let first = points(SamplerKind::Halton, PIXELS[0], SAMPLE_DIM_PIXEL, 16);
+        let second = points(SamplerKind::Halton, PIXELS[1], SAMPLE_DIM_PIXEL, 16);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn lower_discrepancy_than_random() {
+        let average = |kind: SamplerKind| -> f32 {
+            PIXELS.iter().map(|&pixel| star_discrepancy(&points(kind, pixel, SAMPLE_DIM_PIXEL, 128))).sum::<f32>() / PIXELS.len() as f32
+        };
+        let random = average(SamplerKind::Random);
+        for &kind in &[SamplerKind::Sobol, SamplerKind::Halton, SamplerKind::BlueNoise] {
+            let discrepancy = average(kind);
+            assert!(discrepancy < random * 0.5, "{:?} has a star discrepancy of {}, random has {}", kind, discrepancy, random);
+        }
+    }
+
+    #[test]
+    fn samples_in_unit_square() {
+        for &kind in &[SamplerKind::Random, SamplerKind::Sobol, SamplerKind::Halton, SamplerKind::BlueNoise] {
+            for p in points(kind, (3, 4), SAMPLE_DIM_BOUNCE, 256) {
+                assert!(p[0] >= 0.0 && p[0] < 1.0 && p[1] >= 0.0 && p[1] < 1.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,794 @@
+//! The scene being rendered. Objects live on the CPU, and get uploaded to the GPU
+//! whenever they change. `shaders/scene.glsl` turns them into the distance field,
+//! `shaders/mesh.glsl` traces the triangle meshes and `shaders/brickmap.glsl` samples the baked distance fields.
+//! `shaders/volume.glsl` tracks rays through the media inside of volume objects, and the fog.
+
+use std::collections::HashMap;
+
+use glam::*;
+
+use glux::gl_types::ShaderStorageBuffer;
+
+use crate::animation::TransformAnimation;
+use crate::brickmap::{BrickMap, BrickAtlas};
+use crate::bvh::{Aabb, Bvh, BvhNode};
+use crate::mesh::TriangleMesh;
+use crate::objects::{Material, Medium};
+use crate::volume::{DensityGrid, MediumTable};
+
+/// Primitive distance fields, and meshes. The values match the defines in `scene.glsl`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+    Sphere {
+        radius: f32,
+    },
+    Box {
+        half_extents: Vec3,
+    },
+    /// Infinite plane through the origin, facing +Y. Everything below it counts as inside.
+    Plane,
+    /// Index into the meshes of the scene. Traced as triangles, rather than marched as a distance field.
+    Mesh {
+        mesh: u32,
+    },
+    /// Index into the brick maps of the scene, a baked distance field.
+    BrickMap {
+        map: u32,
+    },
+}
+
+impl Shape {
+    fn id(&self) -> f32 {
+        match self {
+            Shape::Sphere { .. } => 0.0,
+            Shape::Box { .. } => 1.0,
+            Shape::Plane => 2.0,
+            Shape::Mesh { .. } => 3.0,
+            Shape::BrickMap { .. } => 4.0,
+        }
+    }
+
+    /// Meshes get the root of their BVH filled in when uploading, brick maps where they start in the grid buffer.
+    fn params(&self) -> [f32; 4] {
+        match *self {
+            Shape::Sphere { radius } => [radius, 0.0, 0.0, 0.0],
+            Shape::Box { half_extents } => [half_extents.x, half_extents.y, half_extents.z, 0.0],
+            Shape::Plane | Shape::Mesh { .. } | Shape::BrickMap { .. } => [0.0; 4],
+        }
+    }
+
+    /// Bounding box in object space. `None` for infinite shapes.
+    fn local_bounds(&self, mesh_bounds: &[Aabb], brick_map_bounds: &[Aabb]) -> Option<Aabb> {
+        match *self {
+            Shape::Sphere { radius } => Some(Aabb::new(Vec3::splat(-radius), Vec3::splat(radius))),
+            Shape::Box { half_extents } => Some(Aabb::new(-half_extents, half_extents)),
+            Shape::Plane => None,
+            Shape::Mesh { mesh } => Some(*mesh_bounds.get(mesh as usize).expect("Object uses a mesh that isn't in the scene!")),
+            Shape::BrickMap { map } => Some(*brick_map_bounds.get(map as usize).expect("Object uses a brick map that isn't in the scene!")),
+        }
+    }
+}
+
+/// Most domain operators an object can have, see `MAX_DOMAIN_OPS` in `scene.glsl`.
+pub const MAX_DOMAIN_OPS: usize = 4;
+
+/// Folds space before the shape gets evaluated, in object space, to make many copies of it for the price of one.
+/// Every copy has to stay within its own cell, or the distance field breaks where the cells meet.
+/// The values match the defines in `scene.glsl`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Domain {
+    /// Repeats forever along every axis with a non-zero spacing. Makes the object infinite.
+    Repeat {
+        spacing: Vec3,
+    },
+    /// `count` copies along every axis with a non-zero spacing, centered on the origin.
+    RepeatLimited {
+        spacing: Vec3,
+        count: UVec3,
+    },
+    /// Mirrors the positive side of every selected axis onto the negative side.
+    Mirror {
+        axes: BVec3,
+    },
+    /// `count` copies around the Y axis.
+    Polar {
+        count: u32,
+    },
+}
+
+impl Domain {
+    /// The operator, followed by its parameters.
+    fn data(&self) -> [[f32; 4]; 2] {
+        match *self {
+            Domain::Repeat { spacing } => [[0.0, spacing.x, spacing.y, spacing.z], [0.0; 4]],
+            Domain::RepeatLimited { spacing, count } => {
+                let count = count.max(UVec3::ONE).as_vec3();
+                [[1.0, spacing.x, spacing.y, spacing.z], count.extend(0.0).to_array()]
+            },
+            Domain::Mirror { axes } => {
+                let axes = Vec3::select(axes, Vec3::ONE, Vec3::ZERO);
+                [[2.0, axes.x, axes.y, axes.z], [0.0; 4]]
+            },
+            Domain::Polar { count } => [[3.0, count.max(1) as f32, 0.0, 0.0], [0.0; 4]],
+        }
+    }
+
+    /// Bounds of all copies of something with the given bounds. `None` if there are infinitely many.
+    fn bounds(&self, local: Aabb) -> Option<Aabb> {
+        match *self {
+            Domain::Repeat { spacing } if spacing != Vec3::ZERO => None,
+            Domain::Repeat { .. } => Some(local),
+            Domain::RepeatLimited { spacing, count } => {
+                let offset = (spacing * (count.max(UVec3::ONE) - UVec3::ONE).as_vec3() * 0.5).abs();
+                Some(Aabb::new(local.min - offset, local.max + offset))
+            },
+            Domain::Mirror { axes } => {
+                let extent = local.min.abs().max(local.max.abs());
+                Some(Aabb::new(Vec3::select(axes, -extent, local.min), Vec3::select(axes, extent, local.max)))
+            },
+            Domain::Polar { .. } => {
+                let radius = vec2(local.min.x.abs().max(local.max.x.abs()), local.min.z.abs().max(local.max.z.abs())).length();
+                Some(Aabb::new(vec3(-radius, local.min.y, -radius), vec3(radius, local.max.y, radius)))
+            },
+        }
+    }
+}
+
+/// Smallest scale an object is rendered at. Anything smaller, including 0 and negative scales, is clamped to this.
+pub const MIN_SCALE: f32 = 1e-4;
+
+/// Only uniform scaling is supported, as anything else breaks the distance field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: f32,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: Vec3::ZERO,
+            rotation: Quat::IDENTITY,
+            scale: 1.0,
+        }
+    }
+}
+
+impl Transform {
+    pub fn from_translation(translation: Vec3) -> Self {
+        Self {
+            translation: translation,
+            ..Default::default()
+        }
+    }
+
+    /// World space bounds of a box in object space.
+    fn bounds(&self, local: Aabb) -> Aabb {
+        let rotation = Mat3::from_quat(self.rotation);
+        let half_extents = (local.max - local.min) * 0.5;
+        let center = self.translation + rotation * (local.centroid() * self.scale);
+        let extents = (rotation.x_axis.abs() * half_extents.x + rotation.y_axis.abs() * half_extents.y + rotation.z_axis.abs() * half_extents.z) * self.scale;
+        Aabb::new(center - extents, center + extents)
+    }
+}
+
+/// Constant motion, used for motion blur.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Motion {
+    /// Units per second.
+    pub velocity: Vec3,
+    /// Rotation axis scaled by the speed in radians per second.
+    pub angular_velocity: Vec3,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Object {
+    pub shape: Shape,
+    /// Applied in order to positions in object space, so the last one is closest to the shape.
+    /// At most `MAX_DOMAIN_OPS`, and ignored for meshes.
+    pub domain: Vec<Domain>,
+    /// Index into the materials of the scene.
+    pub material: u32,
+    /// Transform at time 0, or whenever the animation has no keyframes.
+    pub transform: Transform,
+    pub animation: Option<TransformAnimation>,
+    /// Applied on top of the animation.
+    pub motion: Option<Motion>,
+    /// Index into the media of the scene. Turns the object into a volume: its inside is filled with the medium,
+    /// and its surface is invisible, so the material isn't used. Only distance fields can be volumes.
+    pub medium: Option<u32>,
+}
+
+impl Object {
+    pub fn new(shape: Shape, material: u32, transform: Transform) -> Self {
+        Self {
+            shape: shape,
+            domain: Vec::new(),
+            material: material,
+            transform: transform,
+            animation: None,
+            motion: None,
+            medium: None,
+        }
+    }
+
+    /// Transform at a point in time, in seconds.
+    pub fn transform_at(&self, time: f32) -> Transform {
+        let transform = match &self.animation {
+            Some(animation) => animation.evaluate(time, &self.transform),
+            None => self.transform,
+        };
+        //The shader divides by the scale, so an animation passing through 0 would break the distance field
+        let scale = transform.scale.max(MIN_SCALE);
+        match self.motion {
+            Some(motion) => Transform {
+                translation: transform.translation + motion.velocity * time,
+                rotation: (Quat::from_scaled_axis(motion.angular_velocity * time) * transform.rotation).normalize(),
+                scale: scale,
+            },
+            None => Transform {
+                scale: scale,
+                ..transform
+            },
+        }
+    }
+}
+
+impl Object {
+    fn is_volume(&self) -> bool {
+        self.medium.is_some() && !matches!(self.shape, Shape::Mesh { .. })
+    }
+
+    /// World space bounds over the shutter interval. `None` for infinite shapes.
+    fn bounds(&self, shutter: (f32, f32), mesh_bounds: &[Aabb], brick_map_bounds: &[Aabb]) -> Option<Aabb> {
+        let mut local = self.shape.local_bounds(mesh_bounds, brick_map_bounds)?;
+        if !matches!(self.shape, Shape::Mesh { .. }) {
+            //The first operator is the furthest from the shape, so it spreads the copies of everything after it
+            for domain in self.domain.iter().rev() {
+                local = domain.bounds(local)?;
+            }
+        }
+        let open = self.transform_at(shutter.0);
+        let close = self.transform_at(shutter.1);
+        //Moving in a straight line stays within both ends, but rotating in between can stick out of them.
+        //Rotating around the origin always stays within the bounding sphere.
+        let local = if open.rotation != close.rotation {
+            let radius = local.min.abs().max(local.max.abs()).length();
+            Aabb::new(Vec3::splat(-radius), Vec3::splat(radius))
+        } else {
+            local
+        };
+        Some(open.bounds(local).union(&close.bounds(local)))
+    }
+}
+
+/// Layout of a single shape definition on the GPU, see `ShapeDef` in `scene.glsl`.
+/// Objects with the same shape and domain share one.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuShape {
+    shape: [f32; 4],
+    params: [f32; 4],
+    domain: [[f32; 4]; MAX_DOMAIN_OPS * 2],
+}
+
+impl GpuShape {
+    fn new(shape: &Shape, params: [f32; 4], domain: &[Domain]) -> Self {
+        assert!(domain.len() <= MAX_DOMAIN_OPS, "Object has more than {} domain operators!", MAX_DOMAIN_OPS);
+        let mut data = [[0.0; 4]; MAX_DOMAIN_OPS * 2];
+        for (i, domain) in domain.iter().enumerate() {
+            let [a, b] = domain.data();
+            data[i * 2] = a;
+            data[i * 2 + 1] = b;
+        }
+        Self {
+            shape: [shape.id(), domain.len() as f32, 0.0, 0.0],
+            params: params,
+            domain: data,
+        }
+    }
+
+    /// Floats can't be hashed, their bits can.
+    fn key(&self) -> Vec<u32> {
+        self.shape.iter().chain(&self.params).chain(self.domain.iter().flatten()).map(|f| f.to_bits()).collect()
+    }
+}
+
+/// Layout of a single object on the GPU, see `SceneObject` in `scene.glsl`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuObject {
+    shape: [f32; 4],
+    translation: [[f32; 4]; 2],
+    rotation: [[f32; 4]; 2],
+}
+
+impl GpuObject {
+    fn new(object: &Object, id: u32, definition: usize, shutter: (f32, f32)) -> Self {
+        let open = object.transform_at(shutter.0);
+        let close = object.transform_at(shutter.1);
+        //The shader blends the rotations linearly, which needs them in the same hemisphere
+        let close_rotation = if open.rotation.dot(close.rotation) < 0.0 { -close.rotation } else { close.rotation };
+        //Volumes don't have a surface, so they pass their medium instead
+        let material = if object.is_volume() { object.medium.unwrap() } else { object.material };
+
+        Self {
+            shape: [definition as f32, material as f32, id as f32, 0.0],
+            translation: [open.translation.extend(open.scale).to_array(), close.translation.extend(close.scale).to_array()],
+            rotation: [open.rotation.to_array(), close_rotation.to_array()],
+        }
+    }
+}
+
+/// Layout of a single BVH node on the GPU, see `BvhNode` in `scene.glsl`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuBvhNode {
+    min_first: [f32; 4],
+    max_count: [f32; 4],
+}
+
+impl GpuBvhNode {
+    /// Interior nodes get their children moved by `child_offset`, leaves their first primitive by `leaf_offset`.
+    fn new(node: &BvhNode, child_offset: usize, leaf_offset: usize) -> Self {
+        let first = node.first as usize + if node.is_leaf() { leaf_offset } else { child_offset };
+        Self {
+            min_first: node.bounds.min.extend(first as f32).to_array(),
+            max_count: node.bounds.max.extend(node.count as f32).to_array(),
+        }
+    }
+}
+
+/// Layout of a single mesh vertex on the GPU, see `MeshVertex` in `mesh.glsl`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuVertex {
+    pos_u: [f32; 4],
+    normal_v: [f32; 4],
+}
+
+/// Layout of a single material on the GPU, see `RawMaterial` in `brdf/materials.glsl`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuMaterial {
+    albedo_roughness: [f32; 4],
+    emission_metallic: [f32; 4],
+    subsurface_medium: [f32; 4],
+    brdf: [u32; 4],
+}
+
+impl GpuMaterial {
+    /// `medium` is where the medium for the subsurface walk is in the medium table, if there is one.
+    fn new(material: &Material, medium: Option<usize>) -> Self {
+        let brdf = material.brdf.as_ref().map_or(0, |signature| crate::string_to_id(signature.clone()));
+        Self {
+            albedo_roughness: material.albedo.extend(material.roughness).to_array(),
+            emission_metallic: material.emission.extend(material.metallic).to_array(),
+            subsurface_medium: material.subsurface.extend(medium.map_or(-1.0, |medium| medium as f32)).to_array(),
+            brdf: [brdf, 0, 0, 0],
+        }
+    }
+}
+
+pub struct Scene {
+    /// Object IDs are the index in this list + 1, as 0 is the sky.
+    pub objects: Vec<Object>,
+    /// Indexed by the material of an object.
+    pub materials: Vec<Material>,
+    /// Indexed by the medium of an object, and `fog`.
+    /// At most `volume::MAX_MEDIA`, together with one for every material with `subsurface`.
+    pub media: Vec<Medium>,
+    /// Index into the media, fills the whole scene.
+    pub fog: Option<u32>,
+    /// Indexed by `Density::Grid`. Density grids can only be added, see `add_density_grid`.
+    density_grids: Vec<DensityGrid>,
+    /// Indexed by `Shape::Mesh`. Meshes can only be added, see `add_mesh`.
+    meshes: Vec<TriangleMesh>,
+    mesh_bounds: Vec<Aabb>,
+    /// Indexed by `Shape::BrickMap`. Brick maps can only be added, see `add_brick_map`.
+    brick_maps: Vec<BrickMap>,
+    brick_map_bounds: Vec<Aabb>,
+
+    /// Distance field objects in BVH order, followed by the infinite objects, followed by mesh objects in BVH order,
+    /// followed by the volume objects.
+    pub ssbo: ShaderStorageBuffer,
+    /// Shape definitions the objects refer to, every distinct shape and domain is in here once.
+    pub shape_ssbo: ShaderStorageBuffer,
+    /// The BVH over distance field objects, the BVH over mesh objects, the BVH of every mesh
+    /// and a box around every volume object, in that order.
+    pub bvh_ssbo: ShaderStorageBuffer,
+    pub material_ssbo: ShaderStorageBuffer,
+    /// Vertices of all meshes.
+    pub vertex_ssbo: ShaderStorageBuffer,
+    /// Vertex indices of all meshes, in the order the mesh BVHs use.
+    pub triangle_ssbo: ShaderStorageBuffer,
+    pub brick_atlas: BrickAtlas,
+    pub medium_table: MediumTable,
+    uploaded: Option<(Vec<Object>, Vec<Material>, Vec<Medium>, Option<u32>, (f32, f32))>, //What is currently on the GPU
+    uploaded_meshes: usize,
+    uploaded_brick_maps: usize,
+    uploaded_density_grids: usize,
+    brick_headers: Vec<usize>, //Where every brick map starts in the grid buffer
+    mesh_nodes: Vec<BvhNode>, //BVHs of all meshes, with children relative to the first mesh node
+    mesh_roots: Vec<usize>, //Root of every mesh in mesh_nodes
+    bounded_count: usize, //Distance field objects in the BVH
+    sdf_count: usize, //All distance field objects
+    bvh_node_count: usize, //Nodes in the distance field BVH
+    instance_root: Option<usize>, //Root of the BVH over mesh objects
+    volume_first: usize, //Volume objects come after everything else
+    volume_count: usize,
+    volume_nodes: usize, //First of the boxes around every volume object
+}
+
+impl Scene {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            materials: Vec::new(),
+            media: Vec::new(),
+            fog: None,
+            density_grids: Vec::new(),
+            meshes: Vec::new(),
+            mesh_bounds: Vec::new(),
+            brick_maps: Vec::new(),
+            brick_map_bounds: Vec::new(),
+
+            ssbo: ShaderStorageBuffer::new(),
+            shape_ssbo: ShaderStorageBuffer::new(),
+            bvh_ssbo: ShaderStorageBuffer::new(),
+            material_ssbo: ShaderStorageBuffer::new(),
+            vertex_ssbo: ShaderStorageBuffer::new(),
+            triangle_ssbo: ShaderStorageBuffer::new(),
+            brick_atlas: BrickAtlas::new(),
+            medium_table: MediumTable::new(),
+            uploaded: None,
+            uploaded_meshes: 0,
+            uploaded_brick_maps: 0,
+            uploaded_density_grids: 0,
+            brick_headers: Vec::new(),
+            mesh_nodes: Vec::new(),
+            mesh_roots: Vec::new(),
+            bounded_count: 0,
+            sdf_count: 0,
+            bvh_node_count: 0,
+            instance_root: None,
+            volume_first: 0,
+            volume_count: 0,
+            volume_nodes: 0,
+        }
+    }
+
+    /// The box with 2 spheres this renderer has always shown.
+    pub fn cornell_box() -> Self {
+        let mut scene = Self::new();
+        scene.materials = vec![
+            Material::default(), //Unused
+            Material::default(),
+            Material::diffuse(vec3(1.0, 0.0, 0.0)),
+            Material::emissive(Vec3::splat(5.0)),
+            Material::diffuse(vec3(0.0, 1.0, 0.0)),
+            Material {
+                roughness: 0.01,
+                ..Default::default()
+            },
+        ];
+
+        let add_box = |scene: &mut Self, pos: Vec3, half_extents: Vec3, material: u32| {
+            scene.add(Object::new(Shape::Box { half_extents: half_extents }, material, Transform::from_translation(pos)));
+        };
+
+        //Box
+        scene.add(Object::new(Shape::Plane, 1, Transform::from_translation(vec3(0.0, -2.0, 0.0))));
+        add_box(&mut scene, vec3(-5.0, 0.0, 0.0), vec3(0.25, 3.0, 6.0), 4);
+        add_box(&mut scene, vec3( 5.0, 0.0, 0.0), vec3(0.25, 3.0, 6.0), 2);
+        add_box(&mut scene, vec3( 0.0, 3.0, 0.0), vec3(5.0, 0.25, 6.0), 1);
+        add_box(&mut scene, vec3( 0.0, 0.0, 4.0), vec3(5.0, 3.0, 0.25), 1);
+        add_box(&mut scene, vec3( 0.0, 0.0,-6.0), vec3(5.0, 3.0, 0.25), 1);
+
+        //Objects in room
+        scene.add(Object::new(Shape::Sphere { radius: 1.0 }, 5, Transform::from_translation(vec3(-1.0, -1.0, 1.0))));
+        scene.add(Object::new(Shape::Sphere { radius: 0.75 }, 1, Transform::from_translation(vec3(1.5, -0.7, 0.75))));
+
+        //Lights
+        add_box(&mut scene, vec3(0.0, 3.0, 0.0), vec3(1.0, 0.26, 1.0), 3);
+
+        scene
+    }
+
+    /// Adds an object, and returns its object ID.
+    pub fn add(&mut self, object: Object) -> u32 {
+        self.objects.push(object);
+        self.objects.len() as u32
+    }
+
+    /// Adds a copy of the object for every transform, and returns their object IDs.
+    /// The copies only differ in their transform, so they all share one shape definition on the GPU.
+    pub fn add_instances(&mut self, object: Object, transforms: &[Transform]) -> Vec<u32> {
+        transforms.iter().map(|transform| self.add(Object {
+            transform: *transform,
+            ..object.clone()
+        })).collect()
+    }
+
+    /// Adds a material, and returns its index.
+    pub fn add_material(&mut self, material: Material) -> u32 {
+        self.materials.push(material);
+        self.materials.len() as u32 - 1
+    }
+
+    /// Adds a mesh, and returns its index for `Shape::Mesh`.
+    pub fn add_mesh(&mut self, mesh: TriangleMesh) -> u32 {
+        assert!(mesh.triangle_count() > 0 && mesh.indices.len() % 3 == 0, "Mesh has no triangles!");
+        assert!(mesh.indices.iter().all(|i| (*i as usize) < mesh.positions.len()), "Mesh indices out of range!");
+        assert!(mesh.normals.len() == mesh.positions.len(), "Mesh needs a normal for every position!");
+        self.mesh_bounds.push(mesh.bounds());
+        self.meshes.push(mesh);
+        self.meshes.len() as u32 - 1
+    }
+
+    pub fn meshes(&self) -> &[TriangleMesh] {
+        &self.meshes
+    }
+
+    /// Adds a brick map, and returns its index for `Shape::BrickMap`.
+    pub fn add_brick_map(&mut self, map: BrickMap) -> u32 {
+        assert!(map.grid.len() == (map.dims.x * map.dims.y * map.dims.z) as usize, "Brick map grid doesn't match its dims!");
+        self.brick_map_bounds.push(map.bounds());
+        self.brick_maps.push(map);
+        self.brick_maps.len() as u32 - 1
+    }
+
+    pub fn brick_maps(&self) -> &[BrickMap] {
+        &self.brick_maps
+    }
+
+    /// Adds a medium, and returns its index for `Object::medium` and `fog`.
+    pub fn add_medium(&mut self, medium: Medium) -> u32 {
+        self.media.push(medium);
+        self.media.len() as u32 - 1
+    }
+
+    /// Adds a density grid, and returns its index for `Density::Grid`.
+    pub fn add_density_grid(&mut self, grid: DensityGrid) -> u32 {
+        self.density_grids.push(grid);
+        self.density_grids.len() as u32 - 1
+    }
+
+    pub fn density_grids(&self) -> &[DensityGrid] {
+        &self.density_grids
+    }
+
+    /// Builds the BVH of every mesh, and uploads all vertices and triangles.
+    fn upload_meshes(&mut self) {
+        let mut vertices = Vec::new();
+        let mut triangles = Vec::new();
+        self.mesh_nodes.clear();
+        self.mesh_roots.clear();
+
+        for mesh in &self.meshes {
+            let bvh = Bvh::build(&mesh.triangle_bounds());
+            let node_offset = self.mesh_nodes.len();
+            let triangle_offset = triangles.len();
+            self.mesh_roots.push(node_offset);
+            self.mesh_nodes.extend(bvh.nodes.iter().map(|node| {
+                let offset = if node.is_leaf() { triangle_offset } else { node_offset };
+                BvhNode {
+                    first: node.first + offset as u32,
+                    ..*node
+                }
+            }));
+
+            let vertex_offset = vertices.len() as u32;
+            triangles.extend(bvh.indices.iter().map(|&t| {
+                let t = t as usize * 3;
+                [vertex_offset + mesh.indices[t], vertex_offset + mesh.indices[t + 1], vertex_offset + mesh.indices[t + 2], 0u32]
+            }));
+            vertices.extend(mesh.positions.iter().enumerate().map(|(i, pos)| {
+                let uv = mesh.uvs.get(i).copied().unwrap_or(Vec2::ZERO);
+                GpuVertex {
+                    pos_u: pos.extend(uv.x).to_array(),
+                    normal_v: mesh.normals[i].extend(uv.y).to_array(),
+                }
+            }));
+        }
+
+        //Empty buffers can't be bound, nothing refers to the dummy entries
+        if vertices.is_empty() {
+            vertices.push(GpuVertex { pos_u: [0.0; 4], normal_v: [0.0; 4] });
+            triangles.push([0; 4]);
+        }
+        self.vertex_ssbo.bind();
+        self.vertex_ssbo.data(&vertices[..], gl::STATIC_DRAW);
+        self.vertex_ssbo.unbind();
+        self.triangle_ssbo.bind();
+        self.triangle_ssbo.data(&triangles[..], gl::STATIC_DRAW);
+        self.triangle_ssbo.unbind();
+
+        self.uploaded_meshes = self.meshes.len();
+        debug!("Meshes uploaded with {} triangles", triangles.len());
+    }
+
+    /// Uploads the scene as it is during the shutter interval, if anything changed.
+    /// Objects move during the interval, materials are taken at shutter open.
+    /// Returns whether anything was uploaded, in which case accumulated samples are outdated.
+    pub(crate) fn update(&mut self, shutter: (f32, f32)) -> bool {
+        let meshes_changed = self.uploaded_meshes != self.meshes.len();
+        let brick_maps_changed = self.uploaded_brick_maps != self.brick_maps.len();
+        let grids_changed = self.uploaded_density_grids != self.density_grids.len();
+        if let Some((objects, materials, media, fog, uploaded_shutter)) = &self.uploaded {
+            if !meshes_changed && !brick_maps_changed && !grids_changed && *objects == self.objects && *materials == self.materials
+                && *media == self.media && *fog == self.fog && *uploaded_shutter == shutter {
+                return false;
+            }
+        }
+        if meshes_changed {
+            self.upload_meshes();
+        }
+        if brick_maps_changed {
+            self.brick_headers = self.brick_atlas.upload(&self.brick_maps);
+            self.uploaded_brick_maps = self.brick_maps.len();
+        }
+        if grids_changed {
+            self.medium_table.upload_grids(&self.density_grids);
+            self.uploaded_density_grids = self.density_grids.len();
+        }
+        assert!(self.fog.map_or(true, |fog| (fog as usize) < self.media.len()), "Fog uses a medium that isn't in the scene!");
+
+        //Subsurface materials get a medium of their own after the ones of the scene, for the walk below their surface
+        let materials_at: Vec<Material> = self.materials.iter().map(|material| material.at(shutter.0)).collect();
+        let mut media = self.media.clone();
+        let mut material_media = Vec::new();
+        for material in &materials_at {
+            material_media.push(material.subsurface_medium().map(|medium| {
+                media.push(medium);
+                media.len() - 1
+            }));
+        }
+        self.medium_table.upload_media(&media, &self.density_grids);
+
+        //Infinite objects can't go in a BVH, the shader checks them separately.
+        //Meshes are traced instead of marched, so they get a BVH of their own.
+        //Volumes aren't surfaces, so they are left out of both and only get a box each.
+        let mut bounded = Vec::new();
+        let mut bounds = Vec::new();
+        let mut unbounded = Vec::new();
+        let mut instances = Vec::new();
+        let mut instance_bounds = Vec::new();
+        let mut volumes = Vec::new();
+        let mut volume_bounds = Vec::new();
+        for (i, object) in self.objects.iter().enumerate() {
+            let object_bounds = object.bounds(shutter, &self.mesh_bounds, &self.brick_map_bounds);
+            if object.is_volume() {
+                assert!((object.medium.unwrap() as usize) < self.media.len(), "Object uses a medium that isn't in the scene!");
+                volumes.push(i);
+                volume_bounds.push(object_bounds.unwrap_or(Aabb::new(Vec3::splat(-1e30), Vec3::splat(1e30))));
+                continue;
+            }
+            match (object.shape, object_bounds) {
+                (Shape::Mesh { .. }, Some(b)) => {
+                    //The walk below the surface marches the distance field, which meshes aren't part of
+                    assert!(material_media.get(object.material as usize).map_or(true, |medium| medium.is_none()),
+                        "Object {} is a mesh with a subsurface material, which only works on distance fields! Bake the mesh into a brick map instead.", i);
+                    instances.push(i);
+                    instance_bounds.push(b);
+                },
+                (_, Some(b)) => {
+                    bounded.push(i);
+                    bounds.push(b);
+                },
+                (_, None) => unbounded.push(i),
+            }
+        }
+        let bvh = Bvh::build(&bounds);
+        let instance_bvh = Bvh::build(&instance_bounds);
+        let sdf_count = bounded.len() + unbounded.len();
+        let instance_root = bvh.nodes.len();
+        let mesh_root = instance_root + instance_bvh.nodes.len();
+        let volume_first = sdf_count + instances.len();
+        let volume_nodes = mesh_root + self.mesh_nodes.len();
+
+        let order = bvh.indices.iter().map(|&i| bounded[i as usize])
+            .chain(unbounded.iter().copied())
+            .chain(instance_bvh.indices.iter().map(|&i| instances[i as usize]))
+            .chain(volumes.iter().copied());
+        let mut shapes = Vec::new();
+        let mut shape_lookup = HashMap::new();
+        let mut data: Vec<GpuObject> = order.map(|i| {
+            let object = &self.objects[i];
+            let shape = match object.shape {
+                Shape::Mesh { mesh } => GpuShape::new(&object.shape, [(mesh_root + self.mesh_roots[mesh as usize]) as f32, 0.0, 0.0, 0.0], &[]),
+                Shape::BrickMap { map } => GpuShape::new(&object.shape, [self.brick_headers[map as usize] as f32, 0.0, 0.0, 0.0], &object.domain),
+                shape => GpuShape::new(&shape, shape.params(), &object.domain),
+            };
+            let definition = *shape_lookup.entry(shape.key()).or_insert_with(|| {
+                shapes.push(shape);
+                shapes.len() - 1
+            });
+            GpuObject::new(object, i as u32 + 1, definition, shutter)
+        }).collect();
+        if data.is_empty() {
+            //Empty buffers can't be bound, the shader uses the counts anyway
+            data.push(GpuObject::new(&Object::new(Shape::Plane, 0, Transform::default()), 0, 0, shutter));
+            shapes.push(GpuShape::new(&Shape::Plane, [0.0; 4], &[]));
+        }
+        self.ssbo.bind();
+        self.ssbo.data(&data[..], gl::STATIC_DRAW);
+        self.ssbo.unbind();
+        self.shape_ssbo.bind();
+        self.shape_ssbo.data(&shapes[..], gl::STATIC_DRAW);
+        self.shape_ssbo.unbind();
+
+        let mut nodes: Vec<GpuBvhNode> = bvh.nodes.iter().map(|node| GpuBvhNode::new(node, 0, 0))
+            .chain(instance_bvh.nodes.iter().map(|node| GpuBvhNode::new(node, instance_root, sdf_count)))
+            .chain(self.mesh_nodes.iter().map(|node| GpuBvhNode::new(node, mesh_root, 0)))
+            .chain(volume_bounds.iter().enumerate().map(|(j, b)| GpuBvhNode {
+                min_first: b.min.extend((volume_first + j) as f32).to_array(),
+                max_count: b.max.extend(1.0).to_array(),
+            }))
+            .collect();
+        if nodes.is_empty() {
+            nodes.push(GpuBvhNode { min_first: [0.0; 4], max_count: [0.0; 4] });
+        }
+        self.bvh_ssbo.bind();
+        self.bvh_ssbo.data(&nodes[..], gl::STATIC_DRAW);
+        self.bvh_ssbo.unbind();
+        self.bounded_count = bounded.len();
+        self.sdf_count = sdf_count;
+        self.bvh_node_count = bvh.nodes.len();
+        self.instance_root = if instances.is_empty() { None } else { Some(instance_root) };
+        self.volume_first = volume_first;
+        self.volume_count = volumes.len();
+        self.volume_nodes = volume_nodes;
+
+        let mut materials: Vec<GpuMaterial> = materials_at.iter().zip(&material_media).map(|(material, medium)| GpuMaterial::new(material, *medium)).collect();
+        if materials.is_empty() {
+            materials.push(GpuMaterial::new(&Material::default(), None));
+        }
+        self.material_ssbo.bind();
+        self.material_ssbo.data(&materials[..], gl::STATIC_DRAW);
+        self.material_ssbo.unbind();
+
+        self.uploaded = Some((self.objects.clone(), self.materials.clone(), self.media.clone(), self.fog, shutter));
+        trace!("Scene uploaded with {} objects, {} shape definitions and {} materials", self.objects.len(), shapes.len(), self.materials.len());
+        true
+    }
+
+    /// Distance field objects, which come first on the GPU.
+    pub(crate) fn sdf_count(&self) -> usize {
+        self.sdf_count
+    }
+
+    /// Distance field objects in the BVH, which come before the infinite ones.
+    pub(crate) fn bounded_count(&self) -> usize {
+        self.bounded_count
+    }
+
+    pub(crate) fn bvh_node_count(&self) -> usize {
+        self.bvh_node_count
+    }
+
+    /// First node of the BVH over mesh objects, -1 if there are none.
+    pub(crate) fn instance_root(&self) -> i32 {
+        self.instance_root.map_or(-1, |root| root as i32)
+    }
+
+    /// Volume objects, which come after all other objects on the GPU.
+    pub(crate) fn volume_first(&self) -> usize {
+        self.volume_first
+    }
+
+    pub(crate) fn volume_count(&self) -> usize {
+        self.volume_count
+    }
+
+    /// First of the boxes around every volume object, after all BVH nodes.
+    pub(crate) fn volume_nodes(&self) -> usize {
+        self.volume_nodes
+    }
+
+    /// Medium of the fog, -1 if there is none.
+    pub(crate) fn fog_medium(&self) -> i32 {
+        self.fog.map_or(-1, |fog| fog as i32)
+    }
+}
@@ -0,0 +1,163 @@
+//! Rendering animations to numbered image sequences.
+
+use std::path::Path;
+
+use crate::Raytracer;
+use crate::objects::Camera;
+use crate::scene::Scene;
+use crate::animation::CameraAnimation;
+use crate::output::{exr, hdr};
+use crate::settings::Aov;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SequenceFormat {
+    /// Multi-layer EXR, with every AOV in the render settings as an extra layer.
+    Exr,
+    Hdr,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SequenceSettings {
+    pub first_frame: u32,
+    /// Inclusive.
+    pub last_frame: u32,
+    pub frame_rate: f32,
+    pub samples_per_frame: u32,
+    /// Part of the frame the shutter is open, 0.5 is a 180 degree shutter. 0 disables motion blur.
+    pub shutter: f32,
+    /// Every `#` gets replaced by a digit of the frame number, like `frames/frame_####.exr`.
+    pub path: String,
+    pub format: SequenceFormat,
+}
+
+impl Default for SequenceSettings {
+    fn default() -> Self {
+        Self {
+            first_frame: 0,
+            last_frame: 0,
+            frame_rate: 24.0,
+            samples_per_frame: 64,
+            shutter: 0.5,
+            path: "frame_####.exr".to_string(),
+            format: SequenceFormat::Exr,
+        }
+    }
+}
+
+impl SequenceSettings {
+    /// Time at the start of a frame, in seconds.
+    pub fn frame_time(&self, frame: u32) -> f32 {
+        frame as f32 / self.frame_rate
+    }
+
+    pub fn shutter_interval(&self, frame: u32) -> (f32, f32) {
+        let open = self.frame_time(frame);
+        (open, open + self.shutter / self.frame_rate)
+    }
+
+    /// The output path for a frame, padded with zeroes to the amount of `#`s.
+    /// Frame numbers that don't fit are written out in full.
+    pub fn frame_path(&self, frame: u32) -> String {
+        let start = match self.path.find('#') {
+            Some(start) => start,
+            None => return self.path.clone(),
+        };
+        let digits = self.path[start..].chars().take_while(|c| *c == '#').count();
+        format!("{}{:0width$}{}", &self.path[..start], frame, &self.path[start + digits..], width = digits)
+    }
+}
+
+/// Renders every frame in the sequence and writes it to disk.
+/// The camera animation, if any, is applied for every frame. Objects and materials animate by themselves.
+pub fn render_sequence(raytracer: &mut Raytracer, camera: &mut Camera, scene: &mut Scene, animation: Option<&CameraAnimation>, settings: &SequenceSettings) -> std::io::Result<()> {
+    assert!(settings.frame_rate > 0.0, "Frame rate must be above 0, got {}!", settings.frame_rate);
+    if let Some(dir) = Path::new(&settings.frame_path(settings.first_frame)).parent() {
+        if !dir.as_os_str().is_empty() {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
+
+    for frame in settings.first_frame..=settings.last_frame {
+        let shutter = settings.shutter_interval(frame);
+        match animation {
+            Some(animation) => animation.apply(camera, shutter),
+            None => camera.shutter = shutter,
+        }
+
+        raytracer.reset_accumulation();
+        for _ in 0..settings.samples_per_frame {
+            raytracer.render_sample(camera, scene);
+        }
+
+        let path = settings.frame_path(frame);
+        let image = camera.get_texture_as_float_image();
+        match settings.format {
+            SequenceFormat::Exr => {
+                let aovs: Vec<(Aov, _)> = raytracer.settings().aovs.iter().filter_map(|aov| {
+                    camera.get_aov_as_float_image(*aov).map(|image| (*aov, image))
+                }).collect();
+                let mut layers = vec![exr::Layer::new("", &image, exr::PixelType::Half)];
+                for (aov, image) in &aovs {
+                    let pixel_type = match aov {
+                        Aov::Depth | Aov::Position => exr::PixelType::Float,
+                        _ => exr::PixelType::Half,
+                    };
+                    layers.push(exr::Layer::new(aov.name(), image, pixel_type));
+                }
+                exr::write(&path, &layers)?;
+            },
+            SequenceFormat::Hdr => hdr::write(&path, &image)?,
+        }
+        info!("Frame {} saved to {}", frame, path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_path(path: &str) -> SequenceSettings {
+        SequenceSettings {
+            path: path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn frame_path_padding() {
+        let settings = with_path("frames/frame_####.exr");
+        assert_eq!(settings.frame_path(0), "frames/frame_0000.exr");
+        assert_eq!(settings.frame_path(42), "frames/frame_0042.exr");
+        assert_eq!(settings.frame_path(9999), "frames/frame_9999.exr");
+    }
+
+    #[test]
+    fn frame_path_overflow() {
+        assert_eq!(with_path("frame_##.hdr").frame_path(12345), "frame_12345.hdr");
+        assert_eq!(with_path("#").frame_path(7), "7");
+    }
+
+    #[test]
+    fn frame_path_without_hashes() {
+        assert_eq!(with_path("still.exr").frame_path(3), "still.exr");
+    }
+
+    #[test]
+    fn frame_path_only_first_group() {
+        assert_eq!(with_path("take_#/frame_###.exr").frame_path(5), "take_5/frame_###.exr");
+    }
+
+    #[test]
+    fn shutter_interval() {
+        let settings = SequenceSettings {
+            frame_rate: 25.0,
+            shutter: 0.5,
+            ..Default::default()
+        };
+        assert_eq!(settings.frame_time(50), 2.0);
+        let (open, close) = settings.shutter_interval(25);
+        assert_eq!(open, 1.0);
+        assert!((close - 1.02).abs() < 1e-6);
+    }
+}
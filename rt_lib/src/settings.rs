@@ -0,0 +1,282 @@
+use crate::sampler::SamplerKind;
+use crate::filter::PixelFilter;
+
+/// Arbitrary output variables, rendered alongside the beauty pass.
+/// Surface AOVs come from the first hit of the camera ray.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Aov {
+    Albedo,
+    /// World space normal.
+    Normal,
+    /// Distance from the camera to the first hit. 0 for the sky.
+    Depth,
+    /// World space position.
+    Position,
+    /// Surface uv, only meshes have them.
+    Uv,
+    ObjectId,
+    MaterialId,
+    /// Light reaching the camera after exactly one bounce.
+    Direct,
+    /// Light reaching the camera after two or more bounces.
+    Indirect,
+    /// Emitters seen directly by the camera.
+    Emission,
+    /// March steps taken by every ray of the path together, to see where tracing is expensive.
+    Steps,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 11] = [
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Position,
+        Aov::Uv,
+        Aov::ObjectId,
+        Aov::MaterialId,
+        Aov::Direct,
+        Aov::Indirect,
+        Aov::Emission,
+        Aov::Steps,
+    ];
+
+    /// Name used for layers when saving.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::Uv => "uv",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Emission => "emission",
+            Aov::Steps => "steps",
+        }
+    }
+
+    /// Amount of meaningful channels. The GPU always stores 4.
+    pub fn channels(&self) -> usize {
+        match self {
+            Aov::Depth | Aov::ObjectId | Aov::MaterialId | Aov::Steps => 1,
+            _ => 3,
+        }
+    }
+
+    /// Name of the define that holds the texture layer in the shaders.
+    fn define(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "AOV_ALBEDO",
+            Aov::Normal => "AOV_NORMAL",
+            Aov::Depth => "AOV_DEPTH",
+            Aov::Position => "AOV_POSITION",
+            Aov::Uv => "AOV_UV",
+            Aov::ObjectId => "AOV_OBJECT_ID",
+            Aov::MaterialId => "AOV_MATERIAL_ID",
+            Aov::Direct => "AOV_DIRECT",
+            Aov::Indirect => "AOV_INDIRECT",
+            Aov::Emission => "AOV_EMISSION",
+            Aov::Steps => "AOV_STEPS",
+        }
+    }
+}
+
+/// Limits on how deep paths go. Depths count bounces, so a depth of 0 only shows what the camera sees directly.
+/// The shaders count bounces per type in 8 bits, so those limits are capped at `MAX_TYPE_DEPTH`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PathDepth {
+    /// Bounces of any type. Every sample traces this many waves + 1.
+    pub max: u32,
+    /// Scattering in participating media counts as diffuse.
+    pub diffuse: u32,
+    pub glossy: u32,
+    pub transmission: u32,
+    /// Bounces before Russian roulette starts terminating paths. `None` disables it.
+    pub roulette: Option<u32>,
+}
+
+pub const MAX_TYPE_DEPTH: u32 = 254;
+
+impl Default for PathDepth {
+    fn default() -> Self {
+        Self {
+            max: 16,
+            diffuse: 8,
+            glossy: 12,
+            transmission: 12,
+            roulette: Some(3),
+        }
+    }
+}
+
+/// Everything about how an image is rendered, as opposed to what is in it.
+/// Missing fields take their default when deserializing.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct RenderSettings {
+    /// AOVs to render, in the order they are stored on the GPU.
+    pub aovs: Vec<Aov>,
+    /// Seed for all random numbers. The same seed always gives the same render.
+    pub seed: u32,
+    /// Sequence used for pixel jitter and bounce directions.
+    pub sampler: SamplerKind,
+    /// Reconstruction filter used to spread camera rays over the pixel.
+    pub pixel_filter: PixelFilter,
+    pub path_depth: PathDepth,
+    /// Steps a ray marches before giving up, and counting as a miss.
+    pub max_steps: u32,
+    /// Distance to a surface at which a ray counts as a hit.
+    /// Far away, this grows with the footprint of a pixel, so surfaces aren't marched more precisely than they can be seen.
+    pub hit_epsilon: f32,
+    /// Over-relaxation of sphere tracing steps, between 1 and 2. 1 is plain sphere tracing.
+    /// Larger steps save time in open space, and fall back to plain steps whenever they overshoot.
+    pub relaxation: f32,
+    /// Distance new rays start away from the surface they bounce off, so they don't hit it again right away.
+    pub ray_offset: f32,
+    /// Caps the light a single path can bring in after bouncing, which removes fireflies at the cost of some energy.
+    /// Light seen directly by the camera is never clamped.
+    pub firefly_clamp: Option<f32>,
+    /// Collisions a ray goes through in participating media before it stops tracking them, and carries on to the surface behind.
+    pub max_volume_steps: u32,
+    /// Scattering events a random walk below the surface of a subsurface material goes through before the path is dropped.
+    pub max_subsurface_steps: u32,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            aovs: Vec::new(),
+            seed: 0,
+            sampler: SamplerKind::default(),
+            pixel_filter: PixelFilter::default(),
+            path_depth: PathDepth::default(),
+            max_steps: 512,
+            hit_epsilon: 0.01,
+            relaxation: 1.2,
+            ray_offset: 0.05,
+            firefly_clamp: None,
+            max_volume_steps: 256,
+            max_subsurface_steps: 256,
+        }
+    }
+}
+
+impl RenderSettings {
+    /// Panics on settings that would break the shaders, instead of silently rendering garbage.
+    /// Everything injected as a float define has to be finite, or it won't compile.
+    pub fn validate(&self) {
+        assert!(self.max_steps > 0, "max_steps must be above 0!");
+        //0 would silently turn off media, and drop every path going below a surface
+        assert!(self.max_volume_steps > 0, "max_volume_steps must be above 0!");
+        assert!(self.max_subsurface_steps > 0, "max_subsurface_steps must be above 0!");
+        assert!(self.hit_epsilon.is_finite() && self.hit_epsilon > 0.0, "hit_epsilon must be a finite number above 0, got {}!", self.hit_epsilon);
+        //Above 2, steps can skip over surfaces without the fallback noticing
+        assert!((1.0..=2.0).contains(&self.relaxation), "relaxation must be between 1 and 2, got {}!", self.relaxation);
+        assert!(self.ray_offset.is_finite() && self.ray_offset >= 0.0, "ray_offset must be a finite number of at least 0, got {}!", self.ray_offset);
+        if let Some(clamp) = self.firefly_clamp {
+            assert!(clamp.is_finite() && clamp > 0.0, "firefly_clamp must be a finite number above 0, got {}!", clamp);
+        }
+    }
+
+    /// Defines injected into the shaders that depend on these settings.
+    pub(crate) fn shader_defines(&self) -> Vec<(String, String)> {
+        let mut defines = Vec::new();
+        defines.push(("AOV_COUNT".to_string(), format!("{}", self.aovs.len())));
+        for (layer, aov) in self.aovs.iter().enumerate() {
+            defines.push((aov.define().to_string(), format!("{}", layer)));
+        }
+        //Floats are formatted with Debug, which always gives a valid GLSL float
+        defines.push(("MAX_STEPS".to_string(), format!("{}", self.max_steps)));
+        defines.push(("DIST_PRECISION".to_string(), format!("{:?}", self.hit_epsilon)));
+        defines.push(("RELAXATION".to_string(), format!("{:?}", self.relaxation)));
+        defines.push(("RAY_OFFSET".to_string(), format!("{:?}", self.ray_offset)));
+        defines.push(("MAX_VOLUME_STEPS".to_string(), format!("{}", self.max_volume_steps)));
+        defines.push(("MAX_SUBSURFACE_STEPS".to_string(), format!("{}", self.max_subsurface_steps)));
+        if let Some(clamp) = self.firefly_clamp {
+            defines.push(("FIREFLY_CLAMP".to_string(), format!("{:?}", clamp)));
+        }
+        defines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_valid() {
+        RenderSettings::default().validate();
+    }
+
+    #[test]
+    fn relaxation_range_is_inclusive() {
+        for &relaxation in &[1.0, 2.0] {
+            RenderSettings { relaxation: relaxation, ..Default::default() }.validate();
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "relaxation")]
+    fn relaxation_above_2() {
+        RenderSettings { relaxation: 2.5, ..Default::default() }.validate();
+    }
+
+    #[test]
+    #[should_panic(expected = "relaxation")]
+    fn relaxation_nan() {
+        RenderSettings { relaxation: f32::NAN, ..Default::default() }.validate();
+    }
+
+    #[test]
+    #[should_panic(expected = "max_steps")]
+    fn zero_max_steps() {
+        RenderSettings { max_steps: 0, ..Default::default() }.validate();
+    }
+
+    #[test]
+    #[should_panic(expected = "max_volume_steps")]
+    fn zero_max_volume_steps() {
+        RenderSettings { max_volume_steps: 0, ..Default::default() }.validate();
+    }
+
+    #[test]
+    #[should_panic(expected = "max_subsurface_steps")]
+    fn zero_max_subsurface_steps() {
+        RenderSettings { max_subsurface_steps: 0, ..Default::default() }.validate();
+    }
+
+    #[test]
+    #[should_panic(expected = "hit_epsilon")]
+    fn zero_hit_epsilon() {
+        RenderSettings { hit_epsilon: 0.0, ..Default::default() }.validate();
+    }
+
+    #[test]
+    #[should_panic(expected = "ray_offset")]
+    fn infinite_ray_offset() {
+        RenderSettings { ray_offset: f32::INFINITY, ..Default::default() }.validate();
+    }
+
+    #[test]
+    #[should_panic(expected = "firefly_clamp")]
+    fn nan_firefly_clamp() {
+        RenderSettings { firefly_clamp: Some(f32::NAN), ..Default::default() }.validate();
+    }
+
+    #[test]
+    fn float_defines_are_glsl_floats() {
+        let settings = RenderSettings { relaxation: 2.0, firefly_clamp: Some(10.0), ..Default::default() };
+        for (name, value) in settings.shader_defines() {
+            if name == "RELAXATION" || name == "FIREFLY_CLAMP" {
+                assert!(value.contains('.'), "{} is {}, which GLSL reads as an int", name, value);
+            }
+        }
+    }
+}
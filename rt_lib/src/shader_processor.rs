@@ -25,3 +25,25 @@ pub fn preprocessor(src_path: &Path, dispatch_size: (u32, u32)) -> String {
 
     result
 }
+
+/// Same as `preprocessor`, but also inserts a `#define` for every (name, value) pair,
+/// right after the `#version` line.
+pub fn preprocessor_with_defines(src_path: &Path, dispatch_size: (u32, u32), defines: &[(String, String)]) -> String {
+    let src = preprocessor(src_path, dispatch_size);
+
+    let mut define_src = String::new();
+    for (name, value) in defines {
+        define_src.push_str(&format!("#define {} {}\n", name, value));
+    }
+
+    match src.find("#version") {
+        Some(start) => {
+            let end = src[start..].find('\n').map(|i| start + i + 1).unwrap_or(src.len());
+            let mut result = String::from(&src[..end]);
+            result.push_str(&define_src);
+            result.push_str(&src[end..]);
+            result
+        },
+        None => define_src + &src,
+    }
+}
@@ -0,0 +1,229 @@
+//! View transform, turning the linear render into something displayable.
+//! This is the CPU side twin of `shaders/tonemap.glsl`. Both need to stay in sync,
+//! so the image on screen is the same as the image that gets saved.
+
+use crate::output::FloatImage;
+
+/// Tone curve applied after exposure. The values match the defines in `tonemap.glsl`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ToneCurve {
+    /// No curve, values above 1 are simply clipped.
+    Clamp = 0,
+    Reinhard = 1,
+    /// Stephen Hill's fit of the ACES RRT + sRGB ODT.
+    AcesFilmic = 2,
+    /// John Hable's filmic curve, as used in Uncharted 2.
+    Filmic = 3,
+    /// Polynomial approximation of the AgX base transform.
+    AgX = 4,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ViewTransform {
+    /// Exposure in stops (EV). Every stop doubles the brightness.
+    pub exposure: f32,
+    pub curve: ToneCurve,
+}
+
+impl Default for ViewTransform {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            curve: ToneCurve::AcesFilmic,
+        }
+    }
+}
+
+impl ViewTransform {
+    pub fn new(exposure: f32, curve: ToneCurve) -> Self {
+        Self {
+            exposure: exposure,
+            curve: curve,
+        }
+    }
+
+    /// Applies exposure, the tone curve and the sRGB OETF to a linear colour.
+    /// The result is display encoded, in the range [0, 1].
+    pub fn apply(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let scale = 2f32.powf(self.exposure);
+        let rgb = [rgb[0] * scale, rgb[1] * scale, rgb[2] * scale];
+        let mapped = match self.curve {
+            ToneCurve::Clamp => rgb,
+            ToneCurve::Reinhard => map3(rgb, reinhard),
+            ToneCurve::AcesFilmic => aces_filmic(rgb),
+            ToneCurve::Filmic => map3(rgb, |x| hable(x * 2.0) / hable(HABLE_WHITE)),
+            ToneCurve::AgX => agx(rgb),
+        };
+        map3(mapped, |x| srgb_oetf(x.max(0.0).min(1.0)))
+    }
+
+    /// Converts a linear float image into 8 bit RGBA, ready to be saved as png or similar.
+    pub fn to_ldr(&self, image: &FloatImage) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(image.width * image.height * 4);
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let p = image.pixel(x, y);
+                let rgb = match image.channels {
+                    1 | 2 => [p[0], p[0], p[0]],
+                    _ => [p[0], p[1], p[2]],
+                };
+                let display = self.apply(rgb);
+                pixels.push(quantize(display[0]));
+                pixels.push(quantize(display[1]));
+                pixels.push(quantize(display[2]));
+                pixels.push(255);
+            }
+        }
+        pixels
+    }
+}
+
+fn quantize(x: f32) -> u8 {
+    (x * 255.0 + 0.5) as u8
+}
+
+fn map3<F: Fn(f32) -> f32>(v: [f32; 3], f: F) -> [f32; 3] {
+    [f(v[0]), f(v[1]), f(v[2])]
+}
+
+/// Multiplies a vector with a 3x3 matrix, given as columns like a GLSL `mat3`.
+fn mul_mat3(m: &[f32; 9], v: [f32; 3]) -> [f32; 3] {
+    [
+        m[0] * v[0] + m[3] * v[1] + m[6] * v[2],
+        m[1] * v[0] + m[4] * v[1] + m[7] * v[2],
+        m[2] * v[0] + m[5] * v[1] + m[8] * v[2],
+    ]
+}
+
+/// The sRGB opto-electronic transfer function.
+pub fn srgb_oetf(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn reinhard(x: f32) -> f32 {
+    x / (1.0 + x)
+}
+
+const ACES_INPUT_MAT: [f32; 9] = [
+    0.59719, 0.07600, 0.02840,
+    0.35458, 0.90834, 0.13383,
+    0.04823, 0.01566, 0.83777,
+];
+
+const ACES_OUTPUT_MAT: [f32; 9] = [
+     1.60475, -0.10208, -0.00327,
+    -0.53108,  1.10813, -0.07276,
+    -0.07367, -0.00605,  1.07602,
+];
+
+fn aces_filmic(rgb: [f32; 3]) -> [f32; 3] {
+    let v = mul_mat3(&ACES_INPUT_MAT, rgb);
+    let v = map3(v, |x| (x * (x + 0.0245786) - 0.000090537) / (x * (0.983729 * x + 0.4329510) + 0.238081));
+    mul_mat3(&ACES_OUTPUT_MAT, v)
+}
+
+const HABLE_WHITE: f32 = 11.2;
+
+fn hable(x: f32) -> f32 {
+    const A: f32 = 0.15;
+    const B: f32 = 0.50;
+    const C: f32 = 0.10;
+    const D: f32 = 0.20;
+    const E: f32 = 0.02;
+    const F: f32 = 0.30;
+    ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+}
+
+const AGX_MAT: [f32; 9] = [
+    0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+    0.0784335999999992, 0.878468636469772, 0.0784336,
+    0.0792237451477643, 0.0791661274605434, 0.879142973793104,
+];
+
+const AGX_MAT_INV: [f32; 9] = [
+    1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+    -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+    -0.0990297440797205, -0.0989611768448433, 1.15107367264116,
+];
+
+const AGX_MIN_EV: f32 = -12.47393;
+const AGX_MAX_EV: f32 = 4.026069;
+
+fn agx_contrast(x: f32) -> f32 {
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232
+}
+
+fn agx(rgb: [f32; 3]) -> [f32; 3] {
+    let v = mul_mat3(&AGX_MAT, rgb);
+    let v = map3(v, |x| {
+        //log2(0) is -inf, which the clamp takes care of
+        let ev = x.max(1e-10).log2().max(AGX_MIN_EV).min(AGX_MAX_EV);
+        agx_contrast((ev - AGX_MIN_EV) / (AGX_MAX_EV - AGX_MIN_EV))
+    });
+    let v = mul_mat3(&AGX_MAT_INV, v);
+    //The curve outputs display encoded values, convert back to linear for the OETF
+    map3(v, |x| x.max(0.0).powf(2.2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glsl_interpreter::{Program, Value};
+
+    const CURVES: [ToneCurve; 5] = [ToneCurve::Clamp, ToneCurve::Reinhard, ToneCurve::AcesFilmic, ToneCurve::Filmic, ToneCurve::AgX];
+
+    /// Runs `viewTransform` from `tonemap.glsl` itself, and checks it gives the same colours as `ViewTransform::apply`.
+    #[test]
+    fn matches_shader() {
+        let shader = Program::parse(include_str!("../shaders/tonemap.glsl"));
+        let colours = [
+            [0.0; 3],
+            [0.18; 3],
+            [1.0; 3],
+            [16.0; 3],
+            [0.001, 0.002, 0.0005],
+            [0.5, 0.1, 0.02],
+            [2.0, 0.7, 0.05],
+            [100.0, 40.0, 5.0],
+        ];
+        for curve in CURVES.iter() {
+            for &exposure in &[-3.0, 0.0, 1.5] {
+                let transform = ViewTransform::new(exposure, *curve);
+                for colour in colours.iter() {
+                    let expected = shader.call("viewTransform", &[Value::Vec3(*colour), Value::Float(exposure), Value::Int(*curve as i32)]).vec3();
+                    let result = transform.apply(*colour);
+                    for c in 0..3 {
+                        assert!((result[c] - expected[c]).abs() < 1e-5, "{:?} at {:?} and {} EV: {:?}, the shader gives {:?}", curve, colour, exposure, result, expected);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn curves_are_monotonic() {
+        for curve in CURVES.iter() {
+            let transform = ViewTransform::new(0.0, *curve);
+            let mut last = 0.0;
+            for i in 0..200 {
+                let x = 2f32.powf(i as f32 * 0.1 - 10.0);
+                let y = transform.apply([x; 3])[1];
+                assert!(y >= last - 1e-6 && y <= 1.0, "{:?} isn't monotonic at {}", curve, x);
+                last = y;
+            }
+        }
+    }
+
+    #[test]
+    fn exposure_doubles_per_stop() {
+        let transform = ViewTransform::new(1.0, ToneCurve::Clamp);
+        let reference = ViewTransform::new(0.0, ToneCurve::Clamp);
+        assert_eq!(transform.apply([0.1; 3]), reference.apply([0.2; 3]));
+    }
+}
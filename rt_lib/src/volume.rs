@@ -0,0 +1,181 @@
+//! Participating media on the GPU, tracked through in `shaders/volume.glsl`.
+//! Media themselves are described by `objects::Medium`. Objects with a medium fill their inside with it,
+//! and the scene can have a medium that fills everything, as fog.
+
+use glam::*;
+
+use crate::bvh::Aabb;
+use crate::objects::{Medium, Density};
+
+/// Most media a scene can have, see `MAX_MEDIA` in `medium.glsl`.
+pub const MAX_MEDIA: usize = 16;
+
+/// Densities on a regular grid, for smoke from simulations and the like.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DensityGrid {
+    /// The grid is stretched over these bounds, in the space of the object it is in.
+    /// Everything outside of them has a density of 0.
+    pub bounds: Aabb,
+    pub dims: UVec3,
+    /// X first.
+    pub values: Vec<f32>,
+}
+
+impl DensityGrid {
+    pub fn new(bounds: Aabb, dims: UVec3, values: Vec<f32>) -> Self {
+        assert!(values.len() == (dims.x * dims.y * dims.z) as usize, "Density grid values don't match its dims!");
+        Self {
+            bounds: bounds,
+            dims: dims,
+            values: values,
+        }
+    }
+
+    /// Samples a density function at the center of every voxel.
+    pub fn from_fn(bounds: Aabb, dims: UVec3, density: impl Fn(Vec3) -> f32) -> Self {
+        let voxel = (bounds.max - bounds.min) / dims.as_vec3();
+        let mut values = Vec::with_capacity((dims.x * dims.y * dims.z) as usize);
+        for z in 0..dims.z {
+            for y in 0..dims.y {
+                for x in 0..dims.x {
+                    values.push(density(bounds.min + (uvec3(x, y, z).as_vec3() + 0.5) * voxel));
+                }
+            }
+        }
+        Self::new(bounds, dims, values)
+    }
+
+    /// Highest density in the grid, which tracking needs to know how far it can step.
+    pub fn max(&self) -> f32 {
+        self.values.iter().fold(0.0, |max, v| max.max(*v))
+    }
+}
+
+/// Layout of a single medium on the GPU, see `RawMedium` in `medium.glsl`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuMedium {
+    absorption_kind: [f32; 4],
+    scattering_g: [f32; 4],
+    density: [f32; 4],
+    grid_min: [f32; 4],
+    grid_max: [f32; 4],
+}
+
+impl GpuMedium {
+    fn new(medium: &Medium, grids: &[DensityGrid], layers: &[usize]) -> Self {
+        let (kind, density, grid_min, grid_max) = match medium.density {
+            Density::Constant(density) => (0.0, [density, density, 0.0, 0.0], [0.0; 4], [0.0; 4]),
+            Density::Noise { scale, frequency, octaves } => (1.0, [scale, scale, frequency, octaves.max(1) as f32], [0.0; 4], [0.0; 4]),
+            Density::Grid { grid, scale } => {
+                let data = grids.get(grid as usize).expect("Medium uses a density grid that isn't in the scene!");
+                (
+                    2.0,
+                    [scale, scale * data.max(), data.dims.x as f32, data.dims.y as f32],
+                    data.bounds.min.extend(layers[grid as usize] as f32).to_array(),
+                    data.bounds.max.extend(data.dims.z as f32).to_array(),
+                )
+            },
+        };
+        Self {
+            absorption_kind: medium.absorption.extend(kind).to_array(),
+            scattering_g: medium.scattering.extend(medium.anisotropy.max(-0.99).min(0.99)).to_array(),
+            density: density,
+            grid_min: grid_min,
+            grid_max: grid_max,
+        }
+    }
+}
+
+/// The media of the scene in a uniform buffer, and their density grids stacked on top of each other in a 3D texture.
+//TODO: Implement uniform buffers and 3D textures in glux, so we don't have to wrap them here
+pub struct MediumTable {
+    pub ubo: u32,
+    pub density_atlas: u32,
+    /// First layer of every grid in the atlas.
+    layers: Vec<usize>,
+}
+
+impl MediumTable {
+    pub fn new() -> Self {
+        let mut ubo = 0;
+        unsafe {
+            gl::CreateBuffers(1, &mut ubo);
+            gl::NamedBufferData(ubo, (MAX_MEDIA * std::mem::size_of::<GpuMedium>()) as isize, std::ptr::null(), gl::DYNAMIC_DRAW);
+        }
+        let mut table = Self {
+            ubo: ubo,
+            density_atlas: 0,
+            layers: Vec::new(),
+        };
+        table.upload_grids(&[]);
+        table
+    }
+
+    /// Replaces all density grids.
+    pub fn upload_grids(&mut self, grids: &[DensityGrid]) {
+        //Empty textures can't be bound, so there is always at least one voxel
+        let width = grids.iter().map(|grid| grid.dims.x as usize).max().unwrap_or(1);
+        let height = grids.iter().map(|grid| grid.dims.y as usize).max().unwrap_or(1);
+        let depth = grids.iter().map(|grid| grid.dims.z as usize).sum::<usize>().max(1);
+
+        let mut texels = vec![0.0f32; width * height * depth];
+        self.layers.clear();
+        let mut layer = 0;
+        for grid in grids {
+            self.layers.push(layer);
+            let (x, y) = (grid.dims.x as usize, grid.dims.y as usize);
+            for z in 0..grid.dims.z as usize {
+                for row in 0..y {
+                    let start = width * (row + height * (layer + z));
+                    let source = x * (row + y * z);
+                    texels[start..start + x].copy_from_slice(&grid.values[source..source + x]);
+                }
+            }
+            layer += grid.dims.z as usize;
+        }
+
+        unsafe {
+            if self.density_atlas != 0 {
+                gl::DeleteTextures(1, &self.density_atlas);
+            }
+            gl::CreateTextures(gl::TEXTURE_3D, 1, &mut self.density_atlas);
+            gl::TextureStorage3D(self.density_atlas, 1, gl::R32F, width as i32, height as i32, depth as i32);
+            gl::TextureSubImage3D(self.density_atlas, 0, 0, 0, 0, width as i32, height as i32, depth as i32, gl::RED, gl::FLOAT, texels.as_ptr() as *const std::ffi::c_void);
+            gl::TextureParameteri(self.density_atlas, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+            gl::TextureParameteri(self.density_atlas, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+            gl::TextureParameteri(self.density_atlas, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+            gl::TextureParameteri(self.density_atlas, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+            gl::TextureParameteri(self.density_atlas, gl::TEXTURE_WRAP_R, gl::CLAMP_TO_EDGE as i32);
+        }
+        if !grids.is_empty() {
+            info!("Density grids uploaded, {:.2} MiB", (texels.len() * 4) as f32 / 1048576.0);
+        }
+    }
+
+    /// Replaces all media. Density grids have to be uploaded first.
+    pub fn upload_media(&self, media: &[Medium], grids: &[DensityGrid]) {
+        assert!(media.len() <= MAX_MEDIA, "Scene has more than {} media, counting one for every subsurface material!", MAX_MEDIA);
+        let data: Vec<GpuMedium> = media.iter().map(|medium| GpuMedium::new(medium, grids, &self.layers)).collect();
+        unsafe {
+            gl::NamedBufferSubData(self.ubo, 0, (data.len() * std::mem::size_of::<GpuMedium>()) as isize, data.as_ptr() as *const std::ffi::c_void);
+        }
+    }
+
+    /// Binds the media to a uniform buffer binding, and the density grids to a texture unit. See `medium.glsl`.
+    pub fn bind(&self, ubo_binding: u32, atlas_unit: u32) {
+        unsafe {
+            gl::BindBufferBase(gl::UNIFORM_BUFFER, ubo_binding, self.ubo);
+            gl::BindTextureUnit(atlas_unit, self.density_atlas);
+        }
+    }
+}
+
+impl Drop for MediumTable {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.ubo);
+            gl::DeleteTextures(1, &self.density_atlas);
+        }
+    }
+}
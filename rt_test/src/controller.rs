@@ -0,0 +1,160 @@
+use glam::*;
+
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
+
+use rt_lib::objects::Camera;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlMode {
+    /// WASD to move, Space/LCtrl to go up and down, drag the right mouse button to look around.
+    Fly,
+    /// Drag the right mouse button to orbit around the target, scroll to zoom, WASD to pan.
+    Orbit,
+}
+
+/// Moves a camera around based on keyboard and mouse input.
+/// Tab switches between the control modes, holding shift moves faster.
+pub struct CameraController {
+    pub mode: ControlMode,
+    pub speed: f32, //Units per second
+    pub sensitivity: f32, //Radians per pixel
+
+    yaw: f32,
+    pitch: f32,
+    distance: f32, //Orbit distance to the target
+
+    forward: bool,
+    backward: bool,
+    left: bool,
+    right: bool,
+    up: bool,
+    down: bool,
+    fast: bool,
+    looking: bool,
+    mouse_delta: (i32, i32),
+    scroll: i32,
+}
+
+impl CameraController {
+    pub fn new(camera: &Camera) -> Self {
+        let offset = camera.look_at - camera.eye;
+        let dir = offset.normalize();
+        Self {
+            mode: ControlMode::Fly,
+            speed: 2.0,
+            sensitivity: 0.004,
+
+            yaw: dir.x.atan2(-dir.z),
+            pitch: dir.y.asin(),
+            distance: offset.length(),
+
+            forward: false,
+            backward: false,
+            left: false,
+            right: false,
+            up: false,
+            down: false,
+            fast: false,
+            looking: false,
+            mouse_delta: (0, 0),
+            scroll: 0,
+        }
+    }
+
+    /// Returns true if the event was used by the controller.
+    pub fn handle_event(&mut self, event: &Event) -> bool {
+        match event {
+            Event::KeyDown { keycode: Some(Keycode::Tab), repeat: false, .. } => {
+                self.mode = match self.mode {
+                    ControlMode::Fly => ControlMode::Orbit,
+                    ControlMode::Orbit => ControlMode::Fly,
+                };
+                debug!("Camera control mode: {:?}", self.mode);
+                true
+            },
+            Event::KeyDown { keycode: Some(key), .. } => self.set_key(*key, true),
+            Event::KeyUp { keycode: Some(key), .. } => self.set_key(*key, false),
+            Event::MouseButtonDown { mouse_btn: MouseButton::Right, .. } => {
+                self.looking = true;
+                true
+            },
+            Event::MouseButtonUp { mouse_btn: MouseButton::Right, .. } => {
+                self.looking = false;
+                true
+            },
+            Event::MouseMotion { xrel, yrel, .. } if self.looking => {
+                self.mouse_delta.0 += xrel;
+                self.mouse_delta.1 += yrel;
+                true
+            },
+            Event::MouseWheel { y, .. } => {
+                self.scroll += y;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn set_key(&mut self, key: Keycode, pressed: bool) -> bool {
+        match key {
+            Keycode::W => self.forward = pressed,
+            Keycode::S => self.backward = pressed,
+            Keycode::A => self.left = pressed,
+            Keycode::D => self.right = pressed,
+            Keycode::Space => self.up = pressed,
+            Keycode::LCtrl => self.down = pressed,
+            Keycode::LShift => self.fast = pressed,
+            _ => return false,
+        }
+        true
+    }
+
+    fn direction(&self) -> Vec3 {
+        vec3(self.yaw.sin() * self.pitch.cos(), self.pitch.sin(), -self.yaw.cos() * self.pitch.cos())
+    }
+
+    /// Applies the input gathered since the last update to the camera.
+    /// The raytracer notices the camera changed by itself, and starts accumulating again.
+    pub fn update(&mut self, camera: &mut Camera, delta_s: f32) {
+        let (dx, dy) = std::mem::replace(&mut self.mouse_delta, (0, 0));
+        let scroll = std::mem::replace(&mut self.scroll, 0);
+
+        self.yaw += dx as f32 * self.sensitivity;
+        //Stay away from the poles, where the view matrix breaks down
+        let limit = std::f32::consts::FRAC_PI_2 - 0.01;
+        self.pitch = (self.pitch - dy as f32 * self.sensitivity).max(-limit).min(limit);
+
+        let dir = self.direction();
+        let right = dir.cross(camera.up).normalize();
+        let up = camera.up.normalize();
+
+        let mut movement = Vec3::ZERO;
+        if self.forward { movement += dir; }
+        if self.backward { movement -= dir; }
+        if self.right { movement += right; }
+        if self.left { movement -= right; }
+        if self.up { movement += up; }
+        if self.down { movement -= up; }
+        let speed = if self.fast { self.speed * 4.0 } else { self.speed };
+        let movement = movement.normalize_or_zero() * speed * delta_s;
+
+        //Leave the camera alone, so the render keeps accumulating
+        if dx == 0 && dy == 0 && scroll == 0 && movement == Vec3::ZERO {
+            return;
+        }
+
+        match self.mode {
+            ControlMode::Fly => {
+                camera.eye += movement;
+                camera.look_at = camera.eye + dir * self.distance;
+            },
+            ControlMode::Orbit => {
+                self.distance = (self.distance * 0.9f32.powi(scroll)).max(0.01);
+                camera.look_at += movement;
+                camera.eye = camera.look_at - dir * self.distance;
+            },
+        }
+    }
+}
@@ -2,6 +2,11 @@
 
 use std::time::Instant;
 
+mod controller;
+use controller::CameraController;
+
+use glam::*;
+
 use glux::{
     Program, WindowSettings,
     mesh::{Vertex, Mesh},
@@ -12,15 +17,38 @@ use rt_lib::{
     objects::{
         Camera,
         Lambert,
-    }
+        Material,
+        Medium,
+        Subsurface,
+    },
+    output::{exr, hdr},
+    scene::{Scene, Object, Shape, Transform},
+    mesh::TriangleMesh,
+    brickmap::BrickMap,
+    animation::{CameraAnimation, TransformAnimation, Track, Interpolation},
+    sequence::{render_sequence, SequenceSettings},
+    denoise::{Denoiser, Features},
+    settings::{Aov, RenderSettings},
 };
 
-pub fn get_workgroup_invocations() -> i32 {
-    let mut value = 0;
-    unsafe {
-        gl::GetIntegerv(gl::MAX_COMPUTE_WORK_GROUP_INVOCATIONS, &mut value);
+/// A short camera move, with the mirror sphere bouncing around.
+fn demo_animation(scene: &mut Scene) -> CameraAnimation {
+    let sphere = &mut scene.objects[6];
+    let start = sphere.transform.translation;
+    sphere.animation = Some(TransformAnimation {
+        translation: Track::new()
+            .key(0.0, start, Interpolation::Bezier)
+            .key(0.5, start + vec3(0.0, 1.5, 0.0), Interpolation::Bezier)
+            .key(1.0, start, Interpolation::Bezier),
+        ..Default::default()
+    });
+
+    CameraAnimation {
+        eye: Track::new()
+            .key(0.0, vec3(0.0, 0.0, -5.0), Interpolation::Bezier)
+            .key(2.0, vec3(2.0, 0.5, -5.0), Interpolation::Bezier),
+        ..Default::default()
     }
-    value
 }
 
 fn main() {
@@ -39,16 +67,68 @@ fn main() {
         vsync: false,
     };
 
+    let window_size = win_settings.resolution;
     let mut program = Program::new(win_settings);
 
-    // let dispatch_size = (get_workgroup_invocations() as f32).sqrt() as u32;
-    let dispatch_size = (32, 30); //960, should be able to run on everything
+    // let dispatch_size = rt_lib::dispatch::default_dispatch_size();
+    let dispatch_size = rt_lib::dispatch::validate_dispatch_size((32, 30)); //960, should be able to run on everything
     debug!("Dispatch size: {:?}", dispatch_size);
 
-    let mut raytracer = Raytracer::new(dispatch_size);
+    let settings = RenderSettings {
+        aovs: vec![Aov::Albedo, Aov::Normal, Aov::Depth],
+        ..Default::default()
+    };
+    let mut raytracer = Raytracer::new(dispatch_size, settings);
     let lambert = Lambert;
     raytracer.add_brdf(&lambert);
-    let mut camera = Camera::new((1280, 720), dispatch_size);
+    let subsurface = Subsurface;
+    raytracer.add_brdf(&subsurface);
+    let mut camera = Camera::new((window_size.0 as usize, window_size.1 as usize), dispatch_size);
+    let mut scene = Scene::cornell_box();
+
+    let args: Vec<String> = std::env::args().collect();
+
+    //Put a mesh in the middle of the room: rt_test --mesh <path to .obj/.gltf/.glb>
+    //Add --bake <voxel size> to turn it into a brick map instead of tracing the triangles
+    if let Some(i) = args.iter().position(|arg| arg == "--mesh") {
+        let path = args.get(i + 1).expect("Missing mesh path!");
+        let voxel_size: Option<f32> = args.iter().position(|arg| arg == "--bake")
+            .map(|i| args.get(i + 1).expect("Missing voxel size!").parse().expect("Invalid voxel size!"));
+        for mesh in TriangleMesh::load(std::path::Path::new(path)).expect("Failed to load mesh!") {
+            let shape = match voxel_size {
+                Some(voxel_size) => Shape::BrickMap { map: scene.add_brick_map(BrickMap::from_mesh(&mesh, voxel_size, voxel_size * 2.0)) },
+                None => Shape::Mesh { mesh: scene.add_mesh(mesh) },
+            };
+            scene.add(Object::new(shape, 1, Transform::from_translation(vec3(0.0, -2.0, 0.0))));
+        }
+    }
+
+    //Fill the room with fog: rt_test --fog <density>
+    if let Some(i) = args.iter().position(|arg| arg == "--fog") {
+        let density: f32 = args.get(i + 1).expect("Missing fog density!").parse().expect("Invalid fog density!");
+        let fog = scene.add_medium(Medium::fog(density));
+        scene.fog = Some(fog);
+    }
+
+    //Turn the small sphere into wax: rt_test --subsurface <radius>
+    if let Some(i) = args.iter().position(|arg| arg == "--subsurface") {
+        let radius: f32 = args.get(i + 1).expect("Missing subsurface radius!").parse().expect("Invalid subsurface radius!");
+        let wax = scene.add_material(Material::subsurface(vec3(0.9, 0.75, 0.5), vec3(1.0, 0.5, 0.25) * radius));
+        scene.objects[7].material = wax;
+    }
+
+    //Render an animation instead of opening the viewer: rt_test --sequence <first frame> <last frame>
+    if args.len() >= 4 && args[1] == "--sequence" {
+        let settings = SequenceSettings {
+            first_frame: args[2].parse().expect("Invalid first frame!"),
+            last_frame: args[3].parse().expect("Invalid last frame!"),
+            path: "frames/frame_####.exr".to_string(),
+            ..Default::default()
+        };
+        let animation = demo_animation(&mut scene);
+        render_sequence(&mut raytracer, &mut camera, &mut scene, Some(&animation), &settings).expect("Failed to render sequence!");
+        return;
+    }
 
     let vertices: Vec<Vertex> = vec![
             Vertex {
@@ -85,7 +165,9 @@ fn main() {
         ];
     let quad = Mesh::from_vertices(&vertices);
 
-    raytracer.render_sample(&mut camera);
+    let mut controller = CameraController::new(&camera);
+
+    raytracer.render_sample(&mut camera, &mut scene);
 
     let mut last_frame = Instant::now();
     let mut total_time: f32 = 0.0;
@@ -93,14 +175,66 @@ fn main() {
     let mut event_pump = program.sdl_mut().event_pump().unwrap();
     'program: loop {
         for event in event_pump.poll_iter() {
+            if controller.handle_event(&event) {
+                continue;
+            }
             match event {
                 sdl2::event::Event::Quit {..} => break 'program,
-                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::A), timestamp, window_id, scancode, keymod, repeat } => {
-                    let pixels = camera.get_texture_as_pixels();
+                sdl2::event::Event::Window { win_event: sdl2::event::WindowEvent::SizeChanged(width, height), .. } => {
+                    if width > 0 && height > 0 {
+                        //The camera always renders at the window size, so the image fills the whole window
+                        camera.set_resolution((width as usize, height as usize));
+                        unsafe {
+                            gl::Viewport(0, 0, width, height);
+                        }
+                    }
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::P), .. } => {
+                    let pixels = camera.get_texture_as_pixels(&raytracer.view_transform);
                     println!("Pixels: {}", pixels.len());
-                    image::save_buffer(&std::path::Path::new("test.png"), &pixels, 1280, 720, image::ColorType::Rgba8);
+                    image::save_buffer(&std::path::Path::new("test.png"), &pixels, camera.resolution.0 as u32, camera.resolution.1 as u32, image::ColorType::Rgba8);
                     println!("Image saved!");
                 },
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::X), .. } => {
+                    let image = camera.get_texture_as_float_image();
+                    let aovs: Vec<(Aov, _)> = raytracer.settings().aovs.iter().filter_map(|aov| {
+                        camera.get_aov_as_float_image(*aov).map(|image| (*aov, image))
+                    }).collect();
+                    let mut layers = vec![exr::Layer::new("", &image, exr::PixelType::Half)];
+                    for (aov, image) in &aovs {
+                        let pixel_type = match aov {
+                            Aov::Depth | Aov::Position => exr::PixelType::Float,
+                            _ => exr::PixelType::Half,
+                        };
+                        layers.push(exr::Layer::new(aov.name(), image, pixel_type));
+                    }
+                    match exr::write("test.exr", &layers) {
+                        Ok(_) => println!("EXR saved!"),
+                        Err(e) => error!("Failed to save EXR: {}", e),
+                    }
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::H), .. } => {
+                    let image = camera.get_texture_as_float_image();
+                    match hdr::write("test.hdr", &image) {
+                        Ok(_) => println!("HDR saved!"),
+                        Err(e) => error!("Failed to save HDR: {}", e),
+                    }
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::N), .. } => {
+                    let image = camera.get_texture_as_float_image();
+                    let albedo = camera.get_aov_as_float_image(Aov::Albedo);
+                    let normal = camera.get_aov_as_float_image(Aov::Normal);
+                    let depth = camera.get_aov_as_float_image(Aov::Depth);
+                    let features = Features {
+                        albedo: albedo.as_ref(),
+                        normal: normal.as_ref(),
+                        depth: depth.as_ref(),
+                    };
+                    let denoised = Denoiser::default().denoise(&image, &features);
+                    let pixels = raytracer.view_transform.to_ldr(&denoised);
+                    image::save_buffer(&std::path::Path::new("test_denoised.png"), &pixels, denoised.width as u32, denoised.height as u32, image::ColorType::Rgba8);
+                    println!("Denoised image saved!");
+                },
                 _ => {},
             }
         }
@@ -109,15 +243,17 @@ fn main() {
             gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
         }
 
-        raytracer.render_sample(&mut camera);
-        raytracer.test_output(&camera, &quad);
-
         let now = Instant::now();
         let delta = now - last_frame;
         let delta_s = delta.as_secs() as f32 + delta.subsec_nanos() as f32 / 1_000_000_000.0;
         last_frame = now;
         total_time += delta_s;
 
+        controller.update(&mut camera, delta_s);
+
+        raytracer.render_sample(&mut camera, &mut scene);
+        raytracer.test_output(&camera, &quad);
+
         program.sdl_window().gl_swap_window();
     }
 }